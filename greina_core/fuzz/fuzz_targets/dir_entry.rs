@@ -0,0 +1,10 @@
+#![no_main]
+
+use greina_core::fs::node::dir::DirEntry;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(entry) = DirEntry::try_from_bytes(data) {
+        assert_eq!(&entry.as_bytes()[..], data);
+    }
+});
@@ -0,0 +1,91 @@
+#![no_main]
+
+use std::collections::HashSet;
+
+use arbitrary::Arbitrary;
+use greina_core::{
+    block::{BLOCK_SIZE, Block, storage::Storage},
+    fs::{
+        Filesystem, MountOptions, check,
+        node::{FileType, NodeId, Perms},
+        transaction::ReadTransaction,
+    },
+};
+use greina_core_fuzz::{BLOCK_COUNT, formatted};
+use libfuzzer_sys::fuzz_target;
+
+/// Bytes written over a block of a formatted image.
+#[derive(Arbitrary, Debug)]
+struct Patch {
+    addr: u8,
+    offset: u16,
+    bytes: Vec<u8>,
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    patches: Vec<Patch>,
+    backup_superblock: bool,
+}
+
+fuzz_target!(|input: Input| {
+    let storage = formatted();
+    for patch in &input.patches {
+        let addr = u64::from(patch.addr) % BLOCK_COUNT;
+        let offset = usize::from(patch.offset) % BLOCK_SIZE as usize;
+        let len = patch.bytes.len().min(BLOCK_SIZE as usize - offset);
+
+        let mut block = Block::default();
+        storage.read_at(&mut block, addr).unwrap();
+        block[offset..offset + len].copy_from_slice(&patch.bytes[..len]);
+        storage.write_at(&block, addr).unwrap();
+    }
+
+    let _ = check::check(&storage);
+
+    let options = MountOptions {
+        backup_superblock: input.backup_superblock,
+        ..Default::default()
+    };
+    let Ok(mut fs) = Filesystem::mount_with(storage, &options) else {
+        return;
+    };
+    let _ = fs.read_tx(|tx| {
+        walk(tx, NodeId::ROOT, &mut HashSet::new());
+        Ok(())
+    });
+    let _ = fs.tx(|tx| {
+        let perms = Perms::new(0o644, 0, 0);
+        let id = tx.create_file(NodeId::ROOT, "fuzz", FileType::File, perms)?;
+        tx.write_file_at(id, 0, &[0xAB; 2 * BLOCK_SIZE as usize])
+    });
+});
+
+/// Reads everything reachable from the directory `id`, ignoring errors.
+fn walk<S: Storage>(tx: &ReadTransaction<S>, id: NodeId, visited: &mut HashSet<u64>) {
+    if !visited.insert(id.get()) {
+        return;
+    }
+    let _ = tx.read_node(id);
+    if let Ok(names) = tx.list_xattrs(id) {
+        for name in names {
+            let _ = tx.get_xattr(id, &name);
+        }
+    }
+
+    let Ok(entries) = tx.read_dir(id) else {
+        return;
+    };
+    for entry in entries {
+        match entry.filetype {
+            FileType::Dir => walk(tx, entry.id, visited),
+            FileType::File => {
+                let mut buf = vec![0; 2 * BLOCK_SIZE as usize];
+                let _ = tx.read_file_at(entry.id, 0, &mut buf);
+            }
+            FileType::Symlink => {
+                let _ = tx.read_symlink(entry.id);
+            }
+        }
+    }
+}
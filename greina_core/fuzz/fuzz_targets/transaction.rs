@@ -0,0 +1,209 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use greina_core::fs::{
+    Filesystem, check,
+    node::{FileType, NodeId, Perms, dir::RenameMode, xattr::SetMode},
+};
+use greina_core_fuzz::formatted;
+use libfuzzer_sys::fuzz_target;
+
+/// An operation on nodes and names picked by index, so most of them hit existing ones.
+#[derive(Arbitrary, Debug)]
+enum Op {
+    CreateFile {
+        parent: u8,
+        name: u8,
+    },
+    CreateDir {
+        parent: u8,
+        name: u8,
+    },
+    CreateSymlink {
+        parent: u8,
+        name: u8,
+        len: u16,
+    },
+    Write {
+        node: u8,
+        offset: u32,
+        len: u16,
+        byte: u8,
+    },
+    Truncate {
+        node: u8,
+        size: u32,
+    },
+    Link {
+        parent: u8,
+        node: u8,
+        name: u8,
+    },
+    Unlink {
+        parent: u8,
+        name: u8,
+    },
+    RemoveDir {
+        parent: u8,
+        name: u8,
+    },
+    Rename {
+        parent: u8,
+        name: u8,
+        new_parent: u8,
+        new_name: u8,
+        replace: bool,
+    },
+    SetXattr {
+        node: u8,
+        name: u8,
+        len: u16,
+    },
+    RemoveXattr {
+        node: u8,
+        name: u8,
+    },
+    RemoveOrphans,
+}
+
+fn pick(nodes: &[NodeId], idx: u8) -> Option<NodeId> {
+    match nodes.len() {
+        0 => None,
+        len => Some(nodes[usize::from(idx) % len]),
+    }
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    let storage = formatted();
+    let mut fs = Filesystem::mount(storage.clone()).unwrap();
+    let perms = Perms::new(0o644, 0, 0);
+
+    // Every node ever committed, removed ones included. Like the kernel does, parents are only
+    // picked from directories and files are the only ones written or linked.
+    let mut dirs = vec![NodeId::ROOT];
+    let mut files = vec![];
+    let mut nodes = vec![NodeId::ROOT];
+
+    for op in ops {
+        let parent = |idx| pick(&dirs, idx).expect("root must exist");
+
+        // A failing operation leaves the filesystem untouched, so errors are expected
+        let created = fs.tx(|tx| {
+            let created = match op {
+                Op::CreateFile { parent: idx, name } => {
+                    let filetype = FileType::File;
+                    let id = tx.create_file(parent(idx), &name.to_string(), filetype, perms)?;
+                    Some((id, filetype))
+                }
+                Op::CreateDir { parent: idx, name } => {
+                    let id = tx.create_dir(parent(idx), &name.to_string(), perms)?;
+                    Some((id, FileType::Dir))
+                }
+                Op::CreateSymlink {
+                    parent: idx,
+                    name,
+                    len,
+                } => {
+                    let target = "a".repeat(usize::from(len));
+                    let id = tx.create_symlink(parent(idx), &name.to_string(), &target, perms)?;
+                    Some((id, FileType::Symlink))
+                }
+                Op::Write {
+                    node,
+                    offset,
+                    len,
+                    byte,
+                } => {
+                    if let Some(id) = pick(&files, node) {
+                        let buf = vec![byte; usize::from(len)];
+                        tx.write_file_at(id, offset.into(), &buf)?;
+                    }
+                    None
+                }
+                Op::Truncate { node, size } => {
+                    if let Some(id) = pick(&files, node) {
+                        tx.truncate_file(id, size.into())?;
+                    }
+                    None
+                }
+                Op::Link {
+                    parent: idx,
+                    node,
+                    name,
+                } => {
+                    // The kernel refuses to link files that were unlinked already
+                    if let Some(id) = pick(&files, node)
+                        && tx.read_node(id)?.links.get() > 0
+                    {
+                        tx.link_file(parent(idx), id, &name.to_string())?;
+                    }
+                    None
+                }
+                Op::Unlink { parent: idx, name } => {
+                    tx.unlink_file(parent(idx), &name.to_string())?;
+                    None
+                }
+                Op::RemoveDir { parent: idx, name } => {
+                    tx.remove_dir(parent(idx), &name.to_string())?;
+                    None
+                }
+                Op::Rename {
+                    parent: idx,
+                    name,
+                    new_parent,
+                    new_name,
+                    replace,
+                } => {
+                    let mode = if replace {
+                        RenameMode::Replace
+                    } else {
+                        RenameMode::NoReplace
+                    };
+                    tx.rename_entry(
+                        parent(idx),
+                        &name.to_string(),
+                        parent(new_parent),
+                        &new_name.to_string(),
+                        mode,
+                    )?;
+                    None
+                }
+                Op::SetXattr { node, name, len } => {
+                    let id = pick(&nodes, node).expect("root must exist");
+                    let value = vec![name; usize::from(len)];
+                    let name = format!("user.{name}");
+                    tx.set_xattr(id, name.as_bytes(), &value, SetMode::Upsert)?;
+                    None
+                }
+                Op::RemoveXattr { node, name } => {
+                    let id = pick(&nodes, node).expect("root must exist");
+                    let name = format!("user.{name}");
+                    tx.remove_xattr(id, name.as_bytes())?;
+                    None
+                }
+                Op::RemoveOrphans => {
+                    for id in tx.list_orphans()? {
+                        tx.remove_orphan(id)?;
+                    }
+                    None
+                }
+            };
+            Ok(created)
+        });
+
+        // Nodes only exist once their transaction commits
+        if let Ok(Some((id, filetype))) = created {
+            nodes.push(id);
+            match filetype {
+                FileType::File => files.push(id),
+                FileType::Dir => dirs.push(id),
+                FileType::Symlink => {}
+            }
+        }
+    }
+    drop(fs);
+
+    // Mounting removes the orphans, after which the filesystem must be consistent
+    Filesystem::mount(storage.clone()).unwrap();
+    assert_eq!(check::check(&storage).unwrap(), []);
+});
@@ -0,0 +1,23 @@
+//! Helpers shared by the fuzz targets, run with `cargo fuzz run <target>` from `greina_core`.
+
+use std::sync::OnceLock;
+
+use greina_core::{
+    block::{Block, storage::mem::MemStorage},
+    fs::Filesystem,
+};
+
+/// How many blocks the fuzzed filesystems hold, small enough to fill up quickly.
+pub const BLOCK_COUNT: u64 = 256;
+
+/// Returns a storage holding a freshly formatted filesystem.
+/// The image is only formatted once, as formatting is slow compared to a fuzzing run.
+pub fn formatted() -> MemStorage {
+    static IMAGE: OnceLock<Vec<Block>> = OnceLock::new();
+    let image = IMAGE.get_or_init(|| {
+        let storage = MemStorage::new(BLOCK_COUNT);
+        Filesystem::format(storage.clone()).expect("formatting must succeed");
+        storage.blocks()
+    });
+    MemStorage::from_blocks(image.clone())
+}
@@ -1,13 +1,16 @@
-use std::sync::Mutex;
+use std::{collections::BTreeSet, sync::Mutex};
 
 use bitvec::prelude::*;
 use zerocopy::{FromBytes, IntoBytes, little_endian::U64};
 
 use crate::block::{
-    BlockAddr,
+    BLOCK_SIZE, BlockAddr,
     allocator::{Allocator, Error, Result},
 };
 
+/// How many bits are stored in a block-sized chunk of the bitmap.
+const BITS_PER_CHUNK: usize = BLOCK_SIZE as usize * 8;
+
 /// A bitmap-backed `Allocator`.
 pub struct BitmapAllocator {
     inner: Mutex<BitmapAllocatorInner>,
@@ -22,10 +25,12 @@ impl BitmapAllocator {
     }
 
     /// Constructs a bitmap for `block_count` blocks from bytes.
-    pub fn from_bytes(block_count: u64, bytes: &[u8]) -> Self {
-        Self {
-            inner: Mutex::new(BitmapAllocatorInner::from_bytes(block_count, bytes)),
-        }
+    /// Returns `None` if `bytes` isn't a whole number of words or is too short.
+    pub fn from_bytes(block_count: u64, bytes: &[u8]) -> Option<Self> {
+        let inner = BitmapAllocatorInner::from_bytes(block_count, bytes)?;
+        Some(Self {
+            inner: Mutex::new(inner),
+        })
     }
 
     /// Provides access to the bitmap as bytes.
@@ -37,6 +42,63 @@ impl BitmapAllocator {
         let inner = self.inner.lock().unwrap();
         f(inner.as_bytes())
     }
+
+    /// Calls `f` with the index and bytes of every block-sized chunk of the bitmap
+    /// that was modified since the last call to [`Self::clear_dirty`].
+    /// Holds the lock for the duration of the calls.
+    pub fn try_for_each_dirty<F, E>(&self, mut f: F) -> core::result::Result<(), E>
+    where
+        F: FnMut(u64, &[u8]) -> core::result::Result<(), E>,
+    {
+        let inner = self.inner.lock().unwrap();
+        let bytes = inner.as_bytes();
+        for &idx in &inner.dirty {
+            let start = idx * BLOCK_SIZE as usize;
+            let end = (start + BLOCK_SIZE as usize).min(bytes.len());
+            f(idx as u64, &bytes[start..end])?;
+        }
+        Ok(())
+    }
+
+    /// Marks every chunk of the bitmap as unmodified.
+    pub fn clear_dirty(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.dirty.clear();
+    }
+
+    /// Checks whether the block at `addr` is allocated.
+    ///
+    /// # Panics
+    /// Panics if `addr` is out of bounds.
+    pub fn is_allocated(&self, addr: BlockAddr) -> bool {
+        let inner = self.inner.lock().unwrap();
+        let addr = usize::try_from(addr).expect("'addr' must be addressable");
+        assert!(addr < inner.count, "'addr' must be in bounds");
+        inner.bits[addr]
+    }
+
+    /// Returns the runs of free blocks, by their start and length.
+    pub fn free_extents(&self) -> Vec<(BlockAddr, u64)> {
+        let inner = self.inner.lock().unwrap();
+        let mut extents: Vec<(BlockAddr, u64)> = Vec::new();
+        for addr in inner.bits[..inner.count].iter_zeros() {
+            let addr = addr as BlockAddr;
+            match extents.last_mut() {
+                Some((start, count)) if *start + *count == addr => *count += 1,
+                _ => extents.push((addr, 1)),
+            }
+        }
+        extents
+    }
+
+    /// Marks the block at `addr` as allocated or free, regardless of its current state.
+    ///
+    /// # Panics
+    /// Panics if `addr` is out of bounds.
+    pub fn set_allocated(&self, addr: BlockAddr, allocated: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.set(addr, allocated);
+    }
 }
 
 impl Allocator for BitmapAllocator {
@@ -61,6 +123,8 @@ struct BitmapAllocatorInner {
     count: usize,
     available: usize,
     last_cursor: usize,
+    // The indices of chunks modified since they were last written
+    dirty: BTreeSet<usize>,
 }
 
 impl BitmapAllocatorInner {
@@ -72,20 +136,22 @@ impl BitmapAllocatorInner {
             bits,
             available: count,
             last_cursor: 0,
+            dirty: BTreeSet::new(),
         }
     }
 
-    fn from_bytes(count: u64, bytes: &[u8]) -> Self {
-        let count = usize::try_from(count).expect("'count' must be addressable");
-        let slice = <[U64]>::ref_from_bytes(bytes).expect("'bytes' must be a valid bitmap");
+    fn from_bytes(count: u64, bytes: &[u8]) -> Option<Self> {
+        let count = usize::try_from(count).ok()?;
+        let slice = <[U64]>::ref_from_bytes(bytes).ok()?;
         let bits: BitBox<u64> = slice.iter().map(|v| v.get()).collect();
-        let available = bits[..count].count_zeros();
-        Self {
+        let available = bits.get(..count)?.count_zeros();
+        Some(Self {
             bits,
             count,
             available,
             last_cursor: 0,
-        }
+            dirty: BTreeSet::new(),
+        })
     }
 
     fn as_bytes(&self) -> &[u8] {
@@ -118,6 +184,13 @@ impl BitmapAllocatorInner {
         }
         None
     }
+
+    /// Marks the chunks covering bits `start..end` as modified.
+    fn mark_dirty(&mut self, start: usize, end: usize) {
+        let first = start / BITS_PER_CHUNK;
+        let last = (end - 1) / BITS_PER_CHUNK;
+        self.dirty.extend(first..=last);
+    }
 }
 
 impl BitmapAllocatorInner {
@@ -127,6 +200,7 @@ impl BitmapAllocatorInner {
         let start = self.find_free(count).ok_or(Error::NoSpace)?;
         let end = start + count;
         self.bits[start..end].fill(true);
+        self.mark_dirty(start, end);
         self.available -= count;
         self.last_cursor = end;
 
@@ -137,7 +211,7 @@ impl BitmapAllocatorInner {
         let start = usize::try_from(start).expect("'start' must be addressable");
         let count = usize::try_from(count).expect("'count' must be addressable");
 
-        let end = start + count;
+        let end = start.checked_add(count).ok_or(Error::AddrOutOfBounds)?;
         if end > self.count {
             return Err(Error::AddrOutOfBounds);
         }
@@ -147,6 +221,7 @@ impl BitmapAllocatorInner {
         }
 
         self.bits[start..end].fill(false);
+        self.mark_dirty(start, end);
         self.available += count;
 
         Ok(())
@@ -155,6 +230,22 @@ impl BitmapAllocatorInner {
     fn available(&self) -> u64 {
         self.available as u64
     }
+
+    fn set(&mut self, addr: BlockAddr, allocated: bool) {
+        let addr = usize::try_from(addr).expect("'addr' must be addressable");
+        assert!(addr < self.count, "'addr' must be in bounds");
+        if self.bits[addr] == allocated {
+            return;
+        }
+
+        self.bits.set(addr, allocated);
+        self.mark_dirty(addr, addr + 1);
+        if allocated {
+            self.available -= 1;
+        } else {
+            self.available += 1;
+        }
+    }
 }
 
 #[cfg(test)]
@@ -178,13 +269,80 @@ mod tests {
         let addr_1 = original.allocate(8).unwrap();
         let addr_2 = original.allocate(8).unwrap();
 
+        let available = original.available();
         original.with_bytes(|bytes| {
-            let restored = BitmapAllocator::from_bytes(16, bytes);
+            let restored = BitmapAllocator::from_bytes(16, bytes).unwrap();
 
-            assert_eq!(restored.available(), original.available());
+            assert_eq!(restored.available(), available);
 
             restored.deallocate(addr_1, 8).unwrap();
             restored.deallocate(addr_2, 8).unwrap();
         })
     }
+
+    #[test]
+    fn rejects_invalid_bytes() {
+        let original = BitmapAllocator::new(64);
+        original.with_bytes(|bytes| {
+            assert!(BitmapAllocator::from_bytes(128, bytes).is_none());
+            assert!(BitmapAllocator::from_bytes(64, &bytes[1..]).is_none());
+        });
+
+        assert!(matches!(
+            original.deallocate(u64::MAX, 2),
+            Err(Error::AddrOutOfBounds)
+        ));
+    }
+
+    #[test]
+    fn tracks_dirty_chunks() {
+        let allocator = BitmapAllocator::new(BITS_PER_CHUNK as u64 * 3);
+        allocator.clear_dirty();
+
+        // Spans the first two chunks
+        let addr = allocator.allocate(BITS_PER_CHUNK as u64 + 1).unwrap();
+        allocator.deallocate(addr, 1).unwrap();
+
+        let mut dirty = Vec::new();
+        allocator
+            .try_for_each_dirty(|idx, chunk| {
+                assert_eq!(chunk.len(), BLOCK_SIZE as usize);
+                dirty.push(idx);
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        assert_eq!(dirty, [0, 1]);
+
+        allocator.clear_dirty();
+        allocator
+            .try_for_each_dirty(|_, _| Err(()))
+            .expect("no chunk must be dirty");
+    }
+
+    #[test]
+    fn free_extents() {
+        let alloc = BitmapAllocator::new(16);
+        let first = alloc.allocate(4).unwrap();
+        let second = alloc.allocate(4).unwrap();
+        alloc.allocate(4).unwrap();
+        alloc.deallocate(second, 4).unwrap();
+        alloc.set_allocated(first + 1, false);
+
+        assert_eq!(alloc.free_extents(), [(1, 1), (4, 4), (12, 4)]);
+    }
+
+    #[test]
+    fn set_allocated() {
+        let allocator = BitmapAllocator::new(16);
+        let available = allocator.available();
+
+        allocator.set_allocated(3, true);
+        allocator.set_allocated(3, true);
+        assert!(allocator.is_allocated(3));
+        assert_eq!(allocator.available(), available - 1);
+
+        allocator.set_allocated(3, false);
+        assert!(!allocator.is_allocated(3));
+        assert_eq!(allocator.available(), available);
+    }
 }
@@ -10,6 +10,13 @@ pub struct FakeAllocator {
     inner: Mutex<FakeAllocatorInner>,
 }
 
+impl FakeAllocator {
+    /// Makes the blocks allocated so far no longer [fresh](Allocator::is_fresh).
+    pub fn commit(&self) {
+        self.inner.lock().unwrap().fresh.clear();
+    }
+}
+
 impl Allocator for FakeAllocator {
     fn allocate(&self, count: u64) -> Result<BlockAddr> {
         let mut inner = self.inner.lock().unwrap();
@@ -25,11 +32,18 @@ impl Allocator for FakeAllocator {
         let inner = self.inner.lock().unwrap();
         inner.available()
     }
+
+    fn is_fresh(&self, addr: BlockAddr) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.fresh.contains(&addr)
+    }
 }
 
 #[derive(Default)]
 struct FakeAllocatorInner {
     allocs: HashSet<BlockAddr>,
+    // The blocks allocated since the last commit
+    fresh: HashSet<BlockAddr>,
     next: BlockAddr,
 }
 
@@ -39,6 +53,7 @@ impl FakeAllocatorInner {
         self.next += count;
         for i in 0..count {
             self.allocs.insert(start + i);
+            self.fresh.insert(start + i);
         }
         Ok(start)
     }
@@ -51,6 +66,7 @@ impl FakeAllocatorInner {
         }
         for i in 0..count {
             self.allocs.remove(&(start + i));
+            self.fresh.remove(&(start + i));
         }
         Ok(())
     }
@@ -15,6 +15,12 @@ pub trait Allocator {
 
     /// Returns the number of blocks available for allocation.
     fn available(&self) -> u64;
+
+    /// Checks whether the block at `addr` was allocated since the last commit, so that nothing
+    /// committed refers to it and it can be overwritten in place.
+    fn is_fresh(&self, _addr: BlockAddr) -> bool {
+        false
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -122,7 +128,7 @@ mod tests {
     #[macro_export]
     macro_rules! test_allocator {
         ($allocator:ty) => {
-            use crate::block::allocator;
+            use $crate::block::allocator;
 
             #[test]
             fn test_allocate() {
@@ -0,0 +1,415 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Range,
+    sync::Mutex,
+};
+
+use crate::block::{
+    Block, BlockAddr,
+    storage::{Result, Storage},
+};
+
+/// A `Storage` keeping the most recently used blocks of another one in memory.
+///
+/// Writes go through to the inner storage before the cache is updated, so the cache never holds
+/// anything the inner storage doesn't. Transactions only write when they commit, so aborted ones
+/// never reach it.
+pub struct CachedStorage<S> {
+    inner: S,
+    // The most blocks kept in memory
+    capacity: usize,
+    cache: Mutex<Lru>,
+}
+
+/// How many reads the cache served, and how many went to the inner storage.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    blocks: HashMap<BlockAddr, (Box<Block>, u64)>,
+    // The cached addresses by when they were last used
+    order: BTreeMap<u64, BlockAddr>,
+    // The addresses being read from the inner storage by a ticket, which writing them revokes so
+    // that the block read isn't cached over what was written
+    reading: HashMap<BlockAddr, u64>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl Lru {
+    /// Returns the cached block, marking it as the most recently used.
+    fn get(&mut self, addr: BlockAddr) -> Option<&Block> {
+        let (block, used) = self.blocks.get_mut(&addr)?;
+        self.order.remove(used);
+        self.clock += 1;
+        *used = self.clock;
+        self.order.insert(self.clock, addr);
+        Some(block)
+    }
+
+    /// Caches a block, evicting the least recently used ones past `capacity`.
+    fn insert(&mut self, addr: BlockAddr, block: &Block, capacity: usize) {
+        self.remove(addr);
+        if capacity == 0 {
+            return;
+        }
+        while self.blocks.len() >= capacity {
+            let (_, oldest) = self.order.pop_first().expect("cache must not be empty");
+            self.blocks.remove(&oldest);
+        }
+        self.clock += 1;
+        self.blocks.insert(addr, (Box::new(*block), self.clock));
+        self.order.insert(self.clock, addr);
+    }
+
+    fn remove(&mut self, addr: BlockAddr) {
+        self.reading.remove(&addr);
+        if let Some((_, used)) = self.blocks.remove(&addr) {
+            self.order.remove(&used);
+        }
+    }
+
+    fn remove_range(&mut self, range: Range<BlockAddr>) {
+        self.reading.retain(|addr, _| !range.contains(addr));
+        let order = &mut self.order;
+        self.blocks.retain(|addr, (_, used)| {
+            let discarded = range.contains(addr);
+            if discarded {
+                order.remove(used);
+            }
+            !discarded
+        });
+    }
+
+    /// Returns a ticket to read a missing block from the inner storage without holding the lock.
+    fn start_read(&mut self, addr: BlockAddr) -> u64 {
+        self.stats.misses += 1;
+        self.clock += 1;
+        self.reading.insert(addr, self.clock);
+        self.clock
+    }
+
+    /// Caches the block read with `ticket` if it's still valid, i.e. nothing was written to the
+    /// address since, and `block` is given.
+    fn finish_read(
+        &mut self,
+        addr: BlockAddr,
+        ticket: u64,
+        block: Option<&Block>,
+        capacity: usize,
+    ) {
+        if self.reading.get(&addr) != Some(&ticket) {
+            return;
+        }
+        self.reading.remove(&addr);
+        if let Some(block) = block {
+            self.insert(addr, block, capacity);
+        }
+    }
+}
+
+impl<S: Storage> CachedStorage<S> {
+    /// Constructs a cache of at most `capacity` blocks in front of `inner`.
+    pub fn new(inner: S, capacity: usize) -> Self {
+        Self {
+            inner,
+            capacity,
+            cache: Default::default(),
+        }
+    }
+
+    /// Returns the storage the blocks are cached for.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns how many reads were served so far.
+    pub fn stats(&self) -> CacheStats {
+        self.cache.lock().unwrap().stats
+    }
+}
+
+impl<S: Storage> Storage for CachedStorage<S> {
+    fn read_at(&self, block: &mut Block, addr: BlockAddr) -> Result<()> {
+        let ticket = {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(cached) = cache.get(addr) {
+                *block = *cached;
+                cache.stats.hits += 1;
+                return Ok(());
+            }
+            cache.start_read(addr)
+        };
+
+        // The lock isn't held while reading, so that other reads go on meanwhile
+        let result = self.inner.read_at(block, addr);
+        let read = result.is_ok().then_some(&*block);
+        let mut cache = self.cache.lock().unwrap();
+        cache.finish_read(addr, ticket, read, self.capacity);
+        result
+    }
+
+    fn write_at(&self, block: &Block, addr: BlockAddr) -> Result<()> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Err(err) = self.inner.write_at(block, addr) {
+            // The block may or may not have been written
+            cache.remove(addr);
+            return Err(err);
+        }
+        cache.insert(addr, block, self.capacity);
+        Ok(())
+    }
+
+    fn capacity(&self) -> Result<u64> {
+        self.inner.capacity()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn discard(&self, start: BlockAddr, count: u64) -> Result<()> {
+        let mut cache = self.cache.lock().unwrap();
+        cache.remove_range(start..start.saturating_add(count));
+        self.inner.discard(start, count)
+    }
+
+    fn read_blocks(&self, blocks: &mut [(BlockAddr, &mut Block)]) -> Result<()> {
+        let mut misses = Vec::new();
+        let mut tickets = Vec::new();
+        {
+            let mut cache = self.cache.lock().unwrap();
+            for (addr, block) in blocks {
+                if let Some(cached) = cache.get(*addr) {
+                    **block = *cached;
+                    cache.stats.hits += 1;
+                } else {
+                    tickets.push(cache.start_read(*addr));
+                    misses.push((*addr, &mut **block));
+                }
+            }
+        }
+
+        // The blocks missing from the cache are read together, without holding the lock
+        let result = self.inner.read_blocks(&mut misses);
+        let mut cache = self.cache.lock().unwrap();
+        for ((addr, block), ticket) in misses.into_iter().zip(tickets) {
+            let read = result.is_ok().then_some(&*block);
+            cache.finish_read(addr, ticket, read, self.capacity);
+        }
+        result
+    }
+
+    fn write_blocks(&self, blocks: &[(BlockAddr, &Block)]) -> Result<()> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Err(err) = self.inner.write_blocks(blocks) {
+            for &(addr, _) in blocks {
+                cache.remove(addr);
+            }
+            return Err(err);
+        }
+        for &(addr, block) in blocks {
+            cache.insert(addr, block, self.capacity);
+        }
+        Ok(())
+    }
+}
+
+impl<S> Drop for CachedStorage<S> {
+    fn drop(&mut self) {
+        let stats = self.cache.get_mut().unwrap().stats;
+        log::debug!(
+            "block cache served {} reads and missed {}",
+            stats.hits,
+            stats.misses
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{sync::mpsc, thread};
+
+    use crate::{
+        block::storage::{crash::CrashStorage, tests::TestableStorage},
+        fs::{
+            Filesystem,
+            error::Error,
+            node::{FileType, NodeId, Perms},
+        },
+        test_storage,
+    };
+
+    impl TestableStorage for CachedStorage<CrashStorage> {
+        fn new_for_test(block_count: u64) -> Self {
+            Self::new(CrashStorage::new_for_test(block_count), 2)
+        }
+    }
+
+    test_storage!(CachedStorage<CrashStorage>);
+
+    fn block(byte: u8) -> Block {
+        let mut block = Block::default();
+        block.fill(byte);
+        block
+    }
+
+    fn read(storage: &impl Storage, addr: BlockAddr) -> Block {
+        let mut block = Block::default();
+        storage.read_at(&mut block, addr).unwrap();
+        block
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let storage = CachedStorage::<CrashStorage>::new_for_test(4);
+        read(&storage, 0);
+        read(&storage, 0);
+        storage.write_at(&block(0xAB), 1).unwrap();
+        assert_eq!(read(&storage, 1), block(0xAB));
+
+        let stats = storage.stats();
+        assert_eq!(stats, CacheStats { hits: 2, misses: 1 });
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let storage = CachedStorage::<CrashStorage>::new_for_test(4);
+        read(&storage, 0);
+        read(&storage, 1);
+        read(&storage, 0);
+        read(&storage, 2);
+        assert_eq!(storage.stats().misses, 3);
+
+        read(&storage, 0);
+        assert_eq!(storage.stats().misses, 3);
+        read(&storage, 1);
+        assert_eq!(storage.stats().misses, 4);
+    }
+
+    #[test]
+    fn reads_missing_blocks_together() {
+        let storage = CachedStorage::<CrashStorage>::new_for_test(4);
+        storage.write_at(&block(0xAB), 1).unwrap();
+
+        let mut blocks = [Block::default(); 3];
+        let mut pairs: Vec<_> = (0..).zip(&mut blocks).collect();
+        storage.read_blocks(&mut pairs).unwrap();
+        assert_eq!(blocks, [Block::default(), block(0xAB), Block::default()]);
+        assert_eq!(storage.stats(), CacheStats { hits: 1, misses: 2 });
+    }
+
+    #[test]
+    fn writes_through() {
+        let storage = CachedStorage::<CrashStorage>::new_for_test(4);
+        read(&storage, 3);
+        storage.write_at(&block(0xCD), 3).unwrap();
+
+        assert_eq!(read(storage.inner(), 3), block(0xCD));
+        assert_eq!(read(&storage, 3), block(0xCD));
+    }
+
+    /// A storage pausing after its reads until told to go on, to write while a block is being read.
+    struct PausingStorage {
+        inner: CrashStorage,
+        reading: Mutex<mpsc::Sender<()>>,
+        resume: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl Storage for PausingStorage {
+        fn read_at(&self, block: &mut Block, addr: BlockAddr) -> Result<()> {
+            self.inner.read_at(block, addr)?;
+            self.reading.lock().unwrap().send(()).unwrap();
+            self.resume.lock().unwrap().recv().unwrap();
+            Ok(())
+        }
+
+        fn write_at(&self, block: &Block, addr: BlockAddr) -> Result<()> {
+            self.inner.write_at(block, addr)
+        }
+
+        fn capacity(&self) -> Result<u64> {
+            self.inner.capacity()
+        }
+
+        fn flush(&self) -> Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn writes_during_reads() {
+        let (reading, paused) = mpsc::channel();
+        let (resume, resumed) = mpsc::channel();
+        let inner = PausingStorage {
+            inner: CrashStorage::new_for_test(4),
+            reading: Mutex::new(reading),
+            resume: Mutex::new(resumed),
+        };
+        let storage = CachedStorage::new(inner, 2);
+
+        thread::scope(|scope| {
+            let reader = scope.spawn(|| read(&storage, 0));
+            paused.recv().unwrap();
+            // The lock isn't held by the paused read
+            storage.write_at(&block(0xAB), 0).unwrap();
+            resume.send(()).unwrap();
+            reader.join().unwrap();
+        });
+
+        // The block read before the write isn't cached over it
+        assert_eq!(storage.stats().hits, 0);
+        assert_eq!(read(&storage, 0), block(0xAB));
+        assert_eq!(storage.stats().hits, 1);
+    }
+
+    #[test]
+    fn forgets_discarded_blocks() {
+        let storage = CachedStorage::<CrashStorage>::new_for_test(4);
+        storage.write_at(&block(0xAB), 1).unwrap();
+        storage.write_at(&block(0xCD), 2).unwrap();
+        storage.discard(0, 2).unwrap();
+
+        read(&storage, 1);
+        read(&storage, 2);
+        assert_eq!(storage.stats(), CacheStats { hits: 1, misses: 1 });
+    }
+
+    #[test]
+    fn serves_repeated_lookups() {
+        let storage = CrashStorage::new(vec![Block::default(); 1024]);
+        let mut fs = Filesystem::format(CachedStorage::new(storage, 64)).unwrap();
+        let perms = Perms::new(0o644, 0, 0);
+        fs.tx(|tx| tx.create_file(NodeId::ROOT, "file", FileType::File, perms))
+            .unwrap();
+
+        let lookup = |fs: &Filesystem<_>| fs.read_tx(|tx| tx.find_entry(NodeId::ROOT, "file"));
+        let id = lookup(&fs).unwrap().id;
+        let misses = fs.storage().stats().misses;
+
+        // The tree's nodes were read already
+        assert_eq!(lookup(&fs).unwrap().id, id);
+        assert_eq!(fs.storage().stats().misses, misses);
+    }
+
+    #[test]
+    fn ignores_aborted_transactions() {
+        let storage = CrashStorage::new(vec![Block::default(); 1024]);
+        let mut fs = Filesystem::format(CachedStorage::new(storage, 64)).unwrap();
+        let perms = Perms::new(0o644, 0, 0);
+        let result = fs.tx(|tx| {
+            tx.create_file(NodeId::ROOT, "file", FileType::File, perms)?;
+            tx.find_entry(NodeId::ROOT, "missing")
+        });
+        assert!(matches!(result, Err(Error::DirEntryNotFound)));
+
+        let result = fs.read_tx(|tx| tx.find_entry(NodeId::ROOT, "file"));
+        assert!(matches!(result, Err(Error::DirEntryNotFound)));
+    }
+}
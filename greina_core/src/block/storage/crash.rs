@@ -0,0 +1,239 @@
+use std::{
+    ops::Range,
+    sync::{Arc, RwLock},
+};
+
+use crate::block::{
+    BLOCK_SIZE, Block, BlockAddr,
+    storage::{Result, Storage},
+};
+
+/// The byte discarded blocks are filled with.
+pub const DISCARDED: u8 = 0xDD;
+
+/// An in-memory storage recording every write and flush, to tell what the device would hold had
+/// power been lost after any of them.
+/// Writes since the last flush may reach the device in any order, so a crash can lose any of them.
+/// Discarded blocks are overwritten with [DISCARDED], recorded as writes, so that relying on
+/// their contents shows up as corruption.
+/// Clones share the same blocks, so the storage can be inspected after a filesystem took it.
+#[derive(Clone)]
+pub struct CrashStorage {
+    inner: Arc<RwLock<CrashStorageInner>>,
+}
+
+struct CrashStorageInner {
+    // The blocks before the first recorded write
+    base: Vec<Block>,
+    blocks: Vec<Block>,
+    writes: Vec<(BlockAddr, Block)>,
+    // How many writes were recorded at each flush
+    flushes: Vec<usize>,
+    // How many writes are recorded before the next ones fail
+    fail_after: Option<usize>,
+}
+
+impl CrashStorage {
+    /// Constructs a storage holding `blocks`, recording the writes from then on.
+    pub fn new(blocks: Vec<Block>) -> Self {
+        let inner = CrashStorageInner {
+            base: blocks.clone(),
+            blocks,
+            writes: Vec::new(),
+            flushes: Vec::new(),
+            fail_after: None,
+        };
+        Self {
+            inner: Arc::new(RwLock::new(inner)),
+        }
+    }
+
+    /// Returns a copy of the blocks.
+    pub fn blocks(&self) -> Vec<Block> {
+        self.inner.read().unwrap().blocks.clone()
+    }
+
+    /// Returns the writes recorded so far, in order.
+    pub fn writes(&self) -> Vec<(BlockAddr, Block)> {
+        self.inner.read().unwrap().writes.clone()
+    }
+
+    /// Returns how many writes were recorded at each flush so far, in order.
+    pub fn flushes(&self) -> Vec<usize> {
+        self.inner.read().unwrap().flushes.clone()
+    }
+
+    /// Returns how many writes were recorded so far.
+    pub fn write_count(&self) -> usize {
+        self.inner.read().unwrap().writes.len()
+    }
+
+    /// Makes the writes fail with `EIO` once `count` writes were recorded, or never if `None`.
+    pub fn fail_writes_after(&self, count: Option<usize>) {
+        self.inner.write().unwrap().fail_after = count;
+    }
+
+    /// Returns the writes among the first `count` which no flush followed, and which a crash after
+    /// them could therefore lose.
+    pub fn unflushed(&self, count: usize) -> Range<usize> {
+        let inner = self.inner.read().unwrap();
+        let flushed = inner
+            .flushes
+            .iter()
+            .rev()
+            .find(|&&flushed| flushed <= count);
+        flushed.copied().unwrap_or(0)..count
+    }
+
+    /// Returns the blocks as they'd be had power been lost after the first `count` writes.
+    /// Those of the [unflushed](Self::unflushed) writes for which `lost` returns true never reached
+    /// the device.
+    /// If `torn` is given, only that many leading bytes of the next write reached the device.
+    pub fn crash_image(
+        &self,
+        count: usize,
+        torn: Option<usize>,
+        lost: impl Fn(usize) -> bool,
+    ) -> Vec<Block> {
+        let unflushed = self.unflushed(count);
+        let inner = self.inner.read().unwrap();
+        let mut blocks = inner.base.clone();
+        for (i, (addr, block)) in inner.writes[..count].iter().enumerate() {
+            if !(unflushed.contains(&i) && lost(i)) {
+                blocks[*addr as usize] = *block;
+            }
+        }
+        if let Some(len) = torn
+            && let Some((addr, block)) = inner.writes.get(count)
+        {
+            blocks[*addr as usize][..len].copy_from_slice(&block[..len]);
+        }
+        blocks
+    }
+}
+
+impl Storage for CrashStorage {
+    fn read_at(&self, block: &mut Block, addr: BlockAddr) -> Result<()> {
+        let inner = self.inner.read().unwrap();
+        let idx = usize::try_from(addr).map_err(|_| libc::EIO)?;
+        *block = *inner.blocks.get(idx).ok_or(libc::EIO)?;
+        Ok(())
+    }
+
+    fn write_at(&self, block: &Block, addr: BlockAddr) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        if inner
+            .fail_after
+            .is_some_and(|count| inner.writes.len() >= count)
+        {
+            return Err(libc::EIO);
+        }
+        let idx = usize::try_from(addr).map_err(|_| libc::EIO)?;
+        *inner.blocks.get_mut(idx).ok_or(libc::EIO)? = *block;
+        inner.writes.push((addr, *block));
+        Ok(())
+    }
+
+    fn capacity(&self) -> Result<u64> {
+        Ok(self.inner.read().unwrap().blocks.len() as u64)
+    }
+
+    fn discard(&self, start: BlockAddr, count: u64) -> Result<()> {
+        let end = start.checked_add(count).ok_or(libc::EIO)?;
+        if end > self.capacity()? {
+            return Err(libc::EIO);
+        }
+        let block = Block::new(&[DISCARDED; BLOCK_SIZE as usize]);
+        for addr in start..end {
+            self.write_at(&block, addr)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        let count = inner.writes.len();
+        inner.flushes.push(count);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{block::storage::tests::TestableStorage, test_storage};
+
+    impl TestableStorage for CrashStorage {
+        fn new_for_test(block_count: u64) -> Self {
+            Self::new(vec![Block::default(); block_count as usize])
+        }
+    }
+
+    test_storage!(CrashStorage);
+
+    #[test]
+    fn crash_image() {
+        let storage = CrashStorage::new_for_test(2);
+        let mut block = Block::default();
+        block.fill(0xAB);
+        storage.write_at(&block, 0).unwrap();
+        block.fill(0xCD);
+        storage.write_at(&block, 1).unwrap();
+        storage.write_at(&block, 0).unwrap();
+
+        let image = storage.crash_image(0, None, |_| false);
+        assert_eq!(image, [Block::default(); 2]);
+
+        let image = storage.crash_image(1, Some(16), |_| false);
+        assert!(image[0].iter().all(|&b| b == 0xAB));
+        assert!(image[1][..16].iter().all(|&b| b == 0xCD));
+        assert!(image[1][16..].iter().all(|&b| b == 0));
+
+        assert_eq!(storage.crash_image(3, None, |_| false), storage.blocks());
+    }
+
+    #[test]
+    fn loses_unflushed_writes() {
+        let storage = CrashStorage::new_for_test(2);
+        let mut block = Block::default();
+        block.fill(0xAB);
+        storage.write_at(&block, 0).unwrap();
+        storage.flush().unwrap();
+        block.fill(0xCD);
+        storage.write_at(&block, 1).unwrap();
+        storage.write_at(&block, 0).unwrap();
+        assert_eq!(storage.unflushed(3), 1..3);
+        assert_eq!(storage.unflushed(1), 1..1);
+
+        // The flushed write survives even if lost, while the later ones can be lost out of order
+        let image = storage.crash_image(3, None, |i| i != 1);
+        assert!(image[0].iter().all(|&b| b == 0xAB));
+        assert!(image[1].iter().all(|&b| b == 0xCD));
+
+        let image = storage.crash_image(3, None, |_| true);
+        assert!(image[0].iter().all(|&b| b == 0xAB));
+        assert_eq!(image[1], Block::default());
+    }
+
+    #[test]
+    fn fails_writes() {
+        let storage = CrashStorage::new_for_test(2);
+        storage.write_at(&Block::default(), 0).unwrap();
+        storage.fail_writes_after(Some(1));
+        assert_eq!(storage.write_at(&Block::default(), 1), Err(libc::EIO));
+        assert_eq!(storage.write_count(), 1);
+
+        storage.fail_writes_after(None);
+        storage.write_at(&Block::default(), 1).unwrap();
+    }
+
+    #[test]
+    fn records_flushes() {
+        let storage = CrashStorage::new_for_test(2);
+        storage.flush().unwrap();
+        storage.write_at(&Block::default(), 0).unwrap();
+        storage.flush().unwrap();
+        assert_eq!(storage.flushes(), [0, 1]);
+    }
+}
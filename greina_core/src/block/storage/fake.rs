@@ -25,6 +25,10 @@ impl Storage for FakeStorage {
         let inner = self.inner.read().unwrap();
         inner.capacity()
     }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[derive(Default)]
@@ -1,6 +1,9 @@
 use std::{
     fs::{File, OpenOptions},
-    os::unix::fs::FileExt,
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileExt, FileTypeExt, OpenOptionsExt},
+    },
 };
 
 use crate::block::{
@@ -8,21 +11,44 @@ use crate::block::{
     storage::{Result, Storage},
 };
 
-/// A file-backed `Storage`.
+/// Returns the size of a block device in bytes, which libc doesn't define.
+const BLKGETSIZE64: libc::Ioctl = libc::_IOR::<u64>(0x12, 114);
+
+/// Discards a byte range of a block device, which libc doesn't define.
+const BLKDISCARD: libc::Ioctl = libc::_IO(0x12, 119);
+
+/// The most blocks a single vectored call transfers.
+const MAX_IOVECS: usize = libc::UIO_MAXIOV as usize;
+
+/// A `Storage` backed by a file or a block device.
 pub struct FileStorage {
     file: File,
+    block_count: u64,
+    is_device: bool,
 }
 
 impl FileStorage {
-    /// Opens a file to be used as `FileStorage`.
-    /// If file's size is not a multiple of `BLOCK_SIZE` the remaining bytes are not addressable.
+    /// Opens a file or block device to be used as `FileStorage`.
+    /// If its size is not a multiple of `BLOCK_SIZE` the remaining bytes are not addressable.
     pub fn open(path: &str) -> Result<Self> {
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .open(path)
             .into_errno()?;
-        Ok(Self { file })
+        Self::from_file(file)
+    }
+
+    /// Opens a file or block device like [Self::open], but fails with `EBUSY` if the device is
+    /// in use, such as by a mounted filesystem.
+    pub fn open_exclusive(path: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_EXCL)
+            .open(path)
+            .into_errno()?;
+        Self::from_file(file)
     }
 
     /// Creates a file to be used as `FileStorage`.
@@ -36,14 +62,66 @@ impl FileStorage {
             .open(path)
             .into_errno()?;
         file.set_len(block_count * BLOCK_SIZE).into_errno()?;
-        Ok(Self { file })
+        Self::from_file(file)
+    }
+
+    fn from_file(file: File) -> Result<Self> {
+        let metadata = file.metadata().into_errno()?;
+        let is_device = metadata.file_type().is_block_device();
+        let block_count = if is_device {
+            let (size, sector_size) = device_geometry(&file)?;
+            device_block_count(size, sector_size)?
+        } else {
+            metadata.len() / BLOCK_SIZE
+        };
+        Ok(Self {
+            file,
+            block_count,
+            is_device,
+        })
+    }
+
+    /// Returns the byte offset of `count` blocks starting at `addr`, if they're all addressable.
+    fn run_offset(&self, addr: BlockAddr, count: u64) -> Result<libc::off_t> {
+        let end = addr.checked_add(count).ok_or(libc::EIO)?;
+        if end > self.block_count {
+            return Err(libc::EIO);
+        }
+        libc::off_t::try_from(addr * BLOCK_SIZE).map_err(|_| libc::EIO)
+    }
+}
+
+/// Returns the size in bytes and the logical sector size of a block device.
+fn device_geometry(file: &File) -> Result<(u64, u64)> {
+    let fd = file.as_raw_fd();
+
+    let mut size: u64 = 0;
+    if unsafe { libc::ioctl(fd, BLKGETSIZE64, &mut size) } < 0 {
+        return Err(std::io::Error::last_os_error()).into_errno();
+    }
+
+    let mut sector_size: libc::c_int = 0;
+    if unsafe { libc::ioctl(fd, libc::BLKSSZGET, &mut sector_size) } < 0 {
+        return Err(std::io::Error::last_os_error()).into_errno();
     }
+
+    Ok((size, sector_size as u64))
+}
+
+/// Returns the number of blocks a device holds.
+/// Blocks are read and written whole, so they must be made of whole sectors.
+fn device_block_count(size: u64, sector_size: u64) -> Result<u64> {
+    if !BLOCK_SIZE.is_multiple_of(sector_size) {
+        return Err(libc::EINVAL);
+    }
+    Ok(size / BLOCK_SIZE)
 }
 
 impl Storage for FileStorage {
     fn read_at(&self, block: &mut Block, addr: BlockAddr) -> Result<()> {
+        let offset = addr.checked_mul(BLOCK_SIZE).ok_or(libc::EIO)?;
         self.file
-            .read_at(&mut block[..], addr * BLOCK_SIZE)
+            .read_at(&mut block[..], offset)
             .into_errno()
             .and_then(|b| {
                 if b != BLOCK_SIZE as usize {
@@ -55,7 +133,7 @@ impl Storage for FileStorage {
     }
 
     fn write_at(&self, block: &Block, addr: BlockAddr) -> Result<()> {
-        if addr >= self.capacity()? {
+        if addr >= self.block_count {
             return Err(libc::EIO);
         }
 
@@ -72,11 +150,93 @@ impl Storage for FileStorage {
     }
 
     fn capacity(&self) -> Result<u64> {
-        let size = self.file.metadata().into_errno()?.len();
-        Ok(size / BLOCK_SIZE)
+        Ok(self.block_count)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.file.sync_data().into_errno()
+    }
+
+    /// Discards the blocks of a device, or punches a hole in a file to make it sparse.
+    fn discard(&self, start: BlockAddr, count: u64) -> Result<()> {
+        let offset = self.run_offset(start, count)?;
+        let len = libc::off_t::try_from(count * BLOCK_SIZE).map_err(|_| libc::EIO)?;
+        let fd = self.file.as_raw_fd();
+        let result = if self.is_device {
+            let range = [offset as u64, len as u64];
+            unsafe { libc::ioctl(fd, BLKDISCARD, &range) }
+        } else {
+            let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+            unsafe { libc::fallocate(fd, mode, offset, len) }
+        };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error()).into_errno();
+        }
+        Ok(())
+    }
+
+    fn read_blocks(&self, blocks: &mut [(BlockAddr, &mut Block)]) -> Result<()> {
+        for run in blocks.chunk_by_mut(|(a, _), (b, _)| a.checked_add(1) == Some(*b)) {
+            for run in run.chunks_mut(MAX_IOVECS) {
+                let offset = self.run_offset(run[0].0, run.len() as u64)?;
+                let iovecs: Vec<_> = run
+                    .iter_mut()
+                    .map(|(_, block)| libc::iovec {
+                        iov_base: block.as_mut_ptr().cast(),
+                        iov_len: BLOCK_SIZE as usize,
+                    })
+                    .collect();
+                let read = unsafe {
+                    libc::preadv(
+                        self.file.as_raw_fd(),
+                        iovecs.as_ptr(),
+                        iovecs.len() as libc::c_int,
+                        offset,
+                    )
+                };
+                check_transferred(read, run.len())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, blocks: &[(BlockAddr, &Block)]) -> Result<()> {
+        for run in blocks.chunk_by(|(a, _), (b, _)| a.checked_add(1) == Some(*b)) {
+            for run in run.chunks(MAX_IOVECS) {
+                let offset = self.run_offset(run[0].0, run.len() as u64)?;
+                let iovecs: Vec<_> = run
+                    .iter()
+                    .map(|(_, block)| libc::iovec {
+                        iov_base: block.as_ptr().cast_mut().cast(),
+                        iov_len: BLOCK_SIZE as usize,
+                    })
+                    .collect();
+                let written = unsafe {
+                    libc::pwritev(
+                        self.file.as_raw_fd(),
+                        iovecs.as_ptr(),
+                        iovecs.len() as libc::c_int,
+                        offset,
+                    )
+                };
+                check_transferred(written, run.len())?;
+            }
+        }
+        Ok(())
     }
 }
 
+/// Checks that a vectored call transferred `count` whole blocks.
+fn check_transferred(result: isize, count: usize) -> Result<()> {
+    if result < 0 {
+        return Err(std::io::Error::last_os_error()).into_errno();
+    }
+    if result as usize != count * BLOCK_SIZE as usize {
+        return Err(libc::EIO);
+    }
+    Ok(())
+}
+
 trait IntoErrno {
     type T;
 
@@ -107,9 +267,74 @@ mod tests {
             let file = tempfile().expect("failed to create temporary file");
             let size = block_count * BLOCK_SIZE;
             file.set_len(size).expect("failed to set file length");
-            Self { file }
+            Self::from_file(file).expect("failed to open temporary file")
         }
     }
 
     test_storage!(FileStorage);
+
+    #[test]
+    fn discard_punches_hole() {
+        use std::os::unix::fs::MetadataExt;
+
+        let storage = FileStorage::new_for_test(64);
+        let blocks = vec![Block::new(&[0xAB; BLOCK_SIZE as usize]); 64];
+        let pairs: Vec<_> = (0..).zip(&blocks).collect();
+        storage.write_blocks(&pairs).unwrap();
+        storage.flush().unwrap();
+        let allocated = storage.file.metadata().unwrap().blocks();
+
+        match storage.discard(16, 32) {
+            // Not every filesystem can punch holes
+            Err(libc::EOPNOTSUPP) => return,
+            result => result.unwrap(),
+        }
+        assert!(storage.file.metadata().unwrap().blocks() < allocated);
+        assert_eq!(storage.capacity().unwrap(), 64);
+
+        let mut block = Block::default();
+        storage.read_at(&mut block, 16).unwrap();
+        assert_eq!(block, Block::default());
+        storage.read_at(&mut block, 48).unwrap();
+        assert_eq!(block, blocks[48]);
+
+        assert_eq!(storage.discard(60, 8), Err(libc::EIO));
+    }
+
+    #[test]
+    fn write_blocks_past_iovec_limit() {
+        let count = MAX_IOVECS as u64 + 2;
+        let storage = FileStorage::new_for_test(count);
+        let written: Vec<_> = (0..count).map(|i| Block::new(&i.to_le_bytes())).collect();
+        let pairs: Vec<_> = (0..).zip(&written).collect();
+        storage.write_blocks(&pairs).unwrap();
+
+        let mut read = vec![Block::default(); count as usize];
+        let mut pairs: Vec<_> = (0..).zip(&mut read).collect();
+        storage.read_blocks(&mut pairs).unwrap();
+        assert_eq!(read, written);
+    }
+
+    #[test]
+    fn device_block_count() {
+        let size = 8 * BLOCK_SIZE + 512;
+        assert_eq!(super::device_block_count(size, 512), Ok(8));
+        assert_eq!(super::device_block_count(size, BLOCK_SIZE), Ok(8));
+        assert_eq!(
+            super::device_block_count(size, 2 * BLOCK_SIZE),
+            Err(libc::EINVAL)
+        );
+        assert_eq!(super::device_block_count(size, 0), Err(libc::EINVAL));
+    }
+
+    #[test]
+    fn open_exclusive_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.img");
+        let path = path.to_str().unwrap();
+        FileStorage::create(path, 4).unwrap();
+
+        let storage = FileStorage::open_exclusive(path).unwrap();
+        assert_eq!(storage.capacity().unwrap(), 4);
+    }
 }
@@ -0,0 +1,209 @@
+use std::sync::{Arc, RwLock};
+
+use zerocopy::IntoBytes;
+
+use crate::block::{
+    BLOCK_SIZE, Block, BlockAddr,
+    storage::{Result, Storage},
+};
+
+/// An in-memory `Storage` of a fixed number of blocks, to embed a filesystem or test code using
+/// one without touching the disk.
+/// Clones share the same blocks, so the storage can be inspected after a filesystem took it.
+#[derive(Clone)]
+pub struct MemStorage {
+    blocks: Arc<RwLock<Vec<Block>>>,
+}
+
+impl MemStorage {
+    /// Constructs a storage of `block_count` zeroed blocks.
+    pub fn new(block_count: u64) -> Self {
+        let block_count = usize::try_from(block_count).expect("'block_count' must be addressable");
+        Self::from_blocks(vec![Block::default(); block_count])
+    }
+
+    /// Constructs a storage holding `blocks`.
+    pub fn from_blocks(blocks: Vec<Block>) -> Self {
+        Self {
+            blocks: Arc::new(RwLock::new(blocks)),
+        }
+    }
+
+    /// Constructs a storage holding an image, such as one returned by [Self::to_bytes].
+    /// Fails with `EINVAL` if its length is not a multiple of `BLOCK_SIZE`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if !(bytes.len() as u64).is_multiple_of(BLOCK_SIZE) {
+            return Err(libc::EINVAL);
+        }
+        Ok(Self::from_blocks(Block::slice_from_bytes(bytes).to_vec()))
+    }
+
+    /// Reads an image file, such as one written by [Self::save] or formatted by `mkfs.greina`.
+    pub fn load(path: &str) -> Result<Self> {
+        let bytes = std::fs::read(path).map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// Returns a copy of the blocks.
+    pub fn blocks(&self) -> Vec<Block> {
+        self.blocks.read().unwrap().clone()
+    }
+
+    /// Returns a copy of the image as bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.blocks.read().unwrap().as_bytes().to_vec()
+    }
+
+    /// Writes the image to a file, which can then be opened as a `FileStorage`.
+    pub fn save(&self, path: &str) -> Result<()> {
+        let blocks = self.blocks.read().unwrap();
+        std::fs::write(path, blocks.as_bytes()).map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))
+    }
+}
+
+impl Storage for MemStorage {
+    fn read_at(&self, block: &mut Block, addr: BlockAddr) -> Result<()> {
+        let blocks = self.blocks.read().unwrap();
+        *block = *get(&blocks, addr)?;
+        Ok(())
+    }
+
+    fn write_at(&self, block: &Block, addr: BlockAddr) -> Result<()> {
+        let mut blocks = self.blocks.write().unwrap();
+        *get_mut(&mut blocks, addr)? = *block;
+        Ok(())
+    }
+
+    fn capacity(&self) -> Result<u64> {
+        Ok(self.blocks.read().unwrap().len() as u64)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Zeroes the blocks, like punching a hole in an image file.
+    fn discard(&self, start: BlockAddr, count: u64) -> Result<()> {
+        let mut blocks = self.blocks.write().unwrap();
+        let end = start.checked_add(count).ok_or(libc::EIO)?;
+        if end > blocks.len() as u64 {
+            return Err(libc::EIO);
+        }
+        blocks[start as usize..end as usize].fill(Block::default());
+        Ok(())
+    }
+
+    fn read_blocks(&self, pairs: &mut [(BlockAddr, &mut Block)]) -> Result<()> {
+        let blocks = self.blocks.read().unwrap();
+        for (addr, block) in pairs {
+            **block = *get(&blocks, *addr)?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, pairs: &[(BlockAddr, &Block)]) -> Result<()> {
+        let mut blocks = self.blocks.write().unwrap();
+        for &(addr, block) in pairs {
+            *get_mut(&mut blocks, addr)? = *block;
+        }
+        Ok(())
+    }
+}
+
+fn get(blocks: &[Block], addr: BlockAddr) -> Result<&Block> {
+    let idx = usize::try_from(addr).map_err(|_| libc::EIO)?;
+    blocks.get(idx).ok_or(libc::EIO)
+}
+
+fn get_mut(blocks: &mut [Block], addr: BlockAddr) -> Result<&mut Block> {
+    let idx = usize::try_from(addr).map_err(|_| libc::EIO)?;
+    blocks.get_mut(idx).ok_or(libc::EIO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        block::storage::{file::FileStorage, tests::TestableStorage},
+        fs::{
+            Filesystem,
+            node::{FileType, NodeId, Perms},
+        },
+        test_storage,
+    };
+
+    impl TestableStorage for MemStorage {
+        fn new_for_test(block_count: u64) -> Self {
+            Self::new(block_count)
+        }
+    }
+
+    test_storage!(MemStorage);
+
+    /// Returns the image of a filesystem holding a file.
+    fn image() -> MemStorage {
+        let storage = MemStorage::new(1024);
+        let mut fs = Filesystem::format(storage.clone()).unwrap();
+        fs.tx(|tx| {
+            let perms = Perms::new(0o644, 0, 0);
+            let id = tx.create_file(NodeId::ROOT, "file", FileType::File, perms)?;
+            tx.write_file_at(id, 0, b"Hello from Greina!").map(|_| ())
+        })
+        .unwrap();
+        storage
+    }
+
+    fn read_file(fs: &Filesystem<impl Storage>) -> Vec<u8> {
+        fs.read_tx(|tx| {
+            let id = tx.find_entry(NodeId::ROOT, "file")?.id;
+            let mut contents = vec![0; 18];
+            tx.read_file_at(id, 0, &mut contents)?;
+            Ok(contents)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn round_trips_bytes() {
+        let bytes = image().to_bytes();
+        assert_eq!(bytes.len() as u64, 1024 * BLOCK_SIZE);
+
+        let fs = Filesystem::mount(MemStorage::from_bytes(&bytes).unwrap()).unwrap();
+        assert_eq!(read_file(&fs), b"Hello from Greina!");
+    }
+
+    #[test]
+    fn rejects_partial_blocks() {
+        let bytes = vec![0; BLOCK_SIZE as usize + 1];
+        assert!(matches!(MemStorage::from_bytes(&bytes), Err(libc::EINVAL)));
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.img");
+        let path = path.to_str().unwrap();
+        image().save(path).unwrap();
+
+        let fs = Filesystem::mount(FileStorage::open(path).unwrap()).unwrap();
+        assert_eq!(read_file(&fs), b"Hello from Greina!");
+        drop(fs);
+
+        let fs = Filesystem::mount(MemStorage::load(path).unwrap()).unwrap();
+        assert_eq!(read_file(&fs), b"Hello from Greina!");
+    }
+
+    #[test]
+    fn discard_zeroes() {
+        let storage = MemStorage::new(4);
+        let mut block = Block::default();
+        block.fill(0xAB);
+        storage.write_at(&block, 1).unwrap();
+        storage.discard(1, 2).unwrap();
+
+        storage.read_at(&mut block, 1).unwrap();
+        assert_eq!(block, Block::default());
+        assert_eq!(storage.discard(3, 2), Err(libc::EIO));
+    }
+}
@@ -1,7 +1,11 @@
+pub mod cache;
+#[cfg(test)]
+pub mod crash;
 #[cfg(test)]
 pub mod fake;
 
 pub mod file;
+pub mod mem;
 
 use crate::block::{Block, BlockAddr};
 
@@ -16,6 +20,36 @@ pub trait Storage {
 
     /// Returns the number of blocks the storage can hold.
     fn capacity(&self) -> Result<u64>;
+
+    /// Waits for the blocks written so far to reach the device, so that they survive a crash.
+    /// Writes aren't otherwise ordered: after a crash, any of those since the last flush may
+    /// be missing.
+    fn flush(&self) -> Result<()>;
+
+    /// Tells the storage that the `count` blocks starting at `start` are no longer in use, so
+    /// that it can release the space they take. Their contents are unspecified until written
+    /// again. Storages that can't release space ignore it.
+    fn discard(&self, _start: BlockAddr, _count: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// Reads the blocks at the paired addresses into them.
+    /// If an error is returned, the contents of any of the blocks are unspecified.
+    fn read_blocks(&self, blocks: &mut [(BlockAddr, &mut Block)]) -> Result<()> {
+        for (addr, block) in blocks {
+            self.read_at(block, *addr)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the blocks into the paired addresses, in order.
+    /// If an error is returned, any of the blocks may have been written.
+    fn write_blocks(&self, blocks: &[(BlockAddr, &Block)]) -> Result<()> {
+        for (addr, block) in blocks {
+            self.write_at(block, *addr)?;
+        }
+        Ok(())
+    }
 }
 
 pub type Result<T> = core::result::Result<T, libc::c_int>;
@@ -92,34 +126,81 @@ pub mod tests {
 
         assert_eq!(read_block, write_block_2);
     }
-}
 
-#[macro_export]
-macro_rules! test_storage {
-    ($storage:ty) => {
-        #[test]
-        fn capacity() {
-            $crate::block::storage::tests::capacity::<$storage>();
+    pub fn write_and_read_blocks<S: TestableStorage>() {
+        let storage = S::new_for_test(8);
+        let written: Vec<_> = (1..=5)
+            .map(|byte| {
+                let mut block = Block::default();
+                block.fill(byte);
+                block
+            })
+            .collect();
+        // Two runs of contiguous addresses, and one out of order
+        let addrs = [1, 2, 3, 6, 5];
+        let pairs: Vec<_> = addrs.into_iter().zip(&written).collect();
+        storage.write_blocks(&pairs).unwrap();
+
+        for (addr, expected) in pairs {
+            let mut read_block = Block::default();
+            storage.read_at(&mut read_block, addr).unwrap();
+            assert_eq!(&read_block, expected);
         }
 
-        #[test]
-        fn write_and_read() {
-            $crate::block::storage::tests::write_and_read::<$storage>();
-        }
+        let mut read = vec![Block::default(); addrs.len()];
+        let mut pairs: Vec<_> = addrs.into_iter().zip(&mut read).collect();
+        storage.read_blocks(&mut pairs).unwrap();
+        assert_eq!(read, written);
+    }
 
-        #[test]
-        fn no_interference() {
-            $crate::block::storage::tests::no_interference::<$storage>();
-        }
+    pub fn blocks_out_of_bounds<S: TestableStorage>() {
+        let storage = S::new_for_test(4);
+        let mut blocks = [Block::default(); 2];
 
-        #[test]
-        fn out_of_bounds() {
-            $crate::block::storage::tests::out_of_bounds::<$storage>();
-        }
+        let pairs: Vec<_> = (3..).zip(&blocks).collect();
+        assert!(storage.write_blocks(&pairs).is_err());
 
-        #[test]
-        fn overwrite() {
-            $crate::block::storage::tests::overwrite::<$storage>();
-        }
-    };
+        let mut pairs: Vec<_> = (3..).zip(&mut blocks).collect();
+        assert!(storage.read_blocks(&mut pairs).is_err());
+    }
+
+    #[macro_export]
+    macro_rules! test_storage {
+        ($storage:ty) => {
+            #[test]
+            fn capacity() {
+                $crate::block::storage::tests::capacity::<$storage>();
+            }
+
+            #[test]
+            fn write_and_read() {
+                $crate::block::storage::tests::write_and_read::<$storage>();
+            }
+
+            #[test]
+            fn no_interference() {
+                $crate::block::storage::tests::no_interference::<$storage>();
+            }
+
+            #[test]
+            fn out_of_bounds() {
+                $crate::block::storage::tests::out_of_bounds::<$storage>();
+            }
+
+            #[test]
+            fn overwrite() {
+                $crate::block::storage::tests::overwrite::<$storage>();
+            }
+
+            #[test]
+            fn write_and_read_blocks() {
+                $crate::block::storage::tests::write_and_read_blocks::<$storage>();
+            }
+
+            #[test]
+            fn blocks_out_of_bounds() {
+                $crate::block::storage::tests::blocks_out_of_bounds::<$storage>();
+            }
+        };
+    }
 }
@@ -0,0 +1,1027 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use zerocopy::{FromBytes, TryFromBytes};
+
+use crate::{
+    block::{Block, BlockAddr, allocator::bitmap::BitmapAllocator, storage::Storage},
+    fs::{
+        Filesystem,
+        error::{Error, Result},
+        node::{
+            FileType, Node, NodeId, Perms,
+            bucket::{Bucket, COLLISION_MASK},
+            dir::{DirEntry, DirEntryName},
+            extent::Extent,
+        },
+        superblock::Superblock,
+        transaction::Transaction,
+    },
+    tree::{self, DataType, Key, Tree},
+};
+
+/// The directory unreachable nodes are reattached to, in the root directory.
+pub const LOST_AND_FOUND: &str = "lost+found";
+
+/// How many times [repair] rescans the filesystem, as fixing a problem can uncover others.
+const MAX_PASSES: usize = 8;
+
+/// What a block is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    // The superblock or its backups, the allocator's bitmap or the journal
+    Metadata,
+    // A node of the tree
+    Tree,
+    // The extent stored under the key
+    Extent(Key),
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Metadata => write!(f, "metadata"),
+            Self::Tree => write!(f, "the tree"),
+            Self::Extent(key) => write!(
+                f,
+                "extent at offset {} of node {}",
+                key.offset(),
+                key.id.get()
+            ),
+        }
+    }
+}
+
+/// An inconsistency found in a filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    // The superblock doesn't describe a valid filesystem
+    BadSuperblock,
+    // The backup of the superblock is invalid or doesn't match the superblock
+    BadBackupSuperblock(BlockAddr),
+    // The journal holds a committed transaction, which mounting will replay
+    JournalNotReplayed,
+    Tree(tree::check::Problem),
+    // The item can't be interpreted
+    BadItem(Key),
+    // The item belongs to a node that doesn't exist or can't have it
+    DanglingItem(Key),
+    // The node's id wasn't allocated yet
+    BadNodeId(NodeId),
+    OutOfBounds {
+        addr: BlockAddr,
+        owner: Owner,
+    },
+    MultiplyOwned {
+        addr: BlockAddr,
+        owners: [Owner; 2],
+    },
+    // The block is in use but free in the bitmap
+    Unallocated {
+        addr: BlockAddr,
+        owner: Owner,
+    },
+    // The block is allocated in the bitmap but not used
+    Leaked(BlockAddr),
+    // The root directory doesn't exist
+    MissingRoot,
+    // The entry stored under the key links to a node that doesn't exist
+    MissingNode {
+        key: Key,
+        id: NodeId,
+    },
+    // The entry stored under the key has a different filetype than its node
+    FileTypeMismatch(Key),
+    // The entry stored under the key is outside of its name's bucket
+    MisplacedEntry(Key),
+    // The directory's `.` entry is missing or doesn't link to itself
+    BadItself(NodeId),
+    // The directory's `..` entry is missing or doesn't link to the directory containing it
+    BadParent {
+        id: NodeId,
+        parent: NodeId,
+    },
+    // More than one entry links to the directory
+    MultiplyLinkedDir(NodeId),
+    BadLinks {
+        id: NodeId,
+        links: u32,
+        entries: u32,
+    },
+    // The node can't be reached from the root directory and isn't an orphan
+    Unreachable(NodeId),
+    // The orphan isn't a node without links
+    BadOrphan(NodeId),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadSuperblock => write!(f, "superblock is invalid"),
+            Self::BadBackupSuperblock(addr) => {
+                write!(f, "backup superblock at {addr} is invalid or outdated")
+            }
+            Self::JournalNotReplayed => write!(f, "journal holds a transaction to replay"),
+            Self::Tree(problem) => problem.fmt(f),
+            Self::BadItem(key) => write!(f, "item {key:?} is uninterpretable"),
+            Self::DanglingItem(key) => write!(f, "item {key:?} has no valid owner"),
+            Self::BadNodeId(id) => write!(f, "node {} has an unallocated id", id.get()),
+            Self::OutOfBounds { addr, owner } => {
+                write!(f, "block {addr} of {owner} is out of bounds")
+            }
+            Self::MultiplyOwned { addr, owners } => write!(
+                f,
+                "block {addr} is used by both {} and {}",
+                owners[0], owners[1]
+            ),
+            Self::Unallocated { addr, owner } => {
+                write!(f, "block {addr} of {owner} is not allocated")
+            }
+            Self::Leaked(addr) => write!(f, "block {addr} is allocated but unused"),
+            Self::MissingRoot => write!(f, "root directory is missing"),
+            Self::MissingNode { key, id } => write!(
+                f,
+                "entry {} of directory {} links to missing node {}",
+                key.offset(),
+                key.id.get(),
+                id.get()
+            ),
+            Self::FileTypeMismatch(key) => write!(
+                f,
+                "entry {} of directory {} has a wrong filetype",
+                key.offset(),
+                key.id.get()
+            ),
+            Self::MisplacedEntry(key) => write!(
+                f,
+                "entry {} of directory {} is misplaced",
+                key.offset(),
+                key.id.get()
+            ),
+            Self::BadItself(id) => write!(f, "directory {} has a bad '.' entry", id.get()),
+            Self::BadParent { id, parent } => write!(
+                f,
+                "directory {} has a bad '..' entry, expected {}",
+                id.get(),
+                parent.get()
+            ),
+            Self::MultiplyLinkedDir(id) => {
+                write!(f, "directory {} has more than one link", id.get())
+            }
+            Self::BadLinks { id, links, entries } => {
+                write!(f, "node {} has {links} links, expected {entries}", id.get())
+            }
+            Self::Unreachable(id) => write!(f, "node {} is unreachable", id.get()),
+            Self::BadOrphan(id) => write!(f, "node {} is a bad orphan", id.get()),
+        }
+    }
+}
+
+/// The outcome of [repair].
+#[derive(Debug)]
+pub struct Repair {
+    // The problems found before repairing
+    pub found: Vec<Problem>,
+    // The problems that couldn't be repaired
+    pub remaining: Vec<Problem>,
+}
+
+/// Checks the consistency of the filesystem on a storage device, without writing to it.
+/// Fails if the filesystem was formatted with features this implementation doesn't know of,
+/// as it can't tell what's consistent for them.
+pub fn check<S: Storage>(storage: &S) -> Result<Vec<Problem>> {
+    let superblock = match Filesystem::read_superblock(storage) {
+        Ok(superblock) => superblock,
+        Err(Error::InvalidSuperblock) => return Ok(vec![Problem::BadSuperblock]),
+        Err(err) => return Err(err),
+    };
+    superblock.check_features()?;
+
+    // The tree may be partially checkpointed until the journal is replayed
+    if superblock.journal().is_pending(storage)? {
+        return Ok(vec![Problem::JournalNotReplayed]);
+    }
+
+    let block_alloc = Filesystem::read_block_alloc(storage, &superblock)?;
+    let scan = Scan::new(storage, &superblock, &block_alloc);
+    Ok(scan.problems)
+}
+
+/// Repairs a mounted filesystem, as far as possible.
+///
+/// Rebuilds the allocator's bitmap, removes items that can't be interpreted or link to
+/// nothing, fixes `.` and `..` entries and link counts, and reattaches unreachable nodes
+/// to [LOST_AND_FOUND]. Problems with the structure of the tree and blocks used more than
+/// once aren't repaired, as there's no telling which owner is right.
+pub fn repair<S: Storage>(fs: &mut Filesystem<S>) -> Result<Repair> {
+    if fs.superblock.validate(fs.storage.capacity()?).is_err() {
+        let found = vec![Problem::BadSuperblock];
+        return Ok(Repair {
+            remaining: found.clone(),
+            found,
+        });
+    }
+
+    let mut scan = Scan::new(&fs.storage, &fs.superblock, &fs.block_alloc);
+    let found = scan.problems.clone();
+
+    for _ in 0..MAX_PASSES {
+        if scan.problems.is_empty() || !scan.is_repairable() {
+            break;
+        }
+
+        fs.repair_pass(&scan)?;
+
+        let next = Scan::new(&fs.storage, &fs.superblock, &fs.block_alloc);
+        let stuck = next.problems == scan.problems;
+        scan = next;
+        if stuck {
+            break;
+        }
+    }
+
+    Ok(Repair {
+        found,
+        remaining: scan.problems,
+    })
+}
+
+/// Everything learned about a filesystem by walking its tree.
+struct Scan {
+    problems: Vec<Problem>,
+    owners: BTreeMap<BlockAddr, Owner>,
+    nodes: BTreeMap<NodeId, Node>,
+    // Every entry by its key, including `.` and `..`
+    entries: BTreeMap<Key, DirEntry>,
+    // The keys of the buckets' overflow markers
+    overflows: BTreeSet<Key>,
+    // The keys of the entries linking to a node, excluding `.` and `..`
+    links: BTreeMap<NodeId, Vec<Key>>,
+    // Where the `..` entry of a directory links to
+    parents: BTreeMap<NodeId, NodeId>,
+    orphans: BTreeSet<NodeId>,
+}
+
+impl Scan {
+    fn new<S: Storage>(
+        storage: &S,
+        superblock: &Superblock,
+        block_alloc: &BitmapAllocator,
+    ) -> Self {
+        let mut scan = Self {
+            problems: Vec::new(),
+            owners: BTreeMap::new(),
+            nodes: BTreeMap::new(),
+            entries: BTreeMap::new(),
+            overflows: BTreeSet::new(),
+            links: BTreeMap::new(),
+            parents: BTreeMap::new(),
+            orphans: BTreeSet::new(),
+        };
+
+        let block_count = superblock.block_count;
+        let block_alloc_len = superblock.journal_start - superblock.block_alloc_start;
+        scan.claim(block_count, 0, 1, Owner::Metadata);
+        scan.claim(
+            block_count,
+            superblock.block_alloc_start,
+            block_alloc_len,
+            Owner::Metadata,
+        );
+        scan.claim(
+            block_count,
+            superblock.journal_start,
+            superblock.journal_len,
+            Owner::Metadata,
+        );
+        scan.check_backup_superblocks(storage, superblock);
+
+        let tree = Tree::check(storage, superblock.root_addr);
+        scan.problems
+            .extend(tree.problems.into_iter().map(Problem::Tree));
+        for addr in tree.blocks {
+            scan.claim(block_count, addr, 1, Owner::Tree);
+        }
+
+        for (key, data) in &tree.items {
+            scan.scan_item(superblock, *key, data);
+        }
+
+        scan.check_dirs();
+        scan.check_links();
+        scan.check_reachable();
+        scan.check_bitmap(block_count, block_alloc);
+        scan
+    }
+
+    /// Records `len` blocks starting at `start` as used by `owner`.
+    /// Claims the superblock's backups and checks that they match the superblock.
+    fn check_backup_superblocks<S: Storage>(&mut self, storage: &S, superblock: &Superblock) {
+        let expected = superblock.encode();
+        for addr in superblock.backup_addrs() {
+            self.claim(superblock.block_count, addr, 1, Owner::Metadata);
+
+            let mut block = Block::default();
+            if storage.read_at(&mut block, addr).is_err() || block[..] != expected[..] {
+                self.problems.push(Problem::BadBackupSuperblock(addr));
+            }
+        }
+    }
+
+    fn claim(&mut self, block_count: u64, start: BlockAddr, len: u64, owner: Owner) {
+        if start.checked_add(len).is_none_or(|end| end > block_count) {
+            self.problems
+                .push(Problem::OutOfBounds { addr: start, owner });
+            return;
+        }
+
+        for addr in start..start + len {
+            if let Some(&other) = self.owners.get(&addr) {
+                self.problems.push(Problem::MultiplyOwned {
+                    addr,
+                    owners: [other, owner],
+                });
+            } else {
+                self.owners.insert(addr, owner);
+            }
+        }
+    }
+
+    fn scan_item(&mut self, superblock: &Superblock, key: Key, data: &[u8]) {
+        match key.datatype {
+            DataType::Node => {
+                let Ok(node) = Node::try_read_from_bytes(data) else {
+                    self.problems.push(Problem::BadItem(key));
+                    return;
+                };
+                if key.id.is_null() || key.offset() != 0 {
+                    self.problems.push(Problem::BadItem(key));
+                    return;
+                }
+                if key.id.get() >= superblock.next_node_id {
+                    self.problems.push(Problem::BadNodeId(key.id));
+                }
+                self.nodes.insert(key.id, node);
+            }
+
+            DataType::Orphan => {
+                if !key.id.is_null() || !data.is_empty() {
+                    self.problems.push(Problem::BadItem(key));
+                    return;
+                }
+                self.orphans.insert(NodeId::new(key.offset()));
+            }
+
+            // Every other item belongs to a node, whose item precedes it
+            datatype => {
+                let Some(node) = self.nodes.get(&key.id) else {
+                    self.problems.push(Problem::DanglingItem(key));
+                    return;
+                };
+
+                match datatype {
+                    DataType::Extent => {
+                        if node.filetype == FileType::Dir {
+                            self.problems.push(Problem::DanglingItem(key));
+                            return;
+                        }
+                        let Ok(ext) = Extent::read_from_bytes(data) else {
+                            self.problems.push(Problem::BadItem(key));
+                            return;
+                        };
+                        if !ext.is_empty() {
+                            self.claim(
+                                superblock.block_count,
+                                ext.start(),
+                                ext.len(),
+                                Owner::Extent(key),
+                            );
+                        }
+                    }
+
+                    DataType::Checksum => {
+                        if node.filetype == FileType::Dir {
+                            self.problems.push(Problem::DanglingItem(key));
+                        }
+                    }
+
+                    DataType::DirEntry => {
+                        if node.filetype != FileType::Dir {
+                            self.problems.push(Problem::DanglingItem(key));
+                            return;
+                        }
+                        if Bucket::is_overflow(data) {
+                            if key.offset() & COLLISION_MASK != COLLISION_MASK {
+                                self.problems.push(Problem::BadItem(key));
+                                return;
+                            }
+                            self.overflows.insert(key);
+                            return;
+                        }
+                        let Ok(entry) = DirEntry::try_from_bytes(data) else {
+                            self.problems.push(Problem::BadItem(key));
+                            return;
+                        };
+                        self.entries.insert(key, entry);
+                    }
+
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    /// Checks whether the entry stored at `key` is in the bucket of `name`, which every bucket
+    /// before the entry's must overflow into.
+    fn is_in_bucket(&self, key: Key, name: &DirEntryName) -> bool {
+        let first = Key::direntry(key.id, name.hash());
+        let last = Key::direntry(key.id, key.offset() & !COLLISION_MASK);
+        if key < first || key.offset() & COLLISION_MASK == COLLISION_MASK {
+            return false;
+        }
+        let overflows = self.overflows.range(first..last).count() as u64;
+        overflows == (last.offset() - first.offset()) / (COLLISION_MASK + 1)
+    }
+
+    /// Checks the entries of every directory.
+    fn check_dirs(&mut self) {
+        let itself = DirEntryName::itself();
+        let parent = DirEntryName::parent();
+
+        for (&key, entry) in &self.entries {
+            let dir = key.id;
+            if !self.is_in_bucket(key, &entry.name) {
+                self.problems.push(Problem::MisplacedEntry(key));
+            }
+
+            if entry.name == itself {
+                continue;
+            } else if entry.name == parent {
+                self.parents.insert(dir, entry.id);
+                continue;
+            }
+
+            match self.nodes.get(&entry.id) {
+                None => self
+                    .problems
+                    .push(Problem::MissingNode { key, id: entry.id }),
+                Some(node) => {
+                    if node.filetype != entry.filetype {
+                        self.problems.push(Problem::FileTypeMismatch(key));
+                    }
+                    self.links.entry(entry.id).or_default().push(key);
+                }
+            }
+        }
+
+        for (&id, node) in &self.nodes {
+            if node.filetype != FileType::Dir {
+                continue;
+            }
+            let has_itself = self.entries.range(Self::dir_range(id)).any(|(_, entry)| {
+                entry.name == itself && entry.id == id && entry.filetype == FileType::Dir
+            });
+            if !has_itself {
+                self.problems.push(Problem::BadItself(id));
+            }
+        }
+
+        match self.nodes.get(&NodeId::ROOT) {
+            Some(root) if root.filetype == FileType::Dir => (),
+            _ => self.problems.push(Problem::MissingRoot),
+        }
+    }
+
+    /// Checks that link counts match the entries linking to nodes,
+    /// and that every directory is linked once from the directory its `..` links to.
+    fn check_links(&mut self) {
+        for (&id, node) in &self.nodes {
+            let links = self.links.get(&id).map_or(&[][..], Vec::as_slice);
+
+            if node.filetype == FileType::Dir {
+                if links.len() > 1 || (id == NodeId::ROOT && !links.is_empty()) {
+                    self.problems.push(Problem::MultiplyLinkedDir(id));
+                }
+
+                let expected = if id == NodeId::ROOT {
+                    Some(NodeId::ROOT)
+                } else {
+                    links.first().map(|key| key.id)
+                };
+                if let Some(parent) = expected
+                    && self.parents.get(&id) != Some(&parent)
+                {
+                    self.problems.push(Problem::BadParent { id, parent });
+                }
+
+                if node.links.get() != 1 {
+                    self.problems.push(Problem::BadLinks {
+                        id,
+                        links: node.links.get(),
+                        entries: 1,
+                    });
+                }
+            } else if !links.is_empty() && node.links.get() as usize != links.len() {
+                self.problems.push(Problem::BadLinks {
+                    id,
+                    links: node.links.get(),
+                    entries: links.len() as u32,
+                });
+            }
+        }
+
+        for &id in &self.orphans {
+            if !self.is_valid_orphan(id) {
+                self.problems.push(Problem::BadOrphan(id));
+            }
+        }
+    }
+
+    /// Checks that every node other than an orphan can be reached from the root directory.
+    fn check_reachable(&mut self) {
+        if self.problems.contains(&Problem::MissingRoot) {
+            return;
+        }
+
+        let mut reachable = BTreeSet::from([NodeId::ROOT]);
+        let mut queue = vec![NodeId::ROOT];
+        while let Some(dir) = queue.pop() {
+            for (_, entry) in self.entries.range(Self::dir_range(dir)) {
+                let is_dot =
+                    entry.name == DirEntryName::itself() || entry.name == DirEntryName::parent();
+                let Some(node) = self.nodes.get(&entry.id) else {
+                    continue;
+                };
+                if !is_dot && reachable.insert(entry.id) && node.filetype == FileType::Dir {
+                    queue.push(entry.id);
+                }
+            }
+        }
+
+        for &id in self.nodes.keys() {
+            if !reachable.contains(&id) && !self.is_valid_orphan(id) {
+                self.problems.push(Problem::Unreachable(id));
+            }
+        }
+    }
+
+    /// Checks that the bitmap marks exactly the used blocks as allocated.
+    fn check_bitmap(&mut self, block_count: u64, block_alloc: &BitmapAllocator) {
+        for addr in 0..block_count {
+            match (self.owners.get(&addr), block_alloc.is_allocated(addr)) {
+                (Some(&owner), false) => self.problems.push(Problem::Unallocated { addr, owner }),
+                (None, true) => self.problems.push(Problem::Leaked(addr)),
+                _ => (),
+            }
+        }
+    }
+
+    fn is_valid_orphan(&self, id: NodeId) -> bool {
+        self.orphans.contains(&id)
+            && !self.links.contains_key(&id)
+            && self
+                .nodes
+                .get(&id)
+                .is_some_and(|node| node.filetype != FileType::Dir && node.links.get() == 0)
+    }
+
+    /// The range of keys the entries of directory `id` are stored in.
+    fn dir_range(id: NodeId) -> std::ops::RangeInclusive<Key> {
+        Key::direntry(id, 0)..=Key::direntry(id, u64::MAX)
+    }
+
+    /// Whether the problems found leave the filesystem in a state that's safe to repair.
+    fn is_repairable(&self) -> bool {
+        !self.problems.iter().any(|problem| {
+            matches!(
+                problem,
+                Problem::BadSuperblock
+                    | Problem::JournalNotReplayed
+                    | Problem::Tree(_)
+                    | Problem::MissingRoot
+            )
+        })
+    }
+}
+
+impl<S: Storage> Filesystem<S> {
+    /// Fixes what it can of the problems found by `scan`.
+    /// Fixes may uncover other problems, which are left to the next pass.
+    fn repair_pass(&mut self, scan: &Scan) -> Result<()> {
+        let max_id = scan.nodes.keys().last().map_or(0, NodeId::get);
+        if max_id >= self.superblock.next_node_id {
+            self.superblock.next_node_id = max_id + 1;
+        }
+
+        // The bitmap is rebuilt first, so no used block is handed out by the fixes below
+        for addr in 0..self.superblock.block_count {
+            let used = scan.owners.contains_key(&addr);
+            if self.block_alloc.is_allocated(addr) != used {
+                self.block_alloc.set_allocated(addr, used);
+            }
+        }
+
+        // Committing writes both the superblock and the bitmap
+        self.tx(|tx| {
+            Self::repair_items(tx, scan)?;
+            Self::repair_dirs(tx, scan)?;
+            Self::reattach(tx, scan)
+        })
+    }
+
+    /// Removes items that are uninterpretable or link to nothing,
+    /// and moves misplaced entries into their buckets.
+    fn repair_items(tx: &mut Transaction<S>, scan: &Scan) -> Result<()> {
+        for problem in &scan.problems {
+            match *problem {
+                Problem::BadItem(key)
+                | Problem::DanglingItem(key)
+                | Problem::MissingNode { key, .. }
+                | Problem::OutOfBounds {
+                    owner: Owner::Extent(key),
+                    ..
+                } => tx.remove_item(key)?,
+
+                Problem::BadOrphan(id) => tx.remove_item(Key::orphan(id))?,
+
+                Problem::MisplacedEntry(key) => {
+                    let entry = &scan.entries[&key];
+                    tx.remove_item(key)?;
+                    // A duplicate name is dropped, fixing the links next pass
+                    match tx.upsert_entry(key.id, entry) {
+                        Err(Error::DirEntryExists) => (),
+                        res => res?,
+                    }
+                }
+
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Fixes entries' filetypes, directories' `.` and `..` entries and link counts.
+    fn repair_dirs(tx: &mut Transaction<S>, scan: &Scan) -> Result<()> {
+        for problem in &scan.problems {
+            match *problem {
+                Problem::FileTypeMismatch(key) => {
+                    let entry = &scan.entries[&key];
+                    let fixed = DirEntry {
+                        filetype: scan.nodes[&entry.id].filetype,
+                        id: entry.id,
+                        name: entry.name.clone(),
+                    };
+                    tx.upsert_entry(key.id, &fixed)?;
+                }
+
+                Problem::BadItself(id) => {
+                    let entry = DirEntry {
+                        filetype: FileType::Dir,
+                        id,
+                        name: DirEntryName::itself(),
+                    };
+                    tx.upsert_entry(id, &entry)?;
+                }
+
+                Problem::BadParent { id, parent } => {
+                    let entry = DirEntry {
+                        filetype: FileType::Dir,
+                        id: parent,
+                        name: DirEntryName::parent(),
+                    };
+                    tx.upsert_entry(id, &entry)?;
+                }
+
+                // Keeps the entry in the directory `..` links to, if any
+                Problem::MultiplyLinkedDir(id) => {
+                    let links = &scan.links[&id];
+                    let keep = links
+                        .iter()
+                        .position(|key| scan.parents.get(&id) == Some(&key.id))
+                        .filter(|_| id != NodeId::ROOT);
+                    for (idx, &key) in links.iter().enumerate() {
+                        if Some(idx) != keep {
+                            tx.remove_item(key)?;
+                        }
+                    }
+                }
+
+                Problem::BadLinks { id, entries, .. } => {
+                    let mut node = tx.read_node(id)?;
+                    node.links.set(entries);
+                    tx.write_node(&node, id)?;
+                }
+
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Links the unreachable nodes that nothing links to into [LOST_AND_FOUND].
+    /// Nodes under them become reachable along with them.
+    fn reattach(tx: &mut Transaction<S>, scan: &Scan) -> Result<()> {
+        let unreachable: Vec<NodeId> = scan
+            .problems
+            .iter()
+            .filter_map(|problem| match *problem {
+                Problem::Unreachable(id) => Some(id),
+                _ => None,
+            })
+            .collect();
+        if unreachable.is_empty() {
+            return Ok(());
+        }
+
+        let mut detached: Vec<NodeId> = unreachable
+            .iter()
+            .copied()
+            .filter(|id| !scan.links.contains_key(id))
+            .collect();
+
+        // Only directories linking to each other are left, so one of them is cut loose
+        if detached.is_empty() {
+            let id = unreachable[0];
+            for &key in &scan.links[&id] {
+                tx.remove_item(key)?;
+            }
+            detached.push(id);
+        }
+
+        let lost_and_found = match tx.find_entry(NodeId::ROOT, LOST_AND_FOUND) {
+            Ok(entry) if entry.filetype == FileType::Dir => entry.id,
+            Ok(_) => return Err(Error::NotDir),
+            Err(Error::DirEntryNotFound) => {
+                tx.create_dir(NodeId::ROOT, LOST_AND_FOUND, Perms::new(0o700, 0, 0))?
+            }
+            Err(err) => return Err(err),
+        };
+
+        for id in detached {
+            let filetype = scan.nodes[&id].filetype;
+            let name = DirEntryName::try_from(format!("#{}", id.get()).as_str())?;
+            tx.create_entry(lost_and_found, filetype, id, name)?;
+
+            if filetype == FileType::Dir {
+                let parent = DirEntry {
+                    filetype: FileType::Dir,
+                    id: lost_and_found,
+                    name: DirEntryName::parent(),
+                };
+                tx.upsert_entry(id, &parent)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        block::{Allocator, BLOCK_SIZE, storage::mem::MemStorage},
+        fs::node::xattr::SetMode,
+    };
+
+    fn format() -> Filesystem<MemStorage> {
+        Filesystem::format(MemStorage::new(1024)).unwrap()
+    }
+
+    /// Checks that `fs` has exactly `expected` problems, and that they're repaired.
+    fn assert_repairs(fs: &mut Filesystem<MemStorage>, expected: &[Problem]) {
+        assert_eq!(check(&fs.storage).unwrap(), expected);
+
+        let repair = repair(fs).unwrap();
+        assert_eq!(repair.found, expected);
+        assert_eq!(repair.remaining, []);
+        assert_eq!(check(&fs.storage).unwrap(), []);
+    }
+
+    fn perms() -> Perms {
+        Perms::new(0o644, 0, 0)
+    }
+
+    #[test]
+    fn clean() {
+        let mut fs = format();
+        let file = fs
+            .tx(|tx| {
+                let dir = tx.create_dir(NodeId::ROOT, "dir", perms())?;
+                let file = tx.create_file(dir, "file", FileType::File, perms())?;
+                tx.link_file(NodeId::ROOT, file, "link")?;
+                tx.create_symlink(NodeId::ROOT, "symlink", "dir/file", perms())?;
+                Ok(file)
+            })
+            .unwrap();
+        fs.tx(|tx| tx.write_file_at(file, 0, &[0xAB; 3 * BLOCK_SIZE as usize]))
+            .unwrap();
+        fs.tx(|tx| {
+            let orphan = tx.create_file(NodeId::ROOT, "orphan", FileType::File, perms())?;
+            tx.unlink_file(NodeId::ROOT, "orphan")?;
+            tx.set_xattr(orphan, b"user.foo", b"bar", SetMode::Upsert)
+        })
+        .unwrap();
+
+        assert_eq!(check(&fs.storage).unwrap(), []);
+    }
+
+    #[test]
+    fn leaked_block() {
+        let mut fs = format();
+        let addr = fs.block_alloc.allocate(1).unwrap();
+        fs.tx(|_| Ok(())).unwrap();
+
+        assert_repairs(&mut fs, &[Problem::Leaked(addr)]);
+    }
+
+    #[test]
+    fn bad_backup_superblock() {
+        let mut fs = format();
+        let addr = fs.superblock.backup_addrs()[0];
+        fs.storage.write_at(&Block::default(), addr).unwrap();
+
+        assert_repairs(&mut fs, &[Problem::BadBackupSuperblock(addr)]);
+    }
+
+    #[test]
+    fn unallocated_block() {
+        let mut fs = format();
+        let addr = fs.superblock.root_addr;
+        fs.block_alloc.set_allocated(addr, false);
+        fs.tx(|_| Ok(())).unwrap();
+
+        let owner = Owner::Tree;
+        assert_repairs(&mut fs, &[Problem::Unallocated { addr, owner }]);
+    }
+
+    #[test]
+    fn bad_links() {
+        let mut fs = format();
+        let id = fs
+            .tx(|tx| {
+                let id = tx.create_file(NodeId::ROOT, "file", FileType::File, perms())?;
+                let mut node = tx.read_node(id)?;
+                node.links.set(5);
+                tx.write_node(&node, id)?;
+                Ok(id)
+            })
+            .unwrap();
+
+        let problem = Problem::BadLinks {
+            id,
+            links: 5,
+            entries: 1,
+        };
+        assert_repairs(&mut fs, &[problem]);
+        assert_eq!(fs.read_tx(|tx| tx.read_node(id)).unwrap().links.get(), 1);
+    }
+
+    #[test]
+    fn missing_node() {
+        let mut fs = format();
+        let id = fs
+            .tx(|tx| {
+                let id = tx.create_file(NodeId::ROOT, "file", FileType::File, perms())?;
+                tx.remove_node(id)?;
+                Ok(id)
+            })
+            .unwrap();
+
+        let name = DirEntryName::try_from("file").unwrap();
+        let key = Key::direntry(NodeId::ROOT, name.hash());
+        assert_repairs(&mut fs, &[Problem::MissingNode { key, id }]);
+        assert!(
+            fs.read_tx(|tx| tx.find_entry(NodeId::ROOT, "file"))
+                .is_err()
+        );
+    }
+
+    /// Moves the entry of a new file in the root to the bucket after its name's, optionally
+    /// marking the name's bucket as overflowing into it.
+    /// Returns the entry's new key.
+    fn move_to_next_bucket(fs: &mut Filesystem<MemStorage>, overflow: bool) -> Key {
+        fs.tx(|tx| {
+            tx.create_file(NodeId::ROOT, "file", FileType::File, perms())?;
+            let entry = tx.find_entry(NodeId::ROOT, "file")?;
+            let first = entry.name.hash();
+            tx.remove_item(Key::direntry(NodeId::ROOT, first))?;
+
+            let key = Key::direntry(NodeId::ROOT, first + COLLISION_MASK + 1);
+            tx.insert_item(key, &entry.as_bytes())?;
+            if overflow {
+                tx.insert_item(Key::direntry(NodeId::ROOT, first | COLLISION_MASK), &[])?;
+            }
+            Ok(key)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn misplaced_entry() {
+        let mut fs = format();
+        let key = move_to_next_bucket(&mut fs, false);
+
+        assert_repairs(&mut fs, &[Problem::MisplacedEntry(key)]);
+        assert!(fs.read_tx(|tx| tx.find_entry(NodeId::ROOT, "file")).is_ok());
+    }
+
+    #[test]
+    fn overflowing_bucket() {
+        let mut fs = format();
+        move_to_next_bucket(&mut fs, true);
+
+        assert_eq!(check(&fs.storage).unwrap(), []);
+        assert!(fs.read_tx(|tx| tx.find_entry(NodeId::ROOT, "file")).is_ok());
+    }
+
+    #[test]
+    fn removed_dir_leaves_no_overflow_marker() {
+        let mut fs = format();
+        fs.tx(|tx| {
+            let dir = tx.create_dir(NodeId::ROOT, "dir", perms())?;
+            tx.insert_item(Key::direntry(dir, COLLISION_MASK), &[])?;
+            tx.remove_dir(NodeId::ROOT, "dir")?;
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(check(&fs.storage).unwrap(), []);
+    }
+
+    #[test]
+    fn unreachable_file() {
+        let mut fs = format();
+        let id = fs
+            .tx(|tx| tx.create_node(FileType::File, 1, perms()))
+            .unwrap();
+
+        assert_repairs(&mut fs, &[Problem::Unreachable(id)]);
+
+        let lost_and_found = fs
+            .read_tx(|tx| tx.find_entry(NodeId::ROOT, LOST_AND_FOUND))
+            .unwrap();
+        let entry = fs
+            .read_tx(|tx| tx.find_entry(lost_and_found.id, &format!("#{}", id.get())))
+            .unwrap();
+        assert_eq!(entry.id, id);
+    }
+
+    #[test]
+    fn unreachable_dir() {
+        let mut fs = format();
+        let (dir, file) = fs
+            .tx(|tx| {
+                let dir = tx.create_dir(NodeId::ROOT, "dir", perms())?;
+                let file = tx.create_file(dir, "file", FileType::File, perms())?;
+                let name = DirEntryName::try_from("dir")?;
+                tx.remove_item(Key::direntry(NodeId::ROOT, name.hash()))?;
+                Ok((dir, file))
+            })
+            .unwrap();
+
+        let expected = [Problem::Unreachable(dir), Problem::Unreachable(file)];
+        assert_repairs(&mut fs, &expected);
+
+        let lost_and_found = fs
+            .read_tx(|tx| tx.find_entry(NodeId::ROOT, LOST_AND_FOUND))
+            .unwrap();
+        let read_dir = fs.read_tx(|tx| tx.read_dir(dir)).unwrap();
+        let parent = read_dir
+            .iter()
+            .find(|entry| entry.name == DirEntryName::parent())
+            .unwrap();
+        assert_eq!(parent.id, lost_and_found.id);
+        assert_eq!(
+            fs.read_tx(|tx| tx.find_entry(dir, "file")).unwrap().id,
+            file
+        );
+    }
+
+    #[test]
+    fn bad_parent() {
+        let mut fs = format();
+        let (dir, other) = fs
+            .tx(|tx| {
+                let dir = tx.create_dir(NodeId::ROOT, "dir", perms())?;
+                let other = tx.create_dir(NodeId::ROOT, "other", perms())?;
+                let parent = DirEntry {
+                    filetype: FileType::Dir,
+                    id: other,
+                    name: DirEntryName::parent(),
+                };
+                tx.upsert_entry(dir, &parent)?;
+                Ok((dir, other))
+            })
+            .unwrap();
+        assert_ne!(dir, other);
+
+        let problem = Problem::BadParent {
+            id: dir,
+            parent: NodeId::ROOT,
+        };
+        assert_repairs(&mut fs, &[problem]);
+    }
+}
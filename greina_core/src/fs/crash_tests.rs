@@ -0,0 +1,502 @@
+//! Crash-consistency tests, losing power after every single write of a workload, along with any of
+//! the writes no flush followed yet.
+//! The surviving image must mount, pass the check and hold the state either before or after the
+//! interrupted transaction, the latter as soon as its commit record reached the device.
+
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use proptest::prelude::*;
+
+use crate::{
+    block::storage::crash::{self, CrashStorage},
+    fs::node::{FileType, Perms, dir::RenameMode},
+};
+
+use super::*;
+
+/// Large enough for the workloads, while small enough to copy the image for every crash.
+const BLOCK_COUNT: usize = 1024;
+
+/// Few names, so that operations often hit existing entries.
+const NAMES: [&str; 4] = ["a", "b", "c", "d"];
+
+/// A directory in the root entries can be moved into.
+const SUBDIR: &str = "sub";
+
+/// What a path leads to, as observed through the filesystem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Entry {
+    File { contents: Vec<u8>, links: u32 },
+    Dir,
+    Symlink(Box<[u8]>),
+}
+
+/// The entries reachable from the root, by path.
+type Snapshot = BTreeMap<String, Entry>;
+
+fn snapshot<S: Storage>(fs: &Filesystem<S>) -> Snapshot {
+    fs.read_tx(|tx| {
+        let mut snapshot = Snapshot::new();
+        let mut dirs = vec![(NodeId::ROOT, String::new())];
+        while let Some((dir, path)) = dirs.pop() {
+            for entry in tx.read_dir(dir)? {
+                let name = entry.name.as_str();
+                if name == "." || name == ".." {
+                    continue;
+                }
+                let path = format!("{path}/{name}");
+                let found = match entry.filetype {
+                    FileType::File => {
+                        let node = tx.read_node(entry.id)?;
+                        let mut contents = vec![0; node.size.get() as usize];
+                        tx.read_file_at(entry.id, 0, &mut contents)?;
+                        let links = node.links.get();
+                        Entry::File { contents, links }
+                    }
+                    FileType::Dir => {
+                        dirs.push((entry.id, path.clone()));
+                        Entry::Dir
+                    }
+                    FileType::Symlink => Entry::Symlink(tx.read_symlink(entry.id)?),
+                };
+                snapshot.insert(path, found);
+            }
+        }
+        Ok(snapshot)
+    })
+    .unwrap()
+}
+
+/// An operation on the entries of the root directory.
+#[derive(Debug, Clone)]
+enum Op {
+    CreateFile {
+        name: &'static str,
+        len: usize,
+        byte: u8,
+    },
+    CreateDir {
+        name: &'static str,
+    },
+    CreateSymlink {
+        name: &'static str,
+    },
+    Write {
+        name: &'static str,
+        offset: u64,
+        len: usize,
+        byte: u8,
+    },
+    Truncate {
+        name: &'static str,
+        size: u64,
+    },
+    Link {
+        name: &'static str,
+        new_name: &'static str,
+    },
+    Unlink {
+        name: &'static str,
+    },
+    RemoveDir {
+        name: &'static str,
+    },
+    Rename {
+        name: &'static str,
+        new_name: &'static str,
+        into_subdir: bool,
+    },
+}
+
+fn arb_name() -> impl Strategy<Value = &'static str> {
+    proptest::sample::select(&NAMES[..])
+}
+
+fn arb_op() -> impl Strategy<Value = Op> {
+    let len = 0..2 * BLOCK_SIZE as usize;
+    let offset = 0..3 * BLOCK_SIZE;
+    prop_oneof![
+        (arb_name(), len.clone(), any::<u8>()).prop_map(|(name, len, byte)| Op::CreateFile {
+            name,
+            len,
+            byte
+        }),
+        arb_name().prop_map(|name| Op::CreateDir { name }),
+        arb_name().prop_map(|name| Op::CreateSymlink { name }),
+        (arb_name(), offset.clone(), len, any::<u8>()).prop_map(|(name, offset, len, byte)| {
+            Op::Write {
+                name,
+                offset,
+                len,
+                byte,
+            }
+        }),
+        (arb_name(), offset).prop_map(|(name, size)| Op::Truncate { name, size }),
+        (arb_name(), arb_name()).prop_map(|(name, new_name)| Op::Link { name, new_name }),
+        arb_name().prop_map(|name| Op::Unlink { name }),
+        arb_name().prop_map(|name| Op::RemoveDir { name }),
+        (arb_name(), arb_name(), any::<bool>()).prop_map(|(name, new_name, into_subdir)| {
+            Op::Rename {
+                name,
+                new_name,
+                into_subdir,
+            }
+        }),
+    ]
+}
+
+/// Transactions of a few operations each.
+fn arb_workload() -> impl Strategy<Value = Vec<Vec<Op>>> {
+    prop::collection::vec(prop::collection::vec(arb_op(), 1..4), 1..6)
+}
+
+/// Returns the file `name` in the root directory.
+fn find_file(tx: &Transaction<impl Storage>, name: &str) -> Result<NodeId> {
+    let entry = tx.find_entry(NodeId::ROOT, name)?;
+    if entry.filetype != FileType::File {
+        return Err(Error::NotFile);
+    }
+    Ok(entry.id)
+}
+
+/// Applies `op`, leaving orphans for the next mount to remove.
+fn apply(tx: &mut Transaction<impl Storage>, op: &Op) -> Result<()> {
+    let perms = Perms::new(0o644, 0, 0);
+    match *op {
+        Op::CreateFile { name, len, byte } => {
+            let id = tx.create_file(NodeId::ROOT, name, FileType::File, perms)?;
+            tx.write_file_at(id, 0, &vec![byte; len])?;
+        }
+        Op::CreateDir { name } => {
+            tx.create_dir(NodeId::ROOT, name, perms)?;
+        }
+        Op::CreateSymlink { name } => {
+            tx.create_symlink(NodeId::ROOT, name, name, perms)?;
+        }
+        Op::Write {
+            name,
+            offset,
+            len,
+            byte,
+        } => {
+            let id = find_file(tx, name)?;
+            tx.write_file_at(id, offset, &vec![byte; len])?;
+        }
+        Op::Truncate { name, size } => {
+            let id = find_file(tx, name)?;
+            tx.truncate_file(id, size)?;
+        }
+        Op::Link { name, new_name } => {
+            let id = find_file(tx, name)?;
+            tx.link_file(NodeId::ROOT, id, new_name)?;
+        }
+        Op::Unlink { name } => {
+            tx.unlink_file(NodeId::ROOT, name)?;
+        }
+        Op::RemoveDir { name } => {
+            tx.remove_dir(NodeId::ROOT, name)?;
+        }
+        Op::Rename {
+            name,
+            new_name,
+            into_subdir,
+        } => {
+            let new_parent = match into_subdir {
+                true => tx.find_entry(NodeId::ROOT, SUBDIR)?.id,
+                false => NodeId::ROOT,
+            };
+            tx.rename_entry(
+                NodeId::ROOT,
+                name,
+                new_parent,
+                new_name,
+                RenameMode::Replace,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the image of a fresh filesystem holding [SUBDIR].
+fn formatted() -> Vec<Block> {
+    let storage = CrashStorage::new(vec![Block::default(); BLOCK_COUNT]);
+    let mut fs = Filesystem::format(storage.clone()).unwrap();
+    fs.tx(|tx| tx.create_dir(NodeId::ROOT, SUBDIR, Perms::new(0o755, 0, 0)))
+        .unwrap();
+    storage.blocks()
+}
+
+/// A committed transaction, by the writes it made.
+struct Committed {
+    start: usize,
+    end: usize,
+    // The write of the commit record, after which the transaction is durable
+    record: usize,
+    after: Snapshot,
+}
+
+/// How a crash can go: how many leading bytes of the next write reach the device if it's cut
+/// short, and which of the unflushed writes never reach it.
+type Crash<'a> = (Option<usize>, Box<dyn Fn(usize) -> bool + 'a>);
+
+/// Returns the ways to crash: cutting the next write short or not, then losing all of the
+/// unflushed writes, or those at the indices `lost` picks, repeated over all writes.
+fn crashes(lost: &[bool]) -> [Crash<'_>; 4] {
+    [
+        (None, Box::new(|_| false)),
+        (Some(BLOCK_SIZE as usize / 2), Box::new(|_| false)),
+        (None, Box::new(|_| true)),
+        (None, Box::new(|i| lost[i % lost.len()])),
+    ]
+}
+
+/// Mounts the image left by a crash, checks it and returns its state.
+fn recover(image: Vec<Block>) -> Snapshot {
+    let storage = CrashStorage::new(image);
+    let fs = Filesystem::mount(storage.clone()).expect("surviving image must mount");
+    assert_eq!(check::check(&storage).unwrap(), []);
+    snapshot(&fs)
+}
+
+/// Mounts the filesystem discarding freed blocks, which overwrites them.
+fn mount_discarding(storage: &CrashStorage) -> Filesystem<CrashStorage> {
+    let options = MountOptions {
+        discard: true,
+        ..Default::default()
+    };
+    Filesystem::mount_with(storage.clone(), &options).unwrap()
+}
+
+/// Returns how many blocks are discarded.
+fn discarded_count(storage: &CrashStorage) -> usize {
+    let discarded = Block::new(&[crash::DISCARDED; BLOCK_SIZE as usize]);
+    storage
+        .blocks()
+        .iter()
+        .filter(|&block| *block == discarded)
+        .count()
+}
+
+/// Returns the index of the commit record among the writes made since `start` writes.
+fn record_write(storage: &CrashStorage, journal_start: BlockAddr, start: usize) -> Option<usize> {
+    let writes = storage.writes();
+    (start..writes.len())
+        .find(|&i| writes[i].0 == journal_start && writes[i].1.starts_with(journal::SIGNATURE))
+}
+
+/// Runs the workload, then crashes after every write it made and checks what survives.
+fn check_crashes(workload: &[Vec<Op>], lost: &[bool]) {
+    let storage = CrashStorage::new(formatted());
+    let mut fs = mount_discarding(&storage);
+    let journal_start = fs.superblock().journal_start;
+    let initial = snapshot(&fs);
+
+    let mut txs = Vec::new();
+    for ops in workload {
+        let start = storage.write_count();
+        let result = fs.tx(|tx| {
+            // Invalid operations are rejected without changing anything, so the rest go on
+            for op in ops {
+                let _ = apply(tx, op);
+            }
+            Ok(())
+        });
+        if result.is_err() {
+            assert_eq!(storage.write_count(), start, "failed transaction wrote");
+            continue;
+        }
+        let end = storage.write_count();
+        let record = record_write(&storage, journal_start, start)
+            .expect("committed transaction must write a commit record");
+        let after = snapshot(&fs);
+        txs.push(Committed {
+            start,
+            end,
+            record,
+            after,
+        });
+    }
+    drop(fs);
+
+    let crashes = crashes(lost);
+    let mut before = &initial;
+    let mut txs = txs.iter().peekable();
+    for count in 0..=storage.write_count() {
+        while let Some(tx) = txs.next_if(|tx| tx.end <= count) {
+            before = &tx.after;
+        }
+        let tx = txs.peek().filter(|tx| tx.start <= count);
+        let unflushed = storage.unflushed(count);
+
+        for (torn, lost) in &crashes {
+            if torn.is_some() && count == storage.write_count() {
+                continue;
+            }
+            // The transaction is durable once its commit record reached the device
+            let committed = tx.is_some_and(|tx| match count.cmp(&tx.record) {
+                Ordering::Greater => !(unflushed.contains(&tx.record) && lost(tx.record)),
+                Ordering::Equal => torn.is_some(),
+                Ordering::Less => false,
+            });
+            let expected = match tx {
+                Some(tx) if committed => &tx.after,
+                _ => before,
+            };
+            let state = recover(storage.crash_image(count, *torn, lost));
+            // The states aren't printed, as they hold the files' contents
+            assert!(
+                state == *expected,
+                "crash after {count} writes, torn {torn:?}, losing {:?}, left paths {:?} instead \
+                 of {:?}",
+                unflushed.clone().filter(|&i| lost(i)).collect::<Vec<_>>(),
+                state.keys(),
+                expected.keys()
+            );
+        }
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(16))]
+
+    #[test]
+    fn survives_crashes(
+        workload in arb_workload(),
+        lost in prop::collection::vec(any::<bool>(), 1..16),
+    ) {
+        check_crashes(&workload, &lost);
+    }
+}
+
+#[test]
+fn discards_freed_blocks() {
+    let storage = CrashStorage::new(formatted());
+    let mut fs = mount_discarding(&storage);
+    let create = Op::CreateFile {
+        name: "a",
+        len: 4 * BLOCK_SIZE as usize,
+        byte: 0xAB,
+    };
+    fs.tx(|tx| apply(tx, &create)).unwrap();
+    let discarded = discarded_count(&storage);
+
+    fs.tx(|tx| apply(tx, &Op::Truncate { name: "a", size: 0 }))
+        .unwrap();
+    assert!(discarded_count(&storage) >= discarded + 4);
+    let after = snapshot(&fs);
+    drop(fs);
+    assert_eq!(recover(storage.blocks()), after);
+}
+
+#[test]
+fn trims_free_blocks() {
+    let storage = CrashStorage::new(formatted());
+    let mut fs = Filesystem::mount(storage.clone()).unwrap();
+    let create = Op::CreateFile {
+        name: "a",
+        len: 4 * BLOCK_SIZE as usize,
+        byte: 0xAB,
+    };
+    fs.tx(|tx| apply(tx, &create)).unwrap();
+    let before = snapshot(&fs);
+
+    let trimmed = fs.trim().unwrap();
+    assert_eq!(trimmed, fs.block_alloc().available());
+    assert_eq!(discarded_count(&storage) as u64, trimmed);
+    drop(fs);
+    assert_eq!(recover(storage.blocks()), before);
+}
+
+#[test]
+fn flushes_between_commit_stages() {
+    let storage = CrashStorage::new(formatted());
+    let mut fs = Filesystem::mount(storage.clone()).unwrap();
+    let journal_start = fs.superblock().journal_start;
+    let start = storage.write_count();
+    fs.tx(|tx| apply(tx, &Op::CreateDir { name: "a" })).unwrap();
+
+    let writes = storage.writes();
+    let record = record_write(&storage, journal_start, start).unwrap();
+    let clear = writes.len() - 1;
+    assert_eq!(writes[clear].0, journal_start);
+
+    // The logged blocks, the commit record, the checkpointed blocks and the cleared record each
+    // reach the device before what follows them is written
+    let flushes = storage.flushes();
+    for count in [record, record + 1, clear, clear + 1] {
+        assert!(flushes.contains(&count), "no flush after {count} writes");
+    }
+}
+
+#[test]
+fn keeps_transaction_when_checkpoint_fails() {
+    let create = Op::CreateFile {
+        name: "a",
+        len: 2 * BLOCK_SIZE as usize,
+        byte: 0xAB,
+    };
+
+    // Find the commit record on a copy, so as to fail the writes right after it
+    let copy = CrashStorage::new(formatted());
+    let mut fs = Filesystem::mount(copy.clone()).unwrap();
+    let journal_start = fs.superblock().journal_start;
+    let start = copy.write_count();
+    fs.tx(|tx| apply(tx, &create)).unwrap();
+    let record = record_write(&copy, journal_start, start).unwrap();
+    let after = snapshot(&fs);
+    drop(fs);
+
+    let storage = CrashStorage::new(formatted());
+    let mut fs = Filesystem::mount(storage.clone()).unwrap();
+    storage.fail_writes_after(Some(record + 1));
+    fs.tx(|tx| apply(tx, &create)).unwrap();
+
+    // Nothing goes on until the transaction is written home, which the journal allows
+    let res = fs.read_tx(|tx| tx.list_orphans());
+    assert!(matches!(res, Err(Error::Storage(libc::EIO))));
+    let res = fs.tx(|tx| apply(tx, &Op::CreateDir { name: "b" }));
+    assert!(matches!(res, Err(Error::Storage(libc::EIO))));
+    assert_eq!(recover(storage.blocks()), after);
+
+    storage.fail_writes_after(None);
+    assert_eq!(snapshot(&fs), after);
+    drop(fs);
+    assert_eq!(recover(storage.blocks()), after);
+}
+
+#[test]
+fn survives_crashes_during_replay() {
+    let storage = CrashStorage::new(formatted());
+    let mut fs = Filesystem::mount(storage.clone()).unwrap();
+    let journal_start = fs.superblock().journal_start;
+    let start = storage.write_count();
+    fs.tx(|tx| {
+        apply(tx, &Op::CreateDir { name: "a" })?;
+        apply(
+            tx,
+            &Op::CreateFile {
+                name: "b",
+                len: 2 * BLOCK_SIZE as usize,
+                byte: 0xAB,
+            },
+        )
+    })
+    .unwrap();
+    let after = snapshot(&fs);
+    drop(fs);
+
+    // Crash right after the commit record, leaving the journal to replay
+    let writes = storage.writes();
+    let record = (start..writes.len())
+        .find(|&i| writes[i].0 == journal_start)
+        .unwrap();
+    let replaying = CrashStorage::new(storage.crash_image(record + 1, None, |_| false));
+    Filesystem::mount(replaying.clone()).unwrap();
+
+    // Crash again while replaying
+    for count in 0..=replaying.write_count() {
+        for (torn, lost) in &crashes(&[true, false]) {
+            assert_eq!(recover(replaying.crash_image(count, *torn, lost)), after);
+        }
+    }
+}
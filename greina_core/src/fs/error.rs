@@ -9,15 +9,26 @@ pub enum Error {
     Tree(tree::Error),
 
     Uninterpretable,
+    TransactionTooLarge,
+
+    // Superblock
+    InvalidSuperblock,
+    InvalidLabel,
+    // The filesystem can't be read with unknown incompatible features
+    UnsupportedFeatures,
+    // The filesystem can't be written with unknown read-only compatible features
+    ReadOnlyFeatures,
 
     // Node
     NodeNotFound,
     NodeExists,
+    NotOrphan,
 
     // DirEntry
     InvalidName,
     DirEntryNotFound,
     DirEntryExists,
+    DirEntryNoSpace,
 
     // Dir
     DirNotEmpty,
@@ -27,9 +38,18 @@ pub enum Error {
 
     // File
     NotFile,
+    // A data block doesn't match its checksum
+    DataChecksum,
 
     // Symlink
     NotSymlink,
+
+    // Xattr
+    InvalidXattrName,
+    XattrTooLarge,
+    XattrNoSpace,
+    XattrNotFound,
+    XattrExists,
 }
 
 impl From<libc::c_int> for Error {
@@ -65,17 +85,30 @@ impl From<Error> for libc::c_int {
                 _ => libc::EIO,
             },
             Error::Uninterpretable => libc::EIO,
+            Error::TransactionTooLarge => libc::ENOSPC,
+            Error::InvalidSuperblock => libc::EINVAL,
+            Error::InvalidLabel => libc::EINVAL,
+            Error::UnsupportedFeatures => libc::EOPNOTSUPP,
+            Error::ReadOnlyFeatures => libc::EROFS,
             Error::NodeNotFound => libc::EIO,
             Error::NodeExists => libc::EIO,
+            Error::NotOrphan => libc::EINVAL,
             Error::InvalidName => libc::EINVAL,
             Error::DirEntryNotFound => libc::ENOENT,
             Error::DirEntryExists => libc::EEXIST,
+            Error::DirEntryNoSpace => libc::ENOSPC,
             Error::DirNotEmpty => libc::ENOTEMPTY,
             Error::IsDir => libc::EISDIR,
             Error::NotDir => libc::ENOTDIR,
             Error::InvalidMove => libc::EINVAL,
             Error::NotFile => libc::EINVAL,
+            Error::DataChecksum => libc::EIO,
             Error::NotSymlink => libc::EINVAL,
+            Error::InvalidXattrName => libc::ERANGE,
+            Error::XattrTooLarge => libc::E2BIG,
+            Error::XattrNoSpace => libc::ENOSPC,
+            Error::XattrNotFound => libc::ENODATA,
+            Error::XattrExists => libc::EEXIST,
         }
     }
 }
@@ -0,0 +1,308 @@
+use std::collections::BTreeMap;
+
+use zerocopy::{
+    FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned,
+    little_endian::{U32, U64},
+};
+
+use crate::{
+    block::{BLOCK_SIZE, Block, BlockAddr, BlockAddrStored, storage::Storage},
+    fs::error::{Error, Result},
+};
+
+/// Journal's signature.
+pub const SIGNATURE: &[u8; 8] = b"greinajl";
+
+/// The smallest journal a filesystem is formatted with.
+pub const MIN_LEN: u64 = 16;
+
+/// The largest journal a filesystem is formatted with.
+pub const MAX_LEN: u64 = 32768;
+
+/// How many block addresses fit in a descriptor block.
+const ADDRS_PER_BLOCK: usize = BLOCK_SIZE as usize / size_of::<BlockAddrStored>();
+
+/// A commit record stored in the first block of the journal.
+/// A transaction is durable once its commit record is written.
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
+struct CommitRecord {
+    signature: [u8; 8],
+    // The number of blocks logged by the transaction
+    block_count: U64,
+    // The checksum of the descriptor and data blocks
+    checksum: U32,
+}
+
+/// A write-ahead log of blocks.
+///
+/// # Layout
+/// The first block holds a [CommitRecord]. It is followed by descriptor blocks listing the home
+/// addresses of the logged blocks, which are followed by the logged blocks themselves.
+#[derive(Debug, Clone, Copy)]
+pub struct Journal {
+    start: BlockAddr,
+    len: u64,
+}
+
+impl Journal {
+    /// Constructs a journal spanning `len` blocks starting at `start`.
+    pub fn new(start: BlockAddr, len: u64) -> Self {
+        Self { start, len }
+    }
+
+    /// Returns the journal length for a filesystem of `block_count` blocks.
+    pub fn len_for(block_count: u64) -> u64 {
+        (block_count / 32).clamp(MIN_LEN, MAX_LEN)
+    }
+
+    /// Returns the number of blocks a single transaction can log.
+    pub fn capacity(&self) -> u64 {
+        // Each descriptor block covers 'ADDRS_PER_BLOCK' logged blocks
+        let per_group = ADDRS_PER_BLOCK as u64 + 1;
+        let avail = self.len.saturating_sub(1);
+        let groups = avail / per_group;
+        let remain = avail % per_group;
+        groups * ADDRS_PER_BLOCK as u64 + remain.saturating_sub(1)
+    }
+
+    /// Writes `blocks` followed by a commit record into the journal, flushing the storage after
+    /// each, so that the record is only found with the blocks it commits. Once this returns, the
+    /// transaction is durable: a crash leaves all of the blocks written once the journal is
+    /// replayed, until [Self::checkpoint] writes them and [Self::clear] empties the journal.
+    /// The `in_place` blocks aren't referred to before the commit, so they're written to their
+    /// addresses along with the log rather than logged, and flushed before the commit record.
+    pub fn log(
+        &self,
+        storage: &impl Storage,
+        in_place: &[(BlockAddr, &Block)],
+        blocks: &BTreeMap<BlockAddr, Block>,
+    ) -> Result<()> {
+        let block_count = blocks.len() as u64;
+        if block_count > self.capacity() {
+            return Err(Error::TransactionTooLarge);
+        }
+
+        let addrs: Vec<BlockAddrStored> = blocks.keys().map(|&addr| addr.into()).collect();
+        let descriptors: Vec<Block> = addrs
+            .chunks(ADDRS_PER_BLOCK)
+            .map(|chunk| Block::new(chunk.as_bytes()))
+            .collect();
+
+        let logged: Vec<_> = (self.start + 1..)
+            .zip(descriptors.iter().chain(blocks.values()))
+            .collect();
+        let checksum = logged.iter().fold(0, |checksum, (_, block)| {
+            crc32c::crc32c_append(checksum, &block[..])
+        });
+        storage.write_blocks(in_place)?;
+        storage.write_blocks(&logged)?;
+        storage.flush()?;
+
+        let record = CommitRecord {
+            signature: *SIGNATURE,
+            block_count: block_count.into(),
+            checksum: checksum.into(),
+        };
+        storage.write_at(&Block::new(record.as_bytes()), self.start)?;
+        storage.flush()?;
+
+        Ok(())
+    }
+
+    /// Writes `blocks` to their addresses.
+    /// The journal mustn't be cleared before they're flushed.
+    pub fn checkpoint(storage: &impl Storage, blocks: &BTreeMap<BlockAddr, Block>) -> Result<()> {
+        let blocks: Vec<_> = blocks.iter().map(|(&addr, block)| (addr, block)).collect();
+        storage.write_blocks(&blocks)?;
+        Ok(())
+    }
+
+    /// Marks the journal as empty.
+    /// The storage is flushed, as a stale commit record reaching the device after the next
+    /// transaction's blocks could pass their checksum: blocks embedding their own CRC all add up
+    /// to the same one.
+    pub fn clear(&self, storage: &impl Storage) -> Result<()> {
+        storage.write_at(&Block::default(), self.start)?;
+        storage.flush()?;
+        Ok(())
+    }
+
+    /// Replays a transaction committed to the journal, which stays logged until [Self::clear].
+    /// An incomplete transaction is discarded.
+    /// Returns whether a transaction was replayed.
+    pub fn replay(&self, storage: &impl Storage) -> Result<bool> {
+        let Some(blocks) = self.read(storage)? else {
+            return Ok(false);
+        };
+        Self::checkpoint(storage, &blocks)?;
+        Ok(true)
+    }
+
+    /// Checks whether the journal holds a committed transaction that wasn't replayed.
+    pub fn is_pending(&self, storage: &impl Storage) -> Result<bool> {
+        Ok(self.read(storage)?.is_some())
+    }
+
+    /// Reads the blocks of a committed transaction.
+    /// Returns `None` if the journal doesn't hold a complete transaction.
+    fn read(&self, storage: &impl Storage) -> Result<Option<BTreeMap<BlockAddr, Block>>> {
+        let mut block = Block::default();
+        storage.read_at(&mut block, self.start)?;
+        let (record, _) =
+            CommitRecord::read_from_prefix(&block[..]).map_err(|_| Error::Uninterpretable)?;
+
+        let block_count = record.block_count.get();
+        if record.signature != *SIGNATURE || block_count > self.capacity() {
+            return Ok(None);
+        }
+
+        let block_count = block_count as usize;
+        let descriptor_count = block_count.div_ceil(ADDRS_PER_BLOCK);
+
+        let mut logged = vec![Block::default(); descriptor_count + block_count];
+        let mut pairs: Vec<_> = (self.start + 1..).zip(&mut logged).collect();
+        storage.read_blocks(&mut pairs)?;
+        let checksum = logged.iter().fold(0, |checksum, block| {
+            crc32c::crc32c_append(checksum, &block[..])
+        });
+        if checksum != record.checksum.get() {
+            return Ok(None);
+        }
+        let (descriptors, data) = logged.split_at(descriptor_count);
+
+        let mut addrs = Vec::with_capacity(block_count);
+        for descriptor in descriptors {
+            let remain = (block_count - addrs.len()).min(ADDRS_PER_BLOCK);
+            let (chunk, _) =
+                <[BlockAddrStored]>::ref_from_prefix_with_elems(&descriptor[..], remain)
+                    .map_err(|_| Error::Uninterpretable)?;
+            addrs.extend(chunk.iter().map(|&addr| BlockAddr::from(addr)));
+        }
+        Ok(Some(addrs.into_iter().zip(data.iter().copied()).collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::block::storage::fake::FakeStorage;
+
+    const HOME: BlockAddr = 64;
+
+    fn setup() -> (FakeStorage, Journal) {
+        let storage = FakeStorage::default();
+        let journal = Journal::new(0, MIN_LEN);
+        journal.clear(&storage).unwrap();
+        (storage, journal)
+    }
+
+    fn blocks(count: u64) -> BTreeMap<BlockAddr, Block> {
+        (0..count)
+            .map(|i| {
+                let mut block = Block::default();
+                block.fill(i as u8 + 1);
+                (HOME + i, block)
+            })
+            .collect()
+    }
+
+    fn assert_written(storage: &FakeStorage, blocks: &BTreeMap<BlockAddr, Block>) {
+        for (&addr, expected) in blocks {
+            let mut block = Block::default();
+            storage.read_at(&mut block, addr).unwrap();
+            assert_eq!(&block, expected);
+        }
+    }
+
+    #[test]
+    fn capacity() {
+        assert_eq!(Journal::new(0, 1).capacity(), 0);
+        assert_eq!(Journal::new(0, 3).capacity(), 1);
+        assert_eq!(Journal::new(0, 2 + ADDRS_PER_BLOCK as u64).capacity(), 512);
+        assert_eq!(Journal::new(0, 4 + ADDRS_PER_BLOCK as u64).capacity(), 513);
+    }
+
+    #[test]
+    fn commit() {
+        let (storage, journal) = setup();
+        let blocks = blocks(4);
+
+        journal.log(&storage, &[], &blocks).unwrap();
+        Journal::checkpoint(&storage, &blocks).unwrap();
+
+        assert_written(&storage, &blocks);
+        assert!(journal.is_pending(&storage).unwrap());
+        journal.clear(&storage).unwrap();
+        assert!(!journal.replay(&storage).unwrap());
+    }
+
+    #[test]
+    fn commit_in_place() {
+        let (storage, journal) = setup();
+        let blocks = blocks(journal.capacity());
+        let mut fresh = Block::default();
+        fresh.fill(0xEE);
+        let in_place = [(HOME - 1, &fresh)];
+
+        // The blocks written in place don't take room in the journal
+        journal.log(&storage, &in_place, &blocks).unwrap();
+        Journal::checkpoint(&storage, &blocks).unwrap();
+
+        assert_written(&storage, &blocks);
+        assert_written(&storage, &BTreeMap::from([(HOME - 1, fresh)]));
+    }
+
+    #[test]
+    fn commit_too_large() {
+        let (storage, journal) = setup();
+        let blocks = blocks(journal.capacity() + 1);
+
+        let result = journal.log(&storage, &[], &blocks);
+        assert!(matches!(result, Err(Error::TransactionTooLarge)));
+    }
+
+    #[test]
+    fn replay_committed() {
+        let (storage, journal) = setup();
+        let blocks = blocks(journal.capacity());
+
+        journal.log(&storage, &[], &blocks).unwrap();
+        assert!(journal.is_pending(&storage).unwrap());
+
+        assert!(journal.replay(&storage).unwrap());
+        assert_written(&storage, &blocks);
+        journal.clear(&storage).unwrap();
+        assert!(!journal.replay(&storage).unwrap());
+    }
+
+    #[test]
+    fn discard_uncommitted() {
+        let (storage, journal) = setup();
+        let blocks = blocks(4);
+
+        journal.log(&storage, &[], &blocks).unwrap();
+        journal.clear(&storage).unwrap();
+
+        assert!(!journal.is_pending(&storage).unwrap());
+        assert!(!journal.replay(&storage).unwrap());
+        let mut block = Block::default();
+        assert!(storage.read_at(&mut block, HOME).is_err());
+    }
+
+    #[test]
+    fn discard_torn() {
+        let (storage, journal) = setup();
+        let blocks = blocks(4);
+
+        journal.log(&storage, &[], &blocks).unwrap();
+        let mut garbage = Block::default();
+        garbage.fill(0xFF);
+        storage.write_at(&garbage, journal.start + 3).unwrap();
+
+        assert!(!journal.replay(&storage).unwrap());
+        let mut block = Block::default();
+        assert!(storage.read_at(&mut block, HOME).is_err());
+    }
+}
@@ -1,10 +1,16 @@
 pub mod error;
 use error::*;
 
+pub mod check;
+#[cfg(test)]
+mod crash_tests;
+pub mod journal;
 pub mod node;
 pub mod superblock;
 pub mod transaction;
 
+use std::{collections::BTreeMap, sync::Mutex};
+
 use zerocopy::{FromBytes, IntoBytes};
 
 use crate::{
@@ -14,9 +20,10 @@ use crate::{
         storage::{self, Storage},
     },
     fs::{
+        journal::Journal,
         node::NodeId,
-        superblock::{SUPER_ADDR, Superblock},
-        transaction::Transaction,
+        superblock::{SUPER_ADDR, Superblock, Uuid},
+        transaction::{ReadTransaction, Transaction},
     },
     tree::Tree,
 };
@@ -26,14 +33,47 @@ pub struct Filesystem<S: Storage> {
     storage: S,
     superblock: Superblock,
     block_alloc: BitmapAllocator,
+    journal: Journal,
+    // Whether blocks are discarded once freed
+    discard: bool,
+    // Blocks of a committed transaction whose checkpoint failed, see [Filesystem::tx]
+    pending: Mutex<Option<BTreeMap<BlockAddr, Block>>>,
+}
+
+/// Options for formatting a filesystem.
+#[derive(Debug, Default, Clone)]
+pub struct FormatOptions {
+    // Checksum file data blocks, see [node::checksum]
+    pub data_checksums: bool,
+    // At most 'superblock::LABEL_MAX_LEN' bytes
+    pub label: String,
+    // Generated randomly if not given
+    pub uuid: Option<Uuid>,
+    // The owner of the root directory, root by default
+    pub root_uid: u32,
+    pub root_gid: u32,
+}
+
+/// Options for mounting a filesystem.
+#[derive(Debug, Default, Clone)]
+pub struct MountOptions {
+    // Fall back to a backup of the superblock if the primary one is invalid
+    pub backup_superblock: bool,
+    // Discard blocks as transactions free them
+    pub discard: bool,
 }
 
 impl<S: Storage> Filesystem<S> {
+    /// Formats a storage device with a filesystem, using the default options.
+    pub fn format(storage: S) -> Result<Self> {
+        Self::format_with(storage, &FormatOptions::default())
+    }
+
     /// Formats a storage device with a filesystem.
     ///
     /// # Panics
     /// ...
-    pub fn format(mut storage: S) -> Result<Self> {
+    pub fn format_with(mut storage: S, options: &FormatOptions) -> Result<Self> {
         let block_count = storage.capacity()?;
 
         let mut block_alloc = BitmapAllocator::new(block_count);
@@ -41,23 +81,42 @@ impl<S: Storage> Filesystem<S> {
         Self::allocate_block_alloc(&mut block_alloc, block_count);
 
         let mut superblock = Superblock::new(block_count);
+        superblock.set_label(&options.label)?;
+        superblock.uuid = match options.uuid {
+            Some(uuid) => uuid,
+            None => Uuid::random()?,
+        };
+        if options.data_checksums {
+            superblock.features.ro_compat |= superblock::RO_COMPAT_DATA_CHECKSUMS;
+        }
+        let journal = Self::format_journal(&mut storage, &mut block_alloc, &superblock)?;
+        Self::allocate_backup_superblocks(&mut block_alloc, &superblock);
         Self::format_root(&mut storage, &mut block_alloc, &mut superblock)?;
+        Self::write_block_alloc(&mut storage, &block_alloc, superblock.block_alloc_start)?;
+        block_alloc.clear_dirty();
 
+        // The superblock is written last, so that a crash doesn't leave one describing
+        // structures that weren't written
+        storage.flush()?;
         Self::write_superblock(&mut storage, &superblock)?;
-        Self::write_block_alloc(&mut storage, &block_alloc, superblock.block_alloc_start)?;
+        Self::write_backup_superblocks(&storage, &superblock)?;
 
         // Create filesystem
         let mut fs = Filesystem {
             storage,
             superblock,
             block_alloc,
+            journal,
+            discard: false,
+            pending: Mutex::default(),
         };
 
         {
             // Initialize the root directory
             let mut tx = Transaction::new(&mut fs);
+            let perms = node::Perms::new(0o755, options.root_uid, options.root_gid);
             let root_id = tx
-                .create_root_dir()
+                .create_root_dir(perms)
                 .expect("Must be able to create the root node");
             assert!(root_id == NodeId::ROOT);
             tx.commit()?;
@@ -74,8 +133,22 @@ impl<S: Storage> Filesystem<S> {
     }
 
     fn write_superblock(storage: &mut S, superblock: &Superblock) -> storage::Result<()> {
-        let block = Block::new(superblock.as_bytes());
-        storage.write_at(&block, SUPER_ADDR)
+        storage.write_at(&superblock.encode(), SUPER_ADDR)
+    }
+
+    fn allocate_backup_superblocks(block_alloc: &mut BitmapAllocator, superblock: &Superblock) {
+        for addr in superblock.backup_addrs() {
+            block_alloc.set_allocated(addr, true);
+        }
+    }
+
+    /// Writes the superblock's backups, see [Superblock::backup_addrs].
+    fn write_backup_superblocks(storage: &S, superblock: &Superblock) -> storage::Result<()> {
+        let block = superblock.encode();
+        for addr in superblock.backup_addrs() {
+            storage.write_at(&block, addr)?;
+        }
+        Ok(())
     }
 
     fn allocate_block_alloc(block_alloc: &mut BitmapAllocator, block_count: u64) {
@@ -92,22 +165,38 @@ impl<S: Storage> Filesystem<S> {
         block_alloc: &BitmapAllocator,
         start: BlockAddr,
     ) -> storage::Result<()> {
-        let mut addr = start;
-        let bytes = block_alloc.as_bytes();
-        let (chunks, remainder) = bytes.as_chunks::<{ BLOCK_SIZE as usize }>();
-
-        for chunk in chunks {
-            let block = Block::ref_from_bytes(chunk).expect("'Block' is unaligned");
-            storage.write_at(block, addr)?;
-            addr += 1;
-        }
+        block_alloc.with_bytes(|bytes| {
+            let mut addr = start;
+            let (chunks, remainder) = bytes.as_chunks::<{ BLOCK_SIZE as usize }>();
 
-        if !remainder.is_empty() {
-            let block = Block::new(remainder);
-            storage.write_at(&block, addr)?;
-        }
+            for chunk in chunks {
+                let block = Block::ref_from_bytes(chunk).expect("'Block' is unaligned");
+                storage.write_at(block, addr)?;
+                addr += 1;
+            }
 
-        Ok(())
+            if !remainder.is_empty() {
+                let block = Block::new(remainder);
+                storage.write_at(&block, addr)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn format_journal(
+        storage: &mut S,
+        block_alloc: &mut BitmapAllocator,
+        superblock: &Superblock,
+    ) -> Result<Journal> {
+        let addr = block_alloc.allocate(superblock.journal_len)?;
+        assert_eq!(
+            addr, superblock.journal_start,
+            "journal must start after the allocator"
+        );
+        let journal = superblock.journal();
+        journal.clear(storage)?;
+        Ok(journal)
     }
 
     fn format_root(
@@ -121,62 +210,179 @@ impl<S: Storage> Filesystem<S> {
         Ok(())
     }
 
+    /// Mounts the filesystem from a storage device, using the default options.
+    pub fn mount(storage: S) -> storage::Result<Self> {
+        Self::mount_with(storage, &MountOptions::default())
+    }
+
     /// Mounts the filesystem from a storage device.
     ///
     /// # Panics
     /// ...
-    pub fn mount(mut storage: S) -> storage::Result<Self> {
-        let superblock = Self::read_superblock(&mut storage)?;
-        if superblock.signature != *superblock::SIGNATURE {
-            return Err(libc::EINVAL);
+    pub fn mount_with(storage: S, options: &MountOptions) -> storage::Result<Self> {
+        let mut fs = Self::open_with(storage, options)?;
+
+        // Nothing references orphans left over by a crash anymore. Removing them takes space,
+        // as the tree is copied on write, so they're left for later on a full filesystem.
+        if let Err(err) = fs.remove_orphans() {
+            match err {
+                Error::Allocator(block::allocator::Error::NoSpace) | Error::TransactionTooLarge => {
+                    log::warn!("orphans are left in place for lack of space: {err:?}");
+                }
+                err => return Err(err.into()),
+            }
         }
-        let block_alloc = Self::read_block_alloc(&mut storage, &superblock)?;
+
+        Ok(fs)
+    }
+
+    /// Opens the filesystem from a storage device, using the default options.
+    pub fn open(storage: S) -> storage::Result<Self> {
+        Self::open_with(storage, &MountOptions::default())
+    }
+
+    /// Opens the filesystem from a storage device, replaying the journal but leaving orphans
+    /// in place, as removing them isn't safe until the filesystem is known to be consistent.
+    /// See [check::repair].
+    ///
+    /// Fails if the filesystem was formatted with features this implementation doesn't know of.
+    pub fn open_with(storage: S, options: &MountOptions) -> storage::Result<Self> {
+        let mut superblock = match Self::read_superblock(&storage) {
+            Err(Error::InvalidSuperblock) if options.backup_superblock => {
+                Self::recover_superblock(&storage)
+            }
+            res => res,
+        }
+        .map_err(libc::c_int::from)?;
+        superblock.check_features().map_err(libc::c_int::from)?;
+
+        // Finish or discard a transaction interrupted by a crash
+        let journal = superblock.journal();
+        if journal.replay(&storage).map_err(libc::c_int::from)? {
+            superblock = Self::read_superblock(&storage).map_err(libc::c_int::from)?;
+            // The backups may not have been written after the replayed transaction
+            Self::write_backup_superblocks(&storage, &superblock)?;
+            storage.flush()?;
+            journal.clear(&storage).map_err(libc::c_int::from)?;
+        }
+
+        let block_alloc =
+            Self::read_block_alloc(&storage, &superblock).map_err(libc::c_int::from)?;
         Ok(Self {
             storage,
             superblock,
             block_alloc,
+            journal,
+            discard: options.discard,
+            pending: Mutex::default(),
         })
     }
 
-    fn read_superblock(storage: &mut S) -> storage::Result<Superblock> {
+    /// Removes every orphan, each in its own transaction to bound the journal usage.
+    fn remove_orphans(&mut self) -> Result<()> {
+        let orphans = self.read_tx(|tx| tx.list_orphans())?;
+        for id in orphans {
+            self.tx(|tx| tx.remove_orphan(id))?;
+        }
+        Ok(())
+    }
+
+    /// Reads the superblock at `addr`, checking that it describes a filesystem fitting in
+    /// the storage.
+    fn read_superblock_at(storage: &S, addr: BlockAddr) -> Result<Superblock> {
         let mut block = Block::default();
-        storage.read_at(&mut block, 0)?;
-        let (superblock, _) = Superblock::read_from_prefix(&block[..])
-            .expect("'block.data' must be a valid 'Superblock'");
+        storage.read_at(&mut block, addr)?;
+        let superblock = Superblock::decode(&block)?;
+        superblock.validate(storage.capacity()?)?;
         Ok(superblock)
     }
 
-    fn read_block_alloc(
-        storage: &mut S,
-        superblock: &Superblock,
-    ) -> storage::Result<BitmapAllocator> {
+    fn read_superblock(storage: &S) -> Result<Superblock> {
+        Self::read_superblock_at(storage, SUPER_ADDR)
+    }
+
+    /// Restores the primary superblock from the first valid backup.
+    /// The backups are found from the storage's capacity, which must not have changed since
+    /// the filesystem was formatted.
+    fn recover_superblock(storage: &S) -> Result<Superblock> {
+        let capacity = storage.capacity()?;
+        for addr in Superblock::new(capacity).backup_addrs() {
+            if let Ok(superblock) = Self::read_superblock_at(storage, addr) {
+                log::warn!("superblock is invalid, restoring it from the backup at {addr}");
+                storage.write_at(&superblock.encode(), SUPER_ADDR)?;
+                return Ok(superblock);
+            }
+        }
+        Err(Error::InvalidSuperblock)
+    }
+
+    fn read_block_alloc(storage: &S, superblock: &Superblock) -> Result<BitmapAllocator> {
         let bytes = superblock.block_count.div_ceil(8);
         let blocks = bytes.div_ceil(BLOCK_SIZE);
 
         let mut blocks = vec![Block::default(); blocks as usize];
-        let mut addr = superblock.block_alloc_start;
-        for block in &mut blocks {
+        for (addr, block) in (superblock.block_alloc_start..).zip(&mut blocks) {
             storage.read_at(block, addr)?;
-            addr += 1;
         }
 
-        let allocator = BitmapAllocator::from_bytes(superblock.block_count, blocks.as_bytes());
-        Ok(allocator)
+        BitmapAllocator::from_bytes(superblock.block_count, blocks.as_bytes())
+            .ok_or(Error::Uninterpretable)
+    }
+
+    /// Writes the `logged` blocks of a durable transaction home, then clears the journal.
+    /// The backups are written outside the journal, but before it's cleared, so that a crash in
+    /// between replays the transaction and writes them again.
+    fn checkpoint(
+        storage: &S,
+        journal: &Journal,
+        superblock: &Superblock,
+        logged: &BTreeMap<BlockAddr, Block>,
+    ) -> Result<()> {
+        Journal::checkpoint(storage, logged)?;
+        Self::write_backup_superblocks(storage, superblock)?;
+        storage.flush()?;
+        journal.clear(storage)
+    }
+
+    /// Retries the checkpoint of the last transaction, if it failed.
+    /// Until it succeeds, the storage lags behind the committed state and the journal holds the
+    /// only copy of it, so nothing else may read or write the filesystem.
+    fn finish_checkpoint(&self) -> Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(logged) = &*pending {
+            Self::checkpoint(&self.storage, &self.journal, &self.superblock, logged)?;
+            *pending = None;
+        }
+        Ok(())
     }
 
     /// Executes a given closure within the context of a transaction.
     /// If the closure returns `Ok`, the transaction is commited to storage.
     /// Else if `Err` is returned, the transaction is discarded and no changes are made.
+    /// A transaction is kept once it's durable, even if writing it home fails. The checkpoint is
+    /// then retried by the next transaction, which fails until it succeeds.
     pub fn tx<F, T>(&mut self, f: F) -> Result<T>
     where
         F: FnOnce(&mut Transaction<S>) -> Result<T>,
     {
+        self.finish_checkpoint()?;
         let mut tx = Transaction::new(self);
         let res = f(&mut tx)?;
         tx.commit()?;
         Ok(res)
     }
 
+    /// Executes a given closure within the context of a read-only transaction.
+    /// Nothing is written to storage.
+    pub fn read_tx<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&ReadTransaction<S>) -> Result<T>,
+    {
+        self.finish_checkpoint()?;
+        let tx = ReadTransaction::new(&self.storage, &self.superblock);
+        f(&tx)
+    }
+
     pub fn superblock(&self) -> &Superblock {
         &self.superblock
     }
@@ -184,4 +390,240 @@ impl<S: Storage> Filesystem<S> {
     pub fn block_alloc(&self) -> &impl block::Allocator {
         &self.block_alloc
     }
+
+    /// Waits for everything written to reach the device.
+    /// Transactions are durable once committed, so this only spares replaying the last one on
+    /// the next mount.
+    pub fn flush(&self) -> storage::Result<()> {
+        self.storage.flush()
+    }
+
+    /// Discards every free block, see [Storage::discard].
+    /// Returns how many blocks were discarded.
+    pub fn trim(&self) -> storage::Result<u64> {
+        let mut discarded = 0;
+        for (start, count) in self.block_alloc.free_extents() {
+            self.storage.discard(start, count)?;
+            discarded += count;
+        }
+        Ok(discarded)
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        block::storage::{crash::CrashStorage, mem::MemStorage},
+        fs::node::Perms,
+    };
+
+    #[test]
+    fn mount_removes_orphans() {
+        let storage = MemStorage::new(1024);
+        let mut fs = Filesystem::format(storage.clone()).unwrap();
+        let available = fs.block_alloc().available();
+
+        let orphan = fs
+            .tx(|tx| {
+                let perms = Perms::new(0o644, 0, 0);
+                let id = tx.create_file(NodeId::ROOT, "file", node::FileType::File, perms)?;
+                tx.write_file_at(id, 0, &[0xAB; 3 * BLOCK_SIZE as usize])?;
+                tx.unlink_file(NodeId::ROOT, "file")
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(fs.read_tx(|tx| tx.list_orphans()).unwrap(), [orphan]);
+        assert!(fs.block_alloc().available() < available);
+        drop(fs);
+
+        let fs = Filesystem::mount(storage.clone()).unwrap();
+        assert!(fs.read_tx(|tx| tx.list_orphans()).unwrap().is_empty());
+        assert!(fs.read_tx(|tx| tx.read_node(orphan)).is_err());
+        assert_eq!(fs.block_alloc().available(), available);
+    }
+
+    #[test]
+    fn format_stores_label_and_uuid() {
+        let options = FormatOptions {
+            label: "scratch".to_owned(),
+            ..Default::default()
+        };
+        let storage = MemStorage::new(1024);
+        let uuid = Filesystem::format_with(storage.clone(), &options)
+            .unwrap()
+            .superblock()
+            .uuid;
+        assert_ne!(uuid, Uuid::default());
+
+        let fs = Filesystem::mount(storage.clone()).unwrap();
+        assert_eq!(fs.superblock().label(), "scratch");
+        assert_eq!(fs.superblock().uuid, uuid);
+
+        let options = FormatOptions {
+            label: "a".repeat(superblock::LABEL_MAX_LEN + 1),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Filesystem::format_with(storage, &options),
+            Err(Error::InvalidLabel)
+        ));
+    }
+
+    #[test]
+    fn format_sets_root_owner() {
+        let storage = MemStorage::new(1024);
+        let fs = Filesystem::format(storage.clone()).unwrap();
+        let root = fs.read_tx(|tx| tx.read_node(NodeId::ROOT)).unwrap();
+        assert_eq!((root.uid.get(), root.gid.get()), (0, 0));
+
+        let options = FormatOptions {
+            root_uid: 1000,
+            root_gid: 100,
+            ..Default::default()
+        };
+        let fs = Filesystem::format_with(storage, &options).unwrap();
+        let root = fs.read_tx(|tx| tx.read_node(NodeId::ROOT)).unwrap();
+        assert_eq!((root.uid.get(), root.gid.get()), (1000, 100));
+    }
+
+    #[test]
+    fn mount_falls_back_to_backup_superblock() {
+        let storage = MemStorage::new(1024);
+        let mut fs = Filesystem::format(storage.clone()).unwrap();
+        let id = fs
+            .tx(|tx| {
+                let perms = Perms::new(0o644, 0, 0);
+                tx.create_file(NodeId::ROOT, "file", node::FileType::File, perms)
+            })
+            .unwrap();
+        fs.storage.write_at(&Block::default(), SUPER_ADDR).unwrap();
+        drop(fs);
+
+        assert_eq!(Filesystem::mount(storage.clone()).err(), Some(libc::EINVAL));
+
+        let options = MountOptions {
+            backup_superblock: true,
+            ..Default::default()
+        };
+        let fs = Filesystem::mount_with(storage.clone(), &options).unwrap();
+        assert!(fs.read_tx(|tx| tx.read_node(id)).is_ok());
+        drop(fs);
+
+        // The primary superblock was restored
+        let fs = Filesystem::mount(storage.clone()).unwrap();
+        assert!(fs.read_tx(|tx| tx.read_node(id)).is_ok());
+    }
+
+    #[test]
+    fn mount_rejects_truncated_image() {
+        let storage = MemStorage::new(1024);
+        Filesystem::format(storage.clone()).unwrap();
+
+        let bytes = storage.to_bytes();
+        let storage = MemStorage::from_bytes(&bytes[..512 * BLOCK_SIZE as usize]).unwrap();
+        assert_eq!(Filesystem::mount(storage).err(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn mount_refuses_unknown_features() {
+        let mut storage = MemStorage::new(1024);
+        let fs = Filesystem::format(storage.clone()).unwrap();
+        let mut superblock = fs.superblock().clone();
+        drop(fs);
+
+        superblock.features.compat |= 1 << 63;
+        Filesystem::write_superblock(&mut storage, &superblock).unwrap();
+        Filesystem::mount(storage.clone()).unwrap();
+
+        superblock.features.incompat |= 1 << 63;
+        Filesystem::write_superblock(&mut storage, &superblock).unwrap();
+        assert_eq!(Filesystem::mount(storage).err(), Some(libc::EOPNOTSUPP));
+    }
+
+    #[test]
+    fn failed_commit_leaves_allocator_untouched() {
+        let storage = CrashStorage::new(vec![Block::default(); 1024]);
+        let mut fs = Filesystem::format(storage.clone()).unwrap();
+        let perms = Perms::new(0o644, 0, 0);
+        let id = fs
+            .tx(|tx| {
+                let id = tx.create_file(NodeId::ROOT, "file", node::FileType::File, perms)?;
+                tx.write_file_at(id, 0, &[0xAB; 3 * BLOCK_SIZE as usize])?;
+                Ok(id)
+            })
+            .unwrap();
+        let available = fs.block_alloc().available();
+
+        // Frees the file's blocks, then fails to commit as the storage fails
+        storage.fail_writes_after(Some(storage.write_count()));
+        let res = fs.tx(|tx| {
+            tx.truncate_file(id, 0)?;
+            let big = tx.create_file(NodeId::ROOT, "big", node::FileType::File, perms)?;
+            tx.write_file_at(big, 0, &[0xCD; 4 * BLOCK_SIZE as usize])
+        });
+        assert!(matches!(res, Err(Error::Storage(libc::EIO))));
+        assert_eq!(fs.block_alloc().available(), available);
+        storage.fail_writes_after(None);
+
+        fs.tx(|tx| tx.create_dir(NodeId::ROOT, "dir", perms))
+            .unwrap();
+        assert_eq!(check::check(&fs.storage).unwrap(), []);
+    }
+
+    #[test]
+    fn writes_past_journal_capacity() {
+        let storage = MemStorage::new(1024);
+        let mut fs = Filesystem::format(storage.clone()).unwrap();
+        let perms = Perms::new(0o644, 0, 0);
+        let len = 4 * fs.journal.capacity() as usize * BLOCK_SIZE as usize;
+
+        // New data is written in place and overwritten data moved to new blocks, so neither is
+        // logged
+        let id = fs
+            .tx(|tx| {
+                let id = tx.create_file(NodeId::ROOT, "file", node::FileType::File, perms)?;
+                tx.write_file_at(id, 0, &vec![0xAB; len])?;
+                Ok(id)
+            })
+            .unwrap();
+        fs.tx(|tx| tx.write_file_at(id, 1, &vec![0xCD; len - 2]))
+            .unwrap();
+
+        let mut expected = vec![0xCD; len];
+        expected[0] = 0xAB;
+        expected[len - 1] = 0xAB;
+        let mut buf = vec![0; len];
+        fs.read_tx(|tx| tx.read_file_at(id, 0, &mut buf)).unwrap();
+        assert_eq!(buf, expected);
+        assert_eq!(check::check(&fs.storage).unwrap(), []);
+    }
+
+    #[test]
+    fn mount_keeps_orphans_when_full() {
+        let storage = MemStorage::new(1024);
+        let mut fs = Filesystem::format(storage.clone()).unwrap();
+        let orphan = fs
+            .tx(|tx| {
+                let perms = Perms::new(0o644, 0, 0);
+                let id = tx.create_file(NodeId::ROOT, "file", node::FileType::File, perms)?;
+                tx.write_file_at(id, 0, &[0xAB; 3 * BLOCK_SIZE as usize])?;
+                tx.unlink_file(NodeId::ROOT, "file")
+            })
+            .unwrap()
+            .unwrap();
+
+        // Fills the filesystem up, so not even removing the orphan can be written
+        while fs.block_alloc().allocate(1).is_ok() {}
+        fs.tx(|_| Ok(())).unwrap();
+        drop(fs);
+
+        let fs = Filesystem::mount(storage.clone()).unwrap();
+        assert_eq!(fs.read_tx(|tx| tx.list_orphans()).unwrap(), [orphan]);
+    }
 }
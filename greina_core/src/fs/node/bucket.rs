@@ -0,0 +1,115 @@
+use crate::{
+    block::{self, storage::Storage},
+    tree::{Key, Tree},
+};
+
+use super::*;
+
+/// How many low bits of a key offset tell apart names with the same hash.
+const COLLISION_BITS: u32 = 8;
+
+pub(crate) const COLLISION_MASK: u64 = (1 << COLLISION_BITS) - 1;
+
+/// Items whose names share a hash, stored in a bucket of key offsets.
+///
+/// A name's bucket starts at its hash with the low [COLLISION_BITS] cleared. Once every slot but
+/// the last is taken, the last one holds an empty overflow marker and the bucket carries on into
+/// the next one, so a bucket never runs out of slots. Markers are kept when items are removed, as
+/// the items past them must stay reachable.
+pub struct Bucket;
+
+impl Bucket {
+    /// Returns the first key offset of the bucket a name with `hash` is stored in.
+    pub fn first(hash: u64) -> u64 {
+        hash & !COLLISION_MASK
+    }
+
+    /// Checks whether an item's data is an overflow marker rather than an item of the bucket.
+    pub fn is_overflow(data: &[u8]) -> bool {
+        data.is_empty()
+    }
+
+    /// Reads the items stored in the bucket starting at `first`, including those it overflowed
+    /// into.
+    pub fn read(
+        storage: &impl Storage,
+        superblock: &Superblock,
+        first: Key,
+    ) -> Result<Vec<(Key, Box<[u8]>)>> {
+        let mut items = Vec::new();
+        let mut start = Some(first);
+        while let Some(first) = start {
+            let overflows = Self::read_one(storage, superblock, first, &mut items)?;
+            start = overflows.then(|| Self::next(first)).flatten();
+        }
+        Ok(items)
+    }
+
+    /// Returns the key of a free slot in the bucket starting at `first`, marking the full ones
+    /// it passes as overflowing.
+    /// Returns `None` if the key offsets run out.
+    pub fn free_slot(
+        storage: &mut impl Storage,
+        block_alloc: &mut impl block::Allocator,
+        superblock: &mut Superblock,
+        first: Key,
+    ) -> Result<Option<Key>> {
+        let mut start = Some(first);
+        while let Some(first) = start {
+            let mut items = Vec::new();
+            let overflows = Self::read_one(storage, superblock, first, &mut items)?;
+
+            let last = first.offset() | COLLISION_MASK;
+            let free = (first.offset()..last)
+                .find(|&offset| items.iter().all(|(key, _)| key.offset() != offset));
+            if let Some(offset) = free {
+                return Ok(Some(Key::new(first.id, first.datatype, offset)));
+            }
+
+            if !overflows {
+                let marker = Key::new(first.id, first.datatype, last);
+                Tree::try_insert(storage, block_alloc, &mut superblock.root_addr, marker, &[])?;
+            }
+            start = Self::next(first);
+        }
+        Ok(None)
+    }
+
+    /// Reads the items stored in the single bucket starting at `first` into `items`.
+    /// Returns whether the bucket overflows into the next one.
+    fn read_one(
+        storage: &impl Storage,
+        superblock: &Superblock,
+        first: Key,
+        items: &mut Vec<(Key, Box<[u8]>)>,
+    ) -> Result<bool> {
+        let last = first.offset() | COLLISION_MASK;
+        let mut overflows = false;
+
+        let mut curr_key = Key::new(first.id, first.datatype, last);
+        while let Some((key, val)) = Tree::get_le(storage, superblock.root_addr, curr_key)? {
+            if key.id != first.id || key.datatype != first.datatype || key < first {
+                break;
+            }
+
+            if key.offset() == last && Self::is_overflow(&val) {
+                overflows = true;
+            } else {
+                items.push((key, val));
+            }
+
+            if key.offset() == first.offset() {
+                break;
+            }
+            curr_key = Key::new(first.id, first.datatype, key.offset() - 1);
+        }
+
+        Ok(overflows)
+    }
+
+    /// Returns the first key of the bucket following the one starting at `first`, if any.
+    fn next(first: Key) -> Option<Key> {
+        let offset = first.offset().checked_add(COLLISION_MASK + 1)?;
+        Some(Key::new(first.id, first.datatype, offset))
+    }
+}
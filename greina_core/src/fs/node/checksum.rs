@@ -0,0 +1,265 @@
+use std::collections::BTreeMap;
+
+use zerocopy::{FromBytes, IntoBytes, little_endian::U32};
+
+use crate::{
+    block::{self, BLOCK_SIZE, Block, storage::Storage},
+    tree::{DATA_MAX_LEN, DataType, Key, Tree},
+};
+
+use super::*;
+
+/// How many blocks' checksums are stored in a single item.
+const BLOCKS_PER_ITEM: u64 = (DATA_MAX_LEN / size_of::<U32>()) as u64;
+
+/// How many bytes of a file a single item covers.
+const BYTES_PER_ITEM: u64 = BLOCKS_PER_ITEM * BLOCK_SIZE;
+
+/// Checksums of a file's data blocks, kept when the filesystem is formatted with
+/// [superblock::RO_COMPAT_DATA_CHECKSUMS](crate::fs::superblock::RO_COMPAT_DATA_CHECKSUMS).
+///
+/// # Layout
+/// Stored as items holding the crc32c of `BLOCKS_PER_ITEM` consecutive blocks of the file,
+/// keyed by the offset of the first block.
+/// Every mapped block is written once it's allocated, so it always has a checksum.
+pub struct DataChecksum;
+
+impl DataChecksum {
+    /// Computes the checksum of a data block.
+    pub fn of(block: &Block) -> u32 {
+        crc32c::crc32c(&block[..])
+    }
+
+    /// Stores the checksums of the blocks at given offsets of a file.
+    pub fn write(
+        storage: &mut impl Storage,
+        block_alloc: &mut impl block::Allocator,
+        superblock: &mut Superblock,
+        id: NodeId,
+        checksums: &BTreeMap<u64, u32>,
+    ) -> Result<()> {
+        let mut items: BTreeMap<u64, Vec<(usize, u32)>> = BTreeMap::new();
+        for (&offset, &checksum) in checksums {
+            let (item_offset, idx) = Self::locate(offset);
+            items.entry(item_offset).or_default().push((idx, checksum));
+        }
+
+        for (item_offset, updates) in items {
+            let key = Key::checksum(id, item_offset);
+            let mut checksums = match Tree::get(storage, superblock.root_addr, key)? {
+                Some(bytes) => Self::parse(&bytes)?,
+                None => vec![U32::ZERO; BLOCKS_PER_ITEM as usize],
+            };
+            for (idx, checksum) in updates {
+                checksums[idx].set(checksum);
+            }
+            Tree::insert(
+                storage,
+                block_alloc,
+                &mut superblock.root_addr,
+                key,
+                checksums.as_bytes(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Removes the checksums of the blocks past `size`, once they're deallocated.
+    /// Checksums sharing an item with kept blocks are left in place, as they're rewritten before
+    /// they're read again.
+    pub fn truncate(
+        storage: &mut impl Storage,
+        block_alloc: &mut impl block::Allocator,
+        superblock: &mut Superblock,
+        id: NodeId,
+        size: u64,
+    ) -> Result<()> {
+        let key = Key::checksum(id, u64::MAX);
+        while let Some((key, _)) = Tree::get_le(storage, superblock.root_addr, key)? {
+            if key.id != id || key.datatype != DataType::Checksum || key.offset() < size {
+                break;
+            }
+            Tree::remove(storage, block_alloc, &mut superblock.root_addr, key)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the offset of the item holding the checksum of the block at `offset`,
+    /// and the checksum's index within it.
+    fn locate(offset: u64) -> (u64, usize) {
+        let item_offset = offset - offset % BYTES_PER_ITEM;
+        let idx = (offset - item_offset) / BLOCK_SIZE;
+        (item_offset, idx as usize)
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Vec<U32>> {
+        let checksums = <[U32]>::ref_from_bytes(bytes).map_err(|_| Error::Uninterpretable)?;
+        if checksums.len() != BLOCKS_PER_ITEM as usize {
+            return Err(Error::Uninterpretable);
+        }
+        Ok(checksums.to_vec())
+    }
+}
+
+/// Verifies a file's data blocks as they're read, keeping the last item of checksums around.
+pub struct Verifier {
+    id: NodeId,
+    item: Option<(u64, Vec<U32>)>,
+}
+
+impl Verifier {
+    pub fn new(id: NodeId) -> Self {
+        Self { id, item: None }
+    }
+
+    /// Checks the block read from `offset` of the file against its checksum.
+    pub fn verify(
+        &mut self,
+        storage: &impl Storage,
+        superblock: &Superblock,
+        offset: u64,
+        block: &Block,
+    ) -> Result<()> {
+        let (item_offset, idx) = DataChecksum::locate(offset);
+        let checksums = match &mut self.item {
+            Some((cached_offset, checksums)) if *cached_offset == item_offset => checksums,
+            item => {
+                let key = Key::checksum(self.id, item_offset);
+                let checksums = match Tree::get(storage, superblock.root_addr, key)? {
+                    Some(bytes) => DataChecksum::parse(&bytes)?,
+                    None => vec![U32::ZERO; BLOCKS_PER_ITEM as usize],
+                };
+                &mut item.insert((item_offset, checksums)).1
+            }
+        };
+
+        if checksums[idx].get() != DataChecksum::of(block) {
+            log::error!(
+                "data block at offset {offset} of node {} has a bad checksum",
+                self.id.get()
+            );
+            return Err(Error::DataChecksum);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        block::storage::mem::MemStorage,
+        fs::{Filesystem, FormatOptions, node::extent::MappedExtent},
+    };
+
+    use super::*;
+
+    fn format(data_checksums: bool) -> Filesystem<MemStorage> {
+        let options = FormatOptions {
+            data_checksums,
+            ..Default::default()
+        };
+        Filesystem::format_with(MemStorage::new(1024), &options).unwrap()
+    }
+
+    fn create_file(fs: &mut Filesystem<MemStorage>, data: &[u8]) -> NodeId {
+        fs.tx(|tx| {
+            let perms = Perms::new(0o644, 0, 0);
+            let id = tx.create_file(NodeId::ROOT, "file", FileType::File, perms)?;
+            tx.write_file_at(id, 0, data)?;
+            Ok(id)
+        })
+        .unwrap()
+    }
+
+    /// Flips a bit in the block at `offset` of a file, behind the filesystem's back.
+    fn corrupt(fs: &Filesystem<MemStorage>, id: NodeId, offset: u64) {
+        let map = MappedExtent::read(&fs.storage, &fs.superblock, id, offset)
+            .unwrap()
+            .unwrap();
+        let addr = map.inner.start() + (offset - map.start) / BLOCK_SIZE;
+
+        let mut block = Block::default();
+        fs.storage.read_at(&mut block, addr).unwrap();
+        block[0] ^= 1;
+        fs.storage.write_at(&block, addr).unwrap();
+    }
+
+    fn read(fs: &Filesystem<MemStorage>, id: NodeId, len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        fs.read_tx(|tx| tx.read_file_at(id, 0, &mut buf))?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let mut fs = format(true);
+        let data = vec![0xAB; 3 * BLOCK_SIZE as usize];
+        let id = create_file(&mut fs, &data);
+        assert_eq!(read(&fs, id, data.len()).unwrap(), data);
+
+        corrupt(&fs, id, BLOCK_SIZE);
+        assert!(matches!(
+            read(&fs, id, data.len()),
+            Err(Error::DataChecksum)
+        ));
+
+        // The corrupted block is fixed by overwriting it
+        fs.tx(|tx| tx.write_file_at(id, BLOCK_SIZE, &data[..BLOCK_SIZE as usize]))
+            .unwrap();
+        assert_eq!(read(&fs, id, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn partial_overwrite_detects_corruption() {
+        let mut fs = format(true);
+        let data = vec![0xAB; 3 * BLOCK_SIZE as usize];
+        let id = create_file(&mut fs, &data);
+        corrupt(&fs, id, BLOCK_SIZE);
+
+        // The rest of the corrupted block would otherwise be kept under a new checksum
+        let result = fs.tx(|tx| tx.write_file_at(id, BLOCK_SIZE + 1, &[0xCD; 16]));
+        assert!(matches!(result, Err(Error::DataChecksum)));
+        let result = fs.tx(|tx| tx.truncate_file(id, BLOCK_SIZE + BLOCK_SIZE / 2));
+        assert!(matches!(result, Err(Error::DataChecksum)));
+        assert!(matches!(
+            read(&fs, id, data.len()),
+            Err(Error::DataChecksum)
+        ));
+    }
+
+    #[test]
+    fn truncate_updates_checksums() {
+        let mut fs = format(true);
+        let data = vec![0xAB; 3 * BLOCK_SIZE as usize];
+        let id = create_file(&mut fs, &data);
+
+        let size = BLOCK_SIZE + BLOCK_SIZE / 2;
+        fs.tx(|tx| tx.truncate_file(id, size)).unwrap();
+        assert_eq!(read(&fs, id, size as usize).unwrap(), data[..size as usize]);
+
+        fs.tx(|tx| tx.truncate_file(id, 0)).unwrap();
+        let key = Key::checksum(id, 0);
+        assert!(
+            Tree::get(&fs.storage, fs.superblock.root_addr, key)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn disabled() {
+        let mut fs = format(false);
+        let data = vec![0xAB; BLOCK_SIZE as usize];
+        let id = create_file(&mut fs, &data);
+
+        let key = Key::checksum(id, 0);
+        assert!(
+            Tree::get(&fs.storage, fs.superblock.root_addr, key)
+                .unwrap()
+                .is_none()
+        );
+
+        corrupt(&fs, id, 0);
+        assert_ne!(read(&fs, id, data.len()).unwrap(), data);
+    }
+}
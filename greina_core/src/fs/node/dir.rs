@@ -38,6 +38,17 @@ impl DirEntry {
 /// How long a directory entry name can be.
 pub const NAME_MAX_LEN: usize = 256;
 
+/// How a rename treats an existing entry with the new name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenameMode {
+    // Replaces the existing entry
+    Replace,
+    // Fails if the entry exists
+    NoReplace,
+    // Swaps the two entries, which must both exist
+    Exchange,
+}
+
 /// Represents the name of a directory entry.
 /// Guaranteed to be valid UTF-8.
 #[repr(C)]
@@ -65,16 +76,9 @@ impl DirEntryName {
         Self(b"..".as_ref().into())
     }
 
+    /// Returns the first key offset of the [Bucket] the name is stored in.
     pub fn hash(&self) -> u64 {
-        hash::fnv_hash(self.0.as_bytes())
-    }
-
-    const fn itself_hash() -> u64 {
-        hash::fnv_hash(b".")
-    }
-
-    const fn parent_hash() -> u64 {
-        hash::fnv_hash(b"..")
+        Bucket::first(hash::fnv_hash(self.0.as_bytes()))
     }
 }
 
@@ -125,30 +129,74 @@ impl DirEntry {
         name: DirEntryName,
     ) -> Result<()> {
         let entry = DirEntry { filetype, id, name };
-        let key = Key::direntry(parent, entry.name.hash());
+        entry.insert(storage, block_alloc, superblock, parent)?;
+        Node::touch_id(storage, block_alloc, superblock, parent)?;
+        Ok(())
+    }
+
+    /// Inserts the entry into the first free slot of its name's bucket.
+    fn insert(
+        &self,
+        storage: &mut impl Storage,
+        block_alloc: &mut impl block::Allocator,
+        superblock: &mut Superblock,
+        parent: NodeId,
+    ) -> Result<()> {
+        let bucket = Self::read_bucket(storage, superblock, parent, &self.name)?;
+        if bucket.iter().any(|(_, entry)| entry.name == self.name) {
+            return Err(Error::DirEntryExists);
+        }
+
+        let first = Key::direntry(parent, self.name.hash());
+        let key = Bucket::free_slot(storage, block_alloc, superblock, first)?
+            .ok_or(Error::DirEntryNoSpace)?;
         Tree::try_insert(
             storage,
             block_alloc,
             &mut superblock.root_addr,
             key,
-            &entry.as_bytes(),
+            &self.as_bytes(),
         )?;
         Ok(())
     }
 
+    /// Finds the entry with a given name, along with its key.
+    pub(super) fn find(
+        storage: &impl Storage,
+        superblock: &Superblock,
+        parent: NodeId,
+        name: &DirEntryName,
+    ) -> Result<Option<(Key, DirEntry)>> {
+        let bucket = Self::read_bucket(storage, superblock, parent, name)?;
+        Ok(bucket.into_iter().find(|(_, entry)| entry.name == *name))
+    }
+
+    /// Reads the entries stored in the bucket `name` belongs to.
+    fn read_bucket(
+        storage: &impl Storage,
+        superblock: &Superblock,
+        parent: NodeId,
+        name: &DirEntryName,
+    ) -> Result<Vec<(Key, DirEntry)>> {
+        let first = Key::direntry(parent, name.hash());
+        Bucket::read(storage, superblock, first)?
+            .into_iter()
+            .map(|(key, val)| Ok((key, DirEntry::try_from_bytes(&val)?)))
+            .collect()
+    }
+
     pub fn read(
         storage: &impl Storage,
         superblock: &Superblock,
         parent: NodeId,
-        name_hash: u64,
+        name: &DirEntryName,
     ) -> Result<DirEntry> {
-        let key = Key::direntry(parent, name_hash);
-        let bytes =
-            Tree::get(storage, superblock.root_addr, key)?.ok_or(Error::DirEntryNotFound)?;
-        let entry = Self::try_from_bytes(&bytes)?;
+        let (_, entry) =
+            Self::find(storage, superblock, parent, name)?.ok_or(Error::DirEntryNotFound)?;
         Ok(entry)
     }
 
+    /// Overwrites the existing entry with the same name.
     pub fn write(
         &self,
         storage: &mut impl Storage,
@@ -156,7 +204,8 @@ impl DirEntry {
         superblock: &mut Superblock,
         parent: NodeId,
     ) -> Result<()> {
-        let key = Key::direntry(parent, self.name.hash());
+        let (key, _) =
+            Self::find(storage, superblock, parent, &self.name)?.ok_or(Error::DirEntryNotFound)?;
         Tree::insert(
             storage,
             block_alloc,
@@ -167,6 +216,20 @@ impl DirEntry {
         Ok(())
     }
 
+    /// Overwrites the existing entry with the same name, or creates it if there's none.
+    pub fn upsert(
+        &self,
+        storage: &mut impl Storage,
+        block_alloc: &mut impl block::Allocator,
+        superblock: &mut Superblock,
+        parent: NodeId,
+    ) -> Result<()> {
+        match self.write(storage, block_alloc, superblock, parent) {
+            Err(Error::DirEntryNotFound) => self.insert(storage, block_alloc, superblock, parent),
+            res => res,
+        }
+    }
+
     pub fn link(
         storage: &mut impl Storage,
         block_alloc: &mut impl block::Allocator,
@@ -191,41 +254,36 @@ impl DirEntry {
         )?;
 
         node.links += 1;
+        node.ctime = Timestamp::now();
         node.write(storage, block_alloc, superblock, id)?;
 
         Ok(())
     }
 
+    /// Removes a link to a non-directory node.
+    /// Returns the node if it was left without links, see [Orphan].
     pub fn unlink(
         storage: &mut impl Storage,
         block_alloc: &mut impl block::Allocator,
         superblock: &mut Superblock,
         parent: NodeId,
         name: &DirEntryName,
-    ) -> Result<()> {
-        let key = Key::direntry(parent, name.hash());
-        let bytes =
-            Tree::get(storage, superblock.root_addr, key)?.ok_or(Error::DirEntryNotFound)?;
-        let entry = DirEntry::try_from_bytes(&bytes)?;
+    ) -> Result<Option<NodeId>> {
+        let (key, entry) =
+            Self::find(storage, superblock, parent, name)?.ok_or(Error::DirEntryNotFound)?;
         if entry.filetype == FileType::Dir {
             return Err(Error::IsDir);
         }
 
-        let mut node = Node::read(storage, superblock, entry.id)?;
-
-        Tree::remove(storage, block_alloc, &mut superblock.root_addr, key)?
-            .expect("entry exists because 'bytes' is 'Some'");
-
-        node.links -= 1;
-        if node.links == 0 {
-            Node::remove(storage, block_alloc, superblock, entry.id)?;
-        } else {
-            node.write(storage, block_alloc, superblock, entry.id)?;
-        }
+        let orphan = Self::remove_at(storage, block_alloc, superblock, key, &entry)?;
+        Node::touch_id(storage, block_alloc, superblock, parent)?;
 
-        Ok(())
+        Ok(orphan)
     }
 
+    /// Moves an entry to a new parent and name.
+    /// Returns the replaced node if it was left without links, see [Orphan].
+    #[allow(clippy::too_many_arguments)]
     pub fn rename(
         storage: &mut impl Storage,
         block_alloc: &mut impl block::Allocator,
@@ -234,51 +292,212 @@ impl DirEntry {
         old_name: &DirEntryName,
         new_parent: NodeId,
         new_name: &DirEntryName,
-    ) -> Result<()> {
+        mode: RenameMode,
+    ) -> Result<Option<NodeId>> {
+        let (old_key, mut entry) = Self::find(storage, superblock, old_parent, old_name)?
+            .ok_or(Error::DirEntryNotFound)?;
+
         if old_parent == new_parent && old_name == new_name {
-            return Ok(());
+            return Ok(None);
         }
 
-        let new_name_hash = new_name.hash();
-        if Self::read(storage, superblock, new_parent, new_name_hash).is_ok() {
-            return Err(Error::DirEntryExists);
+        let mut orphan = None;
+        let target = Self::find(storage, superblock, new_parent, new_name)?;
+        match (mode, target) {
+            (RenameMode::NoReplace, Some(_)) => return Err(Error::DirEntryExists),
+            (RenameMode::Exchange, None) => return Err(Error::DirEntryNotFound),
+            (RenameMode::Exchange, Some((new_key, target))) => {
+                Self::exchange(
+                    storage,
+                    block_alloc,
+                    superblock,
+                    (old_parent, old_key, entry),
+                    (new_parent, new_key, target),
+                )?;
+                return Ok(None);
+            }
+            (RenameMode::Replace, Some((new_key, target))) => {
+                // Both names are links to the same node
+                if target.id == entry.id {
+                    return Ok(None);
+                }
+                Self::check_move(storage, superblock, &entry, new_parent)?;
+                match (entry.filetype, target.filetype) {
+                    (FileType::Dir, FileType::Dir) => {
+                        if !Dir::is_empty(storage, superblock, target.id)? {
+                            return Err(Error::DirNotEmpty);
+                        }
+                    }
+                    (FileType::Dir, _) => return Err(Error::NotDir),
+                    (_, FileType::Dir) => return Err(Error::IsDir),
+                    _ => (),
+                }
+                orphan = Self::remove_at(storage, block_alloc, superblock, new_key, &target)?;
+            }
+            (_, None) => Self::check_move(storage, superblock, &entry, new_parent)?,
         }
 
-        let old_name_hash = new_name.hash();
-        let mut entry = Self::read(storage, superblock, old_parent, old_name_hash)?;
+        Tree::remove(storage, block_alloc, &mut superblock.root_addr, old_key)?;
 
         if entry.filetype == FileType::Dir {
-            if DirEntry::is_ancestor(storage, superblock, entry.id, new_parent)? {
-                return Err(Error::InvalidMove);
+            Self::set_parent(storage, block_alloc, superblock, entry.id, new_parent)?;
+        }
+        entry.name = new_name.clone();
+        entry.insert(storage, block_alloc, superblock, new_parent)?;
+
+        Self::touch_moved(
+            storage,
+            block_alloc,
+            superblock,
+            old_parent,
+            new_parent,
+            &[entry.id],
+        )?;
+        Ok(orphan)
+    }
+
+    /// Swaps the nodes two entries link to.
+    /// Each name keeps its slot, so only the entries' values are rewritten.
+    fn exchange(
+        storage: &mut impl Storage,
+        block_alloc: &mut impl block::Allocator,
+        superblock: &mut Superblock,
+        (old_parent, old_key, old_entry): (NodeId, Key, DirEntry),
+        (new_parent, new_key, new_entry): (NodeId, Key, DirEntry),
+    ) -> Result<()> {
+        if old_parent != new_parent {
+            Self::check_move(storage, superblock, &old_entry, new_parent)?;
+            Self::check_move(storage, superblock, &new_entry, old_parent)?;
+
+            if old_entry.filetype == FileType::Dir {
+                Self::set_parent(storage, block_alloc, superblock, old_entry.id, new_parent)?;
+            }
+            if new_entry.filetype == FileType::Dir {
+                Self::set_parent(storage, block_alloc, superblock, new_entry.id, old_parent)?;
             }
+        }
 
-            let parent_entry = DirEntry {
-                filetype: FileType::Dir,
-                id: new_parent,
-                name: DirEntryName::parent(),
+        let swapped = [
+            (old_key, &new_entry, &old_entry.name),
+            (new_key, &old_entry, &new_entry.name),
+        ];
+        for (key, entry, name) in swapped {
+            let entry = DirEntry {
+                filetype: entry.filetype,
+                id: entry.id,
+                name: name.clone(),
             };
-            parent_entry.write(storage, block_alloc, superblock, entry.id)?;
+            Tree::insert(
+                storage,
+                block_alloc,
+                &mut superblock.root_addr,
+                key,
+                &entry.as_bytes(),
+            )?;
         }
 
-        let key = Key::direntry(old_parent, old_name_hash);
-        Tree::remove(storage, block_alloc, &mut superblock.root_addr, key)?;
-
-        entry.name = new_name.clone();
-
-        let key = Key::direntry(new_parent, new_name_hash);
-        Tree::try_insert(
+        Self::touch_moved(
             storage,
             block_alloc,
-            &mut superblock.root_addr,
-            key,
-            &entry.as_bytes(),
-        )?;
+            superblock,
+            old_parent,
+            new_parent,
+            &[old_entry.id, new_entry.id],
+        )
+    }
 
+    /// Fails if moving `entry` into `new_parent` would make a directory its own descendant.
+    fn check_move(
+        storage: &impl Storage,
+        superblock: &Superblock,
+        entry: &DirEntry,
+        new_parent: NodeId,
+    ) -> Result<()> {
+        if entry.filetype == FileType::Dir
+            && Self::is_ancestor(storage, superblock, entry.id, new_parent)?
+        {
+            return Err(Error::InvalidMove);
+        }
         Ok(())
     }
 
-    /// Checks if `ancestor` is an ancestor directory of `dir` directory.
-    /// A directory is its own ancestor.
+    /// Points the `..` entry of directory `id` to `parent`.
+    fn set_parent(
+        storage: &mut impl Storage,
+        block_alloc: &mut impl block::Allocator,
+        superblock: &mut Superblock,
+        id: NodeId,
+        parent: NodeId,
+    ) -> Result<()> {
+        let parent_entry = DirEntry {
+            filetype: FileType::Dir,
+            id: parent,
+            name: DirEntryName::parent(),
+        };
+        parent_entry.write(storage, block_alloc, superblock, id)
+    }
+
+    /// Updates the times of the parents and the nodes involved in a rename.
+    fn touch_moved(
+        storage: &mut impl Storage,
+        block_alloc: &mut impl block::Allocator,
+        superblock: &mut Superblock,
+        old_parent: NodeId,
+        new_parent: NodeId,
+        ids: &[NodeId],
+    ) -> Result<()> {
+        Node::touch_id(storage, block_alloc, superblock, old_parent)?;
+        if new_parent != old_parent {
+            Node::touch_id(storage, block_alloc, superblock, new_parent)?;
+        }
+
+        for &id in ids {
+            let mut node = Node::read(storage, superblock, id)?;
+            node.ctime = Timestamp::now();
+            node.write(storage, block_alloc, superblock, id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the entry stored at `key` and drops its link to the node.
+    /// A directory is expected to be empty and is removed right away, while a node left without
+    /// links is made an [Orphan] and returned.
+    fn remove_at(
+        storage: &mut impl Storage,
+        block_alloc: &mut impl block::Allocator,
+        superblock: &mut Superblock,
+        key: Key,
+        entry: &DirEntry,
+    ) -> Result<Option<NodeId>> {
+        Tree::remove(storage, block_alloc, &mut superblock.root_addr, key)?
+            .ok_or(Error::DirEntryNotFound)?;
+
+        if entry.filetype == FileType::Dir {
+            // Only `.`, `..` and overflow markers are left
+            let key = Key::direntry(entry.id, u64::MAX);
+            while let Some((key, _)) = Tree::get_le(storage, superblock.root_addr, key)? {
+                if key.id != entry.id || key.datatype != DataType::DirEntry {
+                    break;
+                }
+                Tree::remove(storage, block_alloc, &mut superblock.root_addr, key)?;
+            }
+            Node::remove(storage, block_alloc, superblock, entry.id)?;
+            return Ok(None);
+        }
+
+        let mut node = Node::read(storage, superblock, entry.id)?;
+        node.links -= 1;
+        node.ctime = Timestamp::now();
+        node.write(storage, block_alloc, superblock, entry.id)?;
+
+        if node.links == 0 {
+            Orphan::insert(storage, block_alloc, superblock, entry.id)?;
+            return Ok(Some(entry.id));
+        }
+        Ok(None)
+    }
+
     fn is_ancestor(
         storage: &impl Storage,
         superblock: &Superblock,
@@ -292,13 +511,7 @@ impl DirEntry {
             } else if curr_parent == NodeId::ROOT {
                 return Ok(false);
             }
-            curr_parent = Self::read(
-                storage,
-                superblock,
-                curr_parent,
-                DirEntryName::parent_hash(),
-            )?
-            .id;
+            curr_parent = Self::read(storage, superblock, curr_parent, &DirEntryName::parent())?.id;
         }
     }
 }
@@ -312,12 +525,13 @@ impl Dir {
         superblock: &mut Superblock,
         parent: NodeId,
         name: DirEntryName,
+        perms: Perms,
     ) -> Result<NodeId> {
-        if DirEntry::read(storage, superblock, parent, name.hash()).is_ok() {
+        if DirEntry::find(storage, superblock, parent, &name)?.is_some() {
             return Err(Error::DirEntryExists);
         }
 
-        let id = Node::create(storage, block_alloc, superblock, FileType::Dir, 1)?;
+        let id = Node::create(storage, block_alloc, superblock, FileType::Dir, 1, perms)?;
 
         DirEntry::create(
             storage,
@@ -361,7 +575,8 @@ impl Dir {
     ) -> Result<NodeId> {
         let name = DirEntryName::try_from(name)?;
 
-        let entry = DirEntry::read(storage, superblock, parent, name.hash())?;
+        let (key, entry) =
+            DirEntry::find(storage, superblock, parent, &name)?.ok_or(Error::DirEntryNotFound)?;
         if entry.filetype != FileType::Dir {
             return Err(Error::NotDir);
         }
@@ -370,15 +585,8 @@ impl Dir {
             return Err(Error::DirNotEmpty);
         }
 
-        let key = Key::direntry(entry.id, DirEntryName::itself_hash());
-        Tree::remove(storage, block_alloc, &mut superblock.root_addr, key)?;
-        let key = Key::direntry(entry.id, DirEntryName::parent_hash());
-        Tree::remove(storage, block_alloc, &mut superblock.root_addr, key)?;
-
-        let key = Key::direntry(parent, name.hash());
-        Tree::remove(storage, block_alloc, &mut superblock.root_addr, key)?;
-
-        Node::remove(storage, block_alloc, superblock, entry.id)?;
+        DirEntry::remove_at(storage, block_alloc, superblock, key, &entry)?;
+        Node::touch_id(storage, block_alloc, superblock, parent)?;
 
         Ok(entry.id)
     }
@@ -388,20 +596,23 @@ impl Dir {
         superblock: &Superblock,
         id: NodeId,
     ) -> Result<bool> {
-        let itself_hash = DirEntryName::itself_hash();
-        let parent_hash = DirEntryName::parent_hash();
+        let itself = DirEntryName::itself();
+        let parent = DirEntryName::parent();
 
         let mut curr_key = Key::direntry(id, u64::MAX);
-        while let Some((key, _)) = Tree::get_le(storage, superblock.root_addr, curr_key)? {
+        while let Some((key, val)) = Tree::get_le(storage, superblock.root_addr, curr_key)? {
             if key.id != id || key.datatype != DataType::DirEntry {
                 break;
             }
 
-            let hash = key.offset();
-            if hash != itself_hash && hash != parent_hash {
-                return Ok(false);
+            if !Bucket::is_overflow(&val) {
+                let entry = DirEntry::try_from_bytes(&val)?;
+                if entry.name != itself && entry.name != parent {
+                    return Ok(false);
+                }
             }
 
+            let hash = key.offset();
             if hash == 0 {
                 break;
             }
@@ -424,8 +635,9 @@ impl Dir {
                 break;
             }
 
-            let entry = DirEntry::try_from_bytes(&val)?;
-            entries.push(entry);
+            if !Bucket::is_overflow(&val) {
+                entries.push(DirEntry::try_from_bytes(&val)?);
+            }
 
             let hash = key.offset();
             if hash == 0 {
@@ -437,3 +649,424 @@ impl Dir {
         Ok(entries)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        block::{Allocator, allocator::fake::FakeAllocator, storage::fake::FakeStorage},
+        fs::node::file::File,
+    };
+
+    struct Context {
+        storage: FakeStorage,
+        block_alloc: FakeAllocator,
+        superblock: Superblock,
+        dir: NodeId,
+    }
+
+    impl Context {
+        fn new() -> Self {
+            let mut storage = FakeStorage::default();
+            let mut block_alloc = FakeAllocator::default();
+            let mut superblock = Superblock::new(0);
+            superblock.root_addr = block_alloc.allocate(1).unwrap();
+            Tree::format(&mut storage, superblock.root_addr).unwrap();
+
+            let dir = Node::create(
+                &mut storage,
+                &mut block_alloc,
+                &mut superblock,
+                FileType::Dir,
+                1,
+                Perms::new(0o755, 0, 0),
+            )
+            .unwrap();
+
+            Self {
+                storage,
+                block_alloc,
+                superblock,
+                dir,
+            }
+        }
+
+        fn name(name: &str) -> DirEntryName {
+            DirEntryName::try_from(name).unwrap()
+        }
+
+        fn create_file(&mut self, name: &str) -> NodeId {
+            File::create(
+                &mut self.storage,
+                &mut self.block_alloc,
+                &mut self.superblock,
+                self.dir,
+                FileType::File,
+                name,
+                Perms::new(0o644, 0, 0),
+            )
+            .unwrap()
+        }
+
+        /// Creates a file whose entry is stored at slot `slot` of `like`'s bucket,
+        /// as if their names had the same hash.
+        fn create_colliding_file(&mut self, name: &str, like: &str, slot: u64) -> NodeId {
+            let id = Node::create(
+                &mut self.storage,
+                &mut self.block_alloc,
+                &mut self.superblock,
+                FileType::File,
+                1,
+                Perms::new(0o644, 0, 0),
+            )
+            .unwrap();
+            let entry = DirEntry {
+                filetype: FileType::File,
+                id,
+                name: Self::name(name),
+            };
+            let key = Key::direntry(self.dir, Self::name(like).hash() + slot);
+            Tree::try_insert(
+                &mut self.storage,
+                &mut self.block_alloc,
+                &mut self.superblock.root_addr,
+                key,
+                &entry.as_bytes(),
+            )
+            .unwrap();
+            id
+        }
+
+        fn read(&self, name: &str) -> Result<DirEntry> {
+            DirEntry::read(&self.storage, &self.superblock, self.dir, &Self::name(name))
+        }
+
+        fn unlink(&mut self, name: &str) -> Result<Option<NodeId>> {
+            DirEntry::unlink(
+                &mut self.storage,
+                &mut self.block_alloc,
+                &mut self.superblock,
+                self.dir,
+                &Self::name(name),
+            )
+        }
+
+        fn rename(
+            &mut self,
+            old_name: &str,
+            new_name: &str,
+            mode: RenameMode,
+        ) -> Result<Option<NodeId>> {
+            DirEntry::rename(
+                &mut self.storage,
+                &mut self.block_alloc,
+                &mut self.superblock,
+                self.dir,
+                &Self::name(old_name),
+                self.dir,
+                &Self::name(new_name),
+                mode,
+            )
+        }
+
+        fn list(&self) -> Vec<String> {
+            let mut names: Vec<_> = Dir::list(&self.storage, &self.superblock, self.dir)
+                .unwrap()
+                .iter()
+                .map(|entry| String::from(&entry.name))
+                .collect();
+            names.sort();
+            names
+        }
+    }
+
+    #[test]
+    fn colliding_names() {
+        let mut ctx = Context::new();
+        // Occupy the first slot of b's bucket with a name of another bucket
+        ctx.create_colliding_file("a", "b", 0);
+        let b = ctx.create_file("b");
+
+        assert_eq!(ctx.read("b").unwrap().id, b);
+        assert!(matches!(ctx.read("a"), Err(Error::DirEntryNotFound)));
+        assert_eq!(ctx.list(), ["a", "b"]);
+
+        ctx.rename("b", "c", RenameMode::Replace).unwrap();
+        assert!(matches!(ctx.read("b"), Err(Error::DirEntryNotFound)));
+        assert_eq!(ctx.read("c").unwrap().id, b);
+        assert_eq!(ctx.list(), ["a", "c"]);
+    }
+
+    #[test]
+    fn gap_in_bucket() {
+        let mut ctx = Context::new();
+        let b = ctx.create_colliding_file("b", "b", 2);
+
+        // Entries past an empty slot must still be found
+        assert_eq!(ctx.read("b").unwrap().id, b);
+        ctx.unlink("b").unwrap();
+        assert!(matches!(ctx.read("b"), Err(Error::DirEntryNotFound)));
+        assert!(ctx.list().is_empty());
+    }
+
+    #[test]
+    fn existing_colliding_name() {
+        let mut ctx = Context::new();
+        ctx.create_colliding_file("a", "b", 0);
+        ctx.create_colliding_file("b", "b", 3);
+
+        let result = File::create(
+            &mut ctx.storage,
+            &mut ctx.block_alloc,
+            &mut ctx.superblock,
+            ctx.dir,
+            FileType::File,
+            "b",
+            Perms::new(0o644, 0, 0),
+        );
+        assert!(matches!(result, Err(Error::DirEntryExists)));
+    }
+
+    #[test]
+    fn existing_name_creates_no_node() {
+        let mut ctx = Context::new();
+        ctx.create_file("a");
+        let next_node_id = ctx.superblock.next_node_id;
+
+        let result = File::create(
+            &mut ctx.storage,
+            &mut ctx.block_alloc,
+            &mut ctx.superblock,
+            ctx.dir,
+            FileType::File,
+            "a",
+            Perms::new(0o644, 0, 0),
+        );
+        assert!(matches!(result, Err(Error::DirEntryExists)));
+        assert_eq!(ctx.superblock.next_node_id, next_node_id);
+    }
+
+    #[test]
+    fn overflowing_bucket() {
+        let mut ctx = Context::new();
+        for slot in 0..COLLISION_MASK {
+            ctx.create_colliding_file(&format!("a{slot}"), "b", slot);
+        }
+
+        // The full bucket overflows into the next one
+        let b = ctx.create_file("b");
+        assert_eq!(ctx.read("b").unwrap().id, b);
+        assert_eq!(ctx.list().len(), COLLISION_MASK as usize + 1);
+
+        // Freeing a slot keeps the entries past the bucket reachable
+        let key = Key::direntry(ctx.dir, Context::name("b").hash());
+        Tree::remove(
+            &mut ctx.storage,
+            &mut ctx.block_alloc,
+            &mut ctx.superblock.root_addr,
+            key,
+        )
+        .unwrap();
+        assert_eq!(ctx.read("b").unwrap().id, b);
+        let result = File::create(
+            &mut ctx.storage,
+            &mut ctx.block_alloc,
+            &mut ctx.superblock,
+            ctx.dir,
+            FileType::File,
+            "b",
+            Perms::new(0o644, 0, 0),
+        );
+        assert!(matches!(result, Err(Error::DirEntryExists)));
+
+        ctx.unlink("b").unwrap();
+        assert!(matches!(ctx.read("b"), Err(Error::DirEntryNotFound)));
+        assert_eq!(ctx.list().len(), COLLISION_MASK as usize - 1);
+    }
+
+    #[test]
+    fn rename_replaces_file() {
+        let mut ctx = Context::new();
+        let a = ctx.create_file("a");
+        let b = ctx.create_file("b");
+
+        let orphan = ctx.rename("a", "b", RenameMode::Replace).unwrap();
+
+        assert_eq!(orphan, Some(b));
+        assert_eq!(ctx.read("b").unwrap().id, a);
+        assert_eq!(ctx.list(), ["b"]);
+        assert_eq!(Orphan::list(&ctx.storage, &ctx.superblock).unwrap(), [b]);
+    }
+
+    #[test]
+    fn unlink_keeps_orphan() {
+        let mut ctx = Context::new();
+        let a = ctx.create_file("a");
+        let b = ctx.create_file("b");
+
+        assert_eq!(ctx.unlink("a").unwrap(), Some(a));
+        assert_eq!(ctx.unlink("b").unwrap(), Some(b));
+        assert!(ctx.list().is_empty());
+
+        let node = Node::read(&ctx.storage, &ctx.superblock, a).unwrap();
+        assert_eq!(node.links, 0);
+        let mut orphans = Orphan::list(&ctx.storage, &ctx.superblock).unwrap();
+        orphans.sort();
+        assert_eq!(orphans, [a, b]);
+
+        Orphan::remove(
+            &mut ctx.storage,
+            &mut ctx.block_alloc,
+            &mut ctx.superblock,
+            a,
+        )
+        .unwrap();
+        assert!(matches!(
+            Node::read(&ctx.storage, &ctx.superblock, a),
+            Err(Error::NodeNotFound)
+        ));
+        assert_eq!(Orphan::list(&ctx.storage, &ctx.superblock).unwrap(), [b]);
+        assert!(matches!(
+            Orphan::remove(
+                &mut ctx.storage,
+                &mut ctx.block_alloc,
+                &mut ctx.superblock,
+                a
+            ),
+            Err(Error::NotOrphan)
+        ));
+    }
+
+    #[test]
+    fn rename_replaces_linked_file() {
+        let mut ctx = Context::new();
+        let a = ctx.create_file("a");
+        let b = ctx.create_file("b");
+        let dir = ctx.dir;
+        DirEntry::link(
+            &mut ctx.storage,
+            &mut ctx.block_alloc,
+            &mut ctx.superblock,
+            dir,
+            b,
+            Context::name("c"),
+        )
+        .unwrap();
+
+        let orphan = ctx.rename("a", "b", RenameMode::Replace).unwrap();
+
+        assert_eq!(orphan, None);
+        assert_eq!(ctx.read("b").unwrap().id, a);
+        assert_eq!(ctx.read("c").unwrap().id, b);
+        let node = Node::read(&ctx.storage, &ctx.superblock, b).unwrap();
+        assert_eq!(node.links, 1);
+    }
+
+    #[test]
+    fn rename_between_links() {
+        let mut ctx = Context::new();
+        let a = ctx.create_file("a");
+        let dir = ctx.dir;
+        DirEntry::link(
+            &mut ctx.storage,
+            &mut ctx.block_alloc,
+            &mut ctx.superblock,
+            dir,
+            a,
+            Context::name("b"),
+        )
+        .unwrap();
+
+        ctx.rename("a", "b", RenameMode::Replace).unwrap();
+
+        assert_eq!(ctx.list(), ["a", "b"]);
+    }
+
+    #[test]
+    fn rename_no_replace() {
+        let mut ctx = Context::new();
+        let a = ctx.create_file("a");
+        let b = ctx.create_file("b");
+
+        let result = ctx.rename("a", "b", RenameMode::NoReplace);
+        assert!(matches!(result, Err(Error::DirEntryExists)));
+        assert_eq!(ctx.read("a").unwrap().id, a);
+        assert_eq!(ctx.read("b").unwrap().id, b);
+
+        ctx.rename("a", "c", RenameMode::NoReplace).unwrap();
+        assert_eq!(ctx.list(), ["b", "c"]);
+    }
+
+    #[test]
+    fn rename_exchange() {
+        let mut ctx = Context::new();
+        let a = ctx.create_file("a");
+        let b = ctx.create_file("b");
+
+        ctx.rename("a", "b", RenameMode::Exchange).unwrap();
+        assert_eq!(ctx.read("a").unwrap().id, b);
+        assert_eq!(ctx.read("b").unwrap().id, a);
+
+        let result = ctx.rename("a", "c", RenameMode::Exchange);
+        assert!(matches!(result, Err(Error::DirEntryNotFound)));
+    }
+
+    #[test]
+    fn rename_replaces_dir() {
+        let mut ctx = Context::new();
+        let dir = ctx.dir;
+        let perms = Perms::new(0o755, 0, 0);
+        let mkdir = |ctx: &mut Context, name: &str| {
+            Dir::create(
+                &mut ctx.storage,
+                &mut ctx.block_alloc,
+                &mut ctx.superblock,
+                dir,
+                Context::name(name),
+                perms,
+            )
+            .unwrap()
+        };
+        let a = mkdir(&mut ctx, "a");
+        let b = mkdir(&mut ctx, "b");
+        let file = ctx.create_file("file");
+
+        assert!(matches!(
+            ctx.rename("a", "file", RenameMode::Replace),
+            Err(Error::NotDir)
+        ));
+        assert!(matches!(
+            ctx.rename("file", "a", RenameMode::Replace),
+            Err(Error::IsDir)
+        ));
+
+        let orphan = ctx.rename("a", "b", RenameMode::Replace).unwrap();
+        assert_eq!(orphan, None);
+        assert_eq!(ctx.read("b").unwrap().id, a);
+        assert!(matches!(
+            Node::read(&ctx.storage, &ctx.superblock, b),
+            Err(Error::NodeNotFound)
+        ));
+
+        // Non-empty directories can't be replaced
+        let c = mkdir(&mut ctx, "c");
+        DirEntry::rename(
+            &mut ctx.storage,
+            &mut ctx.block_alloc,
+            &mut ctx.superblock,
+            dir,
+            &Context::name("file"),
+            c,
+            &Context::name("file"),
+            RenameMode::Replace,
+        )
+        .unwrap();
+        assert!(matches!(
+            ctx.rename("b", "c", RenameMode::Replace),
+            Err(Error::DirNotEmpty)
+        ));
+        let entry = DirEntry::read(&ctx.storage, &ctx.superblock, c, &Context::name("file"));
+        assert_eq!(entry.unwrap().id, file);
+    }
+}
@@ -20,6 +20,17 @@ impl Extent {
             len: len.into(),
         }
     }
+
+    /// Interprets an extent stored in the tree, checking that it lies within the filesystem.
+    pub fn parse(bytes: &[u8], superblock: &Superblock) -> Result<Self> {
+        let ext = Self::read_from_bytes(bytes).map_err(|_| Error::Uninterpretable)?;
+        let end = ext.start().checked_add(ext.len());
+        if !end.is_some_and(|end| end > ext.start() && end <= superblock.block_count) {
+            return Err(Error::Uninterpretable);
+        }
+        Ok(ext)
+    }
+
     pub fn start(&self) -> BlockAddr {
         self.start.get()
     }
@@ -44,6 +55,8 @@ pub struct MappedExtent {
     pub start: u64,
     pub len: u64,
     pub inner: Extent,
+    /// Whether the blocks were just allocated, so they hold stale data rather than the file's.
+    pub is_new: bool,
 }
 
 impl MappedExtent {
@@ -62,10 +75,19 @@ impl MappedExtent {
             if key.id != id || key.datatype != DataType::Extent {
                 return Ok(None);
             }
-            let inner = Extent::read_from_bytes(&ext).map_err(|_| Error::Uninterpretable)?;
+            let inner = Extent::parse(&ext, superblock)?;
             let start = key.offset();
+            // Fits, as the extent lies within the filesystem
             let len = inner.len() * BLOCK_SIZE;
-            let ext = Self { start, len, inner };
+            if start.checked_add(len).is_none() {
+                return Err(Error::Uninterpretable);
+            }
+            let ext = Self {
+                start,
+                len,
+                inner,
+                is_new: false,
+            };
             if offset < ext.end() {
                 return Ok(Some(ext));
             }
@@ -86,7 +108,16 @@ impl MappedExtent {
         }
 
         let start = (offset / BLOCK_SIZE) * BLOCK_SIZE;
-        let end = offset + len;
+        let mut end = offset + len;
+        // Stops short of the extents mapped past the offset, which must not overlap
+        while let Some((key, _)) =
+            Tree::get_le(storage, superblock.root_addr, Key::extent(id, end - 1))?
+            && key.id == id
+            && key.datatype == DataType::Extent
+            && key.offset() > offset
+        {
+            end = key.offset();
+        }
         let len = end - start;
 
         let ext_len = len.div_ceil(BLOCK_SIZE);
@@ -108,6 +139,46 @@ impl MappedExtent {
             start,
             len,
             inner: ext,
+            is_new: true,
         })
     }
+
+    /// Moves the `count` blocks starting at block `first` of the extent to freshly allocated
+    /// ones, splitting the extent around them, so that writing them leaves the committed blocks
+    /// untouched. The old blocks are freed, but keep their contents until the commit.
+    /// Returns the address of the first new block.
+    pub fn relocate(
+        &self,
+        storage: &mut impl Storage,
+        block_alloc: &mut impl block::Allocator,
+        superblock: &mut Superblock,
+        id: NodeId,
+        first: u64,
+        count: u64,
+    ) -> Result<BlockAddr> {
+        let new_start = block_alloc.allocate(count)?;
+        let end = first + count;
+        let start = self.inner.start();
+        let parts = [
+            (0, start, first),
+            (first, new_start, count),
+            (end, start + end, self.inner.len() - end),
+        ];
+        for (idx, start, len) in parts {
+            if len == 0 {
+                continue;
+            }
+            let ext = Extent::new(start, len);
+            let key = Key::extent(id, self.start + idx * BLOCK_SIZE);
+            Tree::insert(
+                storage,
+                block_alloc,
+                &mut superblock.root_addr,
+                key,
+                ext.as_bytes(),
+            )?;
+        }
+        block_alloc.deallocate(start + first, count)?;
+        Ok(new_start)
+    }
 }
@@ -1,6 +1,11 @@
+use std::collections::BTreeMap;
+
 use super::*;
 use dir::*;
 
+/// The most blocks of a file read from storage at once.
+const READ_BATCH: u64 = 256;
+
 pub struct File;
 
 impl File {
@@ -11,9 +16,14 @@ impl File {
         parent: NodeId,
         filetype: FileType,
         name: &str,
+        perms: Perms,
     ) -> Result<NodeId> {
         let name = DirEntryName::try_from(name)?;
-        let id = Node::create(storage, block_alloc, superblock, filetype, 1)?;
+        if DirEntry::find(storage, superblock, parent, &name)?.is_some() {
+            return Err(Error::DirEntryExists);
+        }
+
+        let id = Node::create(storage, block_alloc, superblock, filetype, 1, perms)?;
         DirEntry::create(storage, block_alloc, superblock, parent, filetype, id, name)?;
         Ok(id)
     }
@@ -36,7 +46,8 @@ impl File {
         buf = &mut buf[..to_read as usize];
 
         let mut read = 0;
-        let mut block = Block::default();
+        let mut blocks = Vec::new();
+        let mut verifier = superblock.has_data_checksums().then(|| Verifier::new(id));
 
         while !buf.is_empty() {
             if let Some(map) = MappedExtent::read(storage, superblock, id, offset)? {
@@ -48,29 +59,41 @@ impl File {
                 let mut offset_in_block = offset_in_ext % BLOCK_SIZE;
 
                 while remain_in_ext != 0 {
-                    let addr = map.inner.start() + block_idx;
-
-                    let remain_in_block = BLOCK_SIZE - offset_in_block;
-                    let chunk_size = remain_in_block.min(remain_in_ext);
-
-                    storage.read_at(&mut block, addr)?;
-
-                    let dst_end = chunk_size as usize;
-                    let (dst, remain) = buf.split_at_mut(dst_end);
-
-                    let src_start = offset_in_block as usize;
-                    let src_end = src_start + chunk_size as usize;
-                    let src = &block[src_start..src_end];
-
-                    dst.copy_from_slice(src);
-
-                    buf = remain;
-                    read += chunk_size;
-                    offset += chunk_size;
-                    remain_in_ext -= chunk_size;
-
-                    offset_in_block = 0;
-                    block_idx += 1;
+                    // The blocks left to read in the extent, up to a batch
+                    let count = (offset_in_block + remain_in_ext)
+                        .div_ceil(BLOCK_SIZE)
+                        .min(READ_BATCH);
+                    blocks.resize(count as usize, Block::default());
+                    let mut pairs: Vec<_> =
+                        (map.inner.start() + block_idx..).zip(&mut blocks).collect();
+                    storage.read_blocks(&mut pairs)?;
+
+                    for block in &blocks {
+                        let remain_in_block = BLOCK_SIZE - offset_in_block;
+                        let chunk_size = remain_in_block.min(remain_in_ext);
+
+                        if let Some(verifier) = &mut verifier {
+                            let block_offset = map.start + block_idx * BLOCK_SIZE;
+                            verifier.verify(storage, superblock, block_offset, block)?;
+                        }
+
+                        let dst_end = chunk_size as usize;
+                        let (dst, remain) = buf.split_at_mut(dst_end);
+
+                        let src_start = offset_in_block as usize;
+                        let src_end = src_start + chunk_size as usize;
+                        let src = &block[src_start..src_end];
+
+                        dst.copy_from_slice(src);
+
+                        buf = remain;
+                        read += chunk_size;
+                        offset += chunk_size;
+                        remain_in_ext -= chunk_size;
+
+                        offset_in_block = 0;
+                        block_idx += 1;
+                    }
                 }
             } else {
                 let offset_in_block = offset % BLOCK_SIZE;
@@ -100,9 +123,16 @@ impl File {
         mut buf: &[u8],
     ) -> Result<u64> {
         let mut node = Node::read(storage, superblock, id)?;
+        // Writing nothing leaves the file as is, even past its end
+        if buf.is_empty() {
+            return Ok(0);
+        }
 
         let mut written = 0;
         let mut block = Block::default();
+        let mut checksums = BTreeMap::new();
+        // Partially overwritten blocks are verified, so that their corruption isn't checksummed
+        let mut verifier = superblock.has_data_checksums().then(|| Verifier::new(id));
 
         while !buf.is_empty() {
             let map = MappedExtent::ensure(
@@ -121,14 +151,33 @@ impl File {
             let mut block_idx = offset_in_ext / BLOCK_SIZE;
             let mut offset_in_block = offset_in_ext % BLOCK_SIZE;
 
+            // Committed blocks are never overwritten, so the written ones are moved to fresh
+            // blocks, which are written in place rather than through the journal
+            let first = block_idx;
+            let dst_start = if block_alloc.is_fresh(map.inner.start()) {
+                map.inner.start() + first
+            } else {
+                let count = (offset_in_block + remain_in_ext).div_ceil(BLOCK_SIZE);
+                map.relocate(storage, block_alloc, superblock, id, first, count)?
+            };
+
             while remain_in_ext != 0 {
                 let addr = map.inner.start() + block_idx;
+                let dst_addr = dst_start + block_idx - first;
 
                 let remain_in_block = BLOCK_SIZE - offset_in_block;
                 let chunk_size = remain_in_block.min(remain_in_ext);
 
+                let block_offset = map.start + block_idx * BLOCK_SIZE;
                 if chunk_size != BLOCK_SIZE {
-                    storage.read_at(&mut block, addr)?;
+                    if map.is_new {
+                        block.fill(0);
+                    } else {
+                        storage.read_at(&mut block, addr)?;
+                        if let Some(verifier) = &mut verifier {
+                            verifier.verify(storage, superblock, block_offset, &block)?;
+                        }
+                    }
                 }
 
                 let src_end = chunk_size as usize;
@@ -140,7 +189,10 @@ impl File {
 
                 dst.copy_from_slice(src);
 
-                storage.write_at(&block, addr)?;
+                storage.write_at(&block, dst_addr)?;
+                if superblock.has_data_checksums() {
+                    checksums.insert(block_offset, DataChecksum::of(&block));
+                }
 
                 buf = remain;
                 written += chunk_size;
@@ -152,10 +204,15 @@ impl File {
             }
         }
 
+        if !checksums.is_empty() {
+            DataChecksum::write(storage, block_alloc, superblock, id, &checksums)?;
+        }
+
         if offset > node.size.get() {
             node.size.set(offset);
-            node.write(storage, block_alloc, superblock, id)?;
         }
+        node.touch();
+        node.write(storage, block_alloc, superblock, id)?;
 
         Ok(written)
     }
@@ -177,29 +234,48 @@ impl File {
             Node::truncate_extents(storage, block_alloc, superblock, id, size)?;
 
             let remain = size % BLOCK_SIZE;
-            if remain != 0 {
-                if let Some(map) = MappedExtent::read(storage, superblock, id, size)? {
-                    let addr = map.inner.start() + map.inner.len() - 1;
-                    Self::zero_block_tail(storage, addr, remain)?;
+            if remain != 0
+                && let Some(map) = MappedExtent::read(storage, superblock, id, size)?
+            {
+                let addr = map.inner.start() + map.inner.len() - 1;
+                let block = Self::zero_block_tail(storage, superblock, id, size, addr)?;
+
+                if superblock.has_data_checksums() {
+                    let checksums = BTreeMap::from([(size - remain, DataChecksum::of(&block))]);
+                    DataChecksum::write(storage, block_alloc, superblock, id, &checksums)?;
                 }
             }
         }
 
         node.size.set(size);
+        node.touch();
         node.write(storage, block_alloc, superblock, id)?;
 
         Ok(())
     }
 
-    pub fn zero_block_tail(storage: &mut impl Storage, addr: BlockAddr, remain: u64) -> Result<()> {
+    /// Zeroes the block at `addr`, holding the end of the file once truncated to `size`, past
+    /// that end, returning its new contents.
+    /// The block is verified first, so that its corruption isn't checksummed.
+    pub fn zero_block_tail(
+        storage: &mut impl Storage,
+        superblock: &Superblock,
+        id: NodeId,
+        size: u64,
+        addr: BlockAddr,
+    ) -> Result<Block> {
         let mut block = Block::default();
         storage.read_at(&mut block, addr)?;
+        let remain = size % BLOCK_SIZE;
+        if superblock.has_data_checksums() {
+            Verifier::new(id).verify(storage, superblock, size - remain, &block)?;
+        }
 
         let start = usize::try_from(remain).unwrap();
         block[start..].fill(0);
 
         storage.write_at(&block, addr)?;
 
-        Ok(())
+        Ok(block)
     }
 }
@@ -1,15 +1,25 @@
+pub mod bucket;
+use bucket::*;
+pub mod checksum;
+use checksum::*;
 pub mod dir;
 pub mod extent;
 use extent::*;
 pub mod file;
 pub mod hash;
+pub mod orphan;
+use orphan::*;
 pub mod symlink;
+pub mod time;
+use time::*;
+pub mod xattr;
+use xattr::*;
 
 use super::error::*;
 
 use zerocopy::{
     FromBytes, Immutable, IntoBytes, KnownLayout, TryFromBytes, Unaligned,
-    little_endian::{U32, U64},
+    little_endian::{U16, U32, U64},
 };
 
 use crate::{
@@ -49,17 +59,61 @@ pub struct Node {
     pub size: U64,
     pub filetype: FileType,
     pub links: U32,
+    // Last access time
+    pub atime: Timestamp,
+    // Last content modification time
+    pub mtime: Timestamp,
+    // Last metadata change time
+    pub ctime: Timestamp,
+    // Creation time
+    pub crtime: Timestamp,
+    // Permission bits, without the filetype
+    pub mode: U16,
+    pub uid: U32,
+    pub gid: U32,
 }
 
 impl Node {
-    /// Constructs a node of given filetype.
-    pub fn new(filetype: FileType, links: u32) -> Self {
+    /// Constructs a node of given filetype, with all times set to now.
+    pub fn new(filetype: FileType, links: u32, perms: Perms) -> Self {
+        let now = Timestamp::now();
         Self {
             size: 0.into(),
             filetype,
             links: links.into(),
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            mode: (perms.mode & Perms::MODE_MASK).into(),
+            uid: perms.uid.into(),
+            gid: perms.gid.into(),
         }
     }
+
+    /// Sets the modification and change times to now.
+    pub fn touch(&mut self) {
+        let now = Timestamp::now();
+        self.mtime = now;
+        self.ctime = now;
+    }
+}
+
+/// Ownership and permission bits of a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Perms {
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Perms {
+    /// The bits of a mode that are stored in a node.
+    pub const MODE_MASK: u16 = 0o7777;
+
+    pub fn new(mode: u16, uid: u32, gid: u32) -> Self {
+        Self { mode, uid, gid }
+    }
 }
 
 /// Filetypes.
@@ -80,9 +134,10 @@ impl Node {
         superblock: &mut Superblock,
         filetype: FileType,
         links: u32,
+        perms: Perms,
     ) -> Result<NodeId> {
         let id = superblock.allocate_node();
-        let node = Self::new(filetype, links);
+        let node = Self::new(filetype, links, perms);
         let key = Key::node(id);
         Tree::try_insert(
             storage,
@@ -119,6 +174,62 @@ impl Node {
         Ok(())
     }
 
+    /// Sets the modification and change times of a node to now.
+    pub fn touch_id(
+        storage: &mut impl Storage,
+        block_alloc: &mut impl block::Allocator,
+        superblock: &mut Superblock,
+        id: NodeId,
+    ) -> Result<()> {
+        let mut node = Self::read(storage, superblock, id)?;
+        node.touch();
+        node.write(storage, block_alloc, superblock, id)
+    }
+
+    /// Sets the access and modification times of a node, updating its change time.
+    pub fn set_times(
+        storage: &mut impl Storage,
+        block_alloc: &mut impl block::Allocator,
+        superblock: &mut Superblock,
+        id: NodeId,
+        atime: Option<Timestamp>,
+        mtime: Option<Timestamp>,
+    ) -> Result<()> {
+        let mut node = Self::read(storage, superblock, id)?;
+        if let Some(atime) = atime {
+            node.atime = atime;
+        }
+        if let Some(mtime) = mtime {
+            node.mtime = mtime;
+        }
+        node.ctime = Timestamp::now();
+        node.write(storage, block_alloc, superblock, id)
+    }
+
+    /// Changes the ownership and permission bits of a node, updating its change time.
+    pub fn set_perms(
+        storage: &mut impl Storage,
+        block_alloc: &mut impl block::Allocator,
+        superblock: &mut Superblock,
+        id: NodeId,
+        mode: Option<u16>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> Result<()> {
+        let mut node = Self::read(storage, superblock, id)?;
+        if let Some(mode) = mode {
+            node.mode.set(mode & Perms::MODE_MASK);
+        }
+        if let Some(uid) = uid {
+            node.uid.set(uid);
+        }
+        if let Some(gid) = gid {
+            node.gid.set(gid);
+        }
+        node.ctime = Timestamp::now();
+        node.write(storage, block_alloc, superblock, id)
+    }
+
     pub fn remove(
         storage: &mut impl Storage,
         block_alloc: &mut impl block::Allocator,
@@ -130,6 +241,7 @@ impl Node {
         if node.filetype == FileType::File || node.filetype == FileType::Symlink {
             Self::truncate_extents(storage, block_alloc, superblock, id, 0)?;
         }
+        Xattr::remove_all(storage, block_alloc, superblock, id)?;
 
         let key = Key::node(id);
         Tree::remove(storage, block_alloc, &mut superblock.root_addr, key)?;
@@ -145,6 +257,8 @@ impl Node {
         id: NodeId,
         size: u64,
     ) -> Result<()> {
+        DataChecksum::truncate(storage, block_alloc, superblock, id, size)?;
+
         let key = Key::extent(id, u64::MAX);
         while let Some((key, bytes)) = Tree::get_le(storage, superblock.root_addr, key)? {
             if key.id != id || key.datatype != DataType::Extent {
@@ -154,11 +268,10 @@ impl Node {
             if key.offset() >= size {
                 Tree::remove(storage, block_alloc, &mut superblock.root_addr, key)?
                     .expect("extent exists because 'key' exists");
-                let ext = Extent::read_from_bytes(&bytes).map_err(|_| Error::Uninterpretable)?;
+                let ext = Extent::parse(&bytes, superblock)?;
                 block_alloc.deallocate(ext.start(), ext.len())?;
             } else {
-                let mut ext =
-                    Extent::read_from_bytes(&bytes).map_err(|_| Error::Uninterpretable)?;
+                let mut ext = Extent::parse(&bytes, superblock)?;
 
                 let keep_bytes = size - key.offset();
                 let keep_blocks = keep_bytes.div_ceil(BLOCK_SIZE);
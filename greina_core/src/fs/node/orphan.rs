@@ -0,0 +1,62 @@
+use crate::{
+    block::{self, storage::Storage},
+    tree::{DataType, Key, Tree},
+};
+
+use super::*;
+
+/// A node that lost its last link while something still referenced it, like an open file.
+/// Its data is kept until [Orphan::remove] is called once the last reference is dropped.
+/// Orphans left over by a crash are removed when the filesystem is mounted.
+pub struct Orphan;
+
+impl Orphan {
+    pub fn insert(
+        storage: &mut impl Storage,
+        block_alloc: &mut impl block::Allocator,
+        superblock: &mut Superblock,
+        id: NodeId,
+    ) -> Result<()> {
+        let key = Key::orphan(id);
+        Tree::try_insert(storage, block_alloc, &mut superblock.root_addr, key, &[])?;
+        Ok(())
+    }
+
+    /// Removes an orphan along with its node.
+    pub fn remove(
+        storage: &mut impl Storage,
+        block_alloc: &mut impl block::Allocator,
+        superblock: &mut Superblock,
+        id: NodeId,
+    ) -> Result<()> {
+        let key = Key::orphan(id);
+        Tree::remove(storage, block_alloc, &mut superblock.root_addr, key)?
+            .ok_or(Error::NotOrphan)?;
+        Node::remove(storage, block_alloc, superblock, id)
+    }
+
+    pub fn contains(storage: &impl Storage, superblock: &Superblock, id: NodeId) -> Result<bool> {
+        let key = Key::orphan(id);
+        Ok(Tree::get(storage, superblock.root_addr, key)?.is_some())
+    }
+
+    pub fn list(storage: &impl Storage, superblock: &Superblock) -> Result<Vec<NodeId>> {
+        let mut ids = Vec::new();
+
+        let mut curr_key = Key::orphan(NodeId::new(u64::MAX));
+        while let Some((key, _)) = Tree::get_le(storage, superblock.root_addr, curr_key)? {
+            if key.id != NodeId::NULL || key.datatype != DataType::Orphan {
+                break;
+            }
+
+            ids.push(NodeId::new(key.offset()));
+
+            if key.offset() == 0 {
+                break;
+            }
+            curr_key = Key::orphan(NodeId::new(key.offset() - 1));
+        }
+
+        Ok(ids)
+    }
+}
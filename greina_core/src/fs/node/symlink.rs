@@ -13,6 +13,7 @@ impl Symlink {
         parent: NodeId,
         name: &str,
         target: &str,
+        perms: Perms,
     ) -> Result<NodeId> {
         let id = File::create(
             storage,
@@ -21,6 +22,7 @@ impl Symlink {
             parent,
             FileType::Symlink,
             name,
+            perms,
         )?;
         File::write_at(storage, block_alloc, superblock, id, 0, target.as_bytes())?;
         Ok(id)
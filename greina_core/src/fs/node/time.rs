@@ -0,0 +1,90 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use zerocopy::{
+    FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned,
+    little_endian::{I64, U32},
+};
+
+/// A point in time with nanosecond precision, relative to the Unix epoch.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[derive(FromBytes, IntoBytes, Immutable, Unaligned, KnownLayout)]
+pub struct Timestamp {
+    // Whole seconds, negative before the epoch
+    pub secs: I64,
+    // Nanoseconds added to 'secs', always less than a second
+    pub nanos: U32,
+}
+
+impl Timestamp {
+    pub fn new(secs: i64, nanos: u32) -> Self {
+        Self {
+            secs: secs.into(),
+            nanos: nanos.into(),
+        }
+    }
+
+    /// Returns the current system time.
+    pub fn now() -> Self {
+        SystemTime::now().into()
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => Self::new(since.as_secs() as i64, since.subsec_nanos()),
+            Err(err) => {
+                let before = err.duration();
+                let secs = -(before.as_secs() as i64);
+                match before.subsec_nanos() {
+                    0 => Self::new(secs, 0),
+                    nanos => Self::new(secs - 1, 1_000_000_000 - nanos),
+                }
+            }
+        }
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    /// Timestamps the system can't represent are read as the epoch.
+    fn from(time: Timestamp) -> Self {
+        let secs = time.secs.get();
+        let nanos = Duration::from_nanos(time.nanos.get().into());
+        let whole = if secs >= 0 {
+            UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64))
+        } else {
+            UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs()))
+        };
+        whole
+            .and_then(|whole| whole.checked_add(nanos))
+            .unwrap_or(UNIX_EPOCH)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_system_time() {
+        let times = [
+            UNIX_EPOCH,
+            UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789),
+            UNIX_EPOCH - Duration::new(10, 0),
+            UNIX_EPOCH - Duration::new(10, 250),
+        ];
+
+        for time in times {
+            let timestamp = Timestamp::from(time);
+            assert!(timestamp.nanos.get() < 1_000_000_000);
+            assert_eq!(SystemTime::from(timestamp), time);
+        }
+    }
+
+    #[test]
+    fn saturates_unrepresentable_time() {
+        let timestamp = Timestamp::new(i64::MAX, u32::MAX);
+        assert_eq!(SystemTime::from(timestamp), UNIX_EPOCH);
+    }
+}
@@ -0,0 +1,415 @@
+use crate::{
+    block::{self, storage::Storage},
+    tree::{DATA_MAX_LEN, DataType, Key, Tree},
+};
+
+use super::*;
+
+/// How long an extended attribute name can be.
+pub const NAME_MAX_LEN: usize = 255;
+
+/// How an extended attribute is set when one with the same name exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetMode {
+    // Creates or replaces the attribute
+    Upsert,
+    // Fails if the attribute exists
+    Create,
+    // Fails if the attribute doesn't exist
+    Replace,
+}
+
+/// An extended attribute of a node.
+/// Attributes whose names share a hash are stored in the same [Bucket] of key offsets, like
+/// directory entries.
+///
+/// # Layout
+/// Stored as the name's length in a single byte, followed by the name and the value.
+pub struct Xattr {
+    pub name: Box<[u8]>,
+    pub value: Box<[u8]>,
+}
+
+impl Xattr {
+    fn as_bytes(&self) -> Box<[u8]> {
+        let mut bytes = Vec::with_capacity(1 + self.name.len() + self.value.len());
+        bytes.push(self.name.len() as u8);
+        bytes.extend_from_slice(&self.name);
+        bytes.extend_from_slice(&self.value);
+        bytes.into()
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self> {
+        let (&name_len, remain) = bytes.split_first().ok_or(Error::Uninterpretable)?;
+        let (name, value) = remain
+            .split_at_checked(name_len.into())
+            .ok_or(Error::Uninterpretable)?;
+        Ok(Self {
+            name: name.into(),
+            value: value.into(),
+        })
+    }
+
+    fn validate_name(name: &[u8]) -> Result<()> {
+        if name.is_empty() || name.len() > NAME_MAX_LEN || name.contains(&0) {
+            return Err(Error::InvalidXattrName);
+        }
+        Ok(())
+    }
+
+    /// Returns the first key offset of the bucket `name` is stored in.
+    fn hash(name: &[u8]) -> u64 {
+        Bucket::first(hash::fnv_hash(name))
+    }
+
+    /// Reads the attributes stored in the bucket `name` belongs to.
+    fn read_bucket(
+        storage: &impl Storage,
+        superblock: &Superblock,
+        id: NodeId,
+        name: &[u8],
+    ) -> Result<Vec<(Key, Self)>> {
+        let first = Key::xattr(id, Self::hash(name));
+        Bucket::read(storage, superblock, first)?
+            .into_iter()
+            .map(|(key, val)| Ok((key, Self::try_from_bytes(&val)?)))
+            .collect()
+    }
+
+    /// Finds the attribute with a given name, along with its key.
+    fn find(
+        storage: &impl Storage,
+        superblock: &Superblock,
+        id: NodeId,
+        name: &[u8],
+    ) -> Result<Option<(Key, Self)>> {
+        let bucket = Self::read_bucket(storage, superblock, id, name)?;
+        Ok(bucket.into_iter().find(|(_, xattr)| *xattr.name == *name))
+    }
+
+    pub fn get(
+        storage: &impl Storage,
+        superblock: &Superblock,
+        id: NodeId,
+        name: &[u8],
+    ) -> Result<Box<[u8]>> {
+        Self::validate_name(name)?;
+        // Make sure the node exists
+        Node::read(storage, superblock, id)?;
+
+        let (_, xattr) = Self::find(storage, superblock, id, name)?.ok_or(Error::XattrNotFound)?;
+        Ok(xattr.value)
+    }
+
+    pub fn set(
+        storage: &mut impl Storage,
+        block_alloc: &mut impl block::Allocator,
+        superblock: &mut Superblock,
+        id: NodeId,
+        name: &[u8],
+        value: &[u8],
+        mode: SetMode,
+    ) -> Result<()> {
+        Self::validate_name(name)?;
+        if 1 + name.len() + value.len() > DATA_MAX_LEN {
+            return Err(Error::XattrTooLarge);
+        }
+
+        let mut node = Node::read(storage, superblock, id)?;
+
+        let bucket = Self::read_bucket(storage, superblock, id, name)?;
+        let existing = bucket.iter().find(|(_, xattr)| *xattr.name == *name);
+        match (mode, existing) {
+            (SetMode::Create, Some(_)) => return Err(Error::XattrExists),
+            (SetMode::Replace, None) => return Err(Error::XattrNotFound),
+            _ => (),
+        }
+
+        // An existing attribute is overwritten, a new one takes the first free slot
+        let key = match existing {
+            Some(&(key, _)) => key,
+            None => {
+                let first = Key::xattr(id, Self::hash(name));
+                Bucket::free_slot(storage, block_alloc, superblock, first)?
+                    .ok_or(Error::XattrNoSpace)?
+            }
+        };
+
+        let xattr = Self {
+            name: name.into(),
+            value: value.into(),
+        };
+        Tree::insert(
+            storage,
+            block_alloc,
+            &mut superblock.root_addr,
+            key,
+            &xattr.as_bytes(),
+        )?;
+
+        node.ctime = Timestamp::now();
+        node.write(storage, block_alloc, superblock, id)
+    }
+
+    pub fn remove(
+        storage: &mut impl Storage,
+        block_alloc: &mut impl block::Allocator,
+        superblock: &mut Superblock,
+        id: NodeId,
+        name: &[u8],
+    ) -> Result<()> {
+        Self::validate_name(name)?;
+        let mut node = Node::read(storage, superblock, id)?;
+
+        let (key, _) = Self::find(storage, superblock, id, name)?.ok_or(Error::XattrNotFound)?;
+        Tree::remove(storage, block_alloc, &mut superblock.root_addr, key)?;
+
+        node.ctime = Timestamp::now();
+        node.write(storage, block_alloc, superblock, id)
+    }
+
+    /// Lists the names of a node's attributes.
+    pub fn list(
+        storage: &impl Storage,
+        superblock: &Superblock,
+        id: NodeId,
+    ) -> Result<Vec<Box<[u8]>>> {
+        // Make sure the node exists
+        Node::read(storage, superblock, id)?;
+
+        let mut names = Vec::new();
+
+        let mut curr_key = Key::xattr(id, u64::MAX);
+        while let Some((key, val)) = Tree::get_le(storage, superblock.root_addr, curr_key)? {
+            if key.id != id || key.datatype != DataType::Xattr {
+                break;
+            }
+
+            if !Bucket::is_overflow(&val) {
+                names.push(Self::try_from_bytes(&val)?.name);
+            }
+
+            let hash = key.offset();
+            if hash == 0 {
+                break;
+            }
+            curr_key = Key::xattr(id, hash - 1);
+        }
+
+        Ok(names)
+    }
+
+    /// Removes all attributes of a node, along with their buckets' overflow markers.
+    pub(super) fn remove_all(
+        storage: &mut impl Storage,
+        block_alloc: &mut impl block::Allocator,
+        superblock: &mut Superblock,
+        id: NodeId,
+    ) -> Result<()> {
+        let key = Key::xattr(id, u64::MAX);
+        while let Some((key, _)) = Tree::get_le(storage, superblock.root_addr, key)? {
+            if key.id != id || key.datatype != DataType::Xattr {
+                break;
+            }
+            Tree::remove(storage, block_alloc, &mut superblock.root_addr, key)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::block::{Allocator, allocator::fake::FakeAllocator, storage::fake::FakeStorage};
+
+    struct Context {
+        storage: FakeStorage,
+        block_alloc: FakeAllocator,
+        superblock: Superblock,
+        id: NodeId,
+    }
+
+    impl Context {
+        fn new() -> Self {
+            let mut storage = FakeStorage::default();
+            let mut block_alloc = FakeAllocator::default();
+            let mut superblock = Superblock::new(0);
+            superblock.root_addr = block_alloc.allocate(1).unwrap();
+            Tree::format(&mut storage, superblock.root_addr).unwrap();
+
+            let id = Node::create(
+                &mut storage,
+                &mut block_alloc,
+                &mut superblock,
+                FileType::File,
+                1,
+                Perms::new(0o644, 0, 0),
+            )
+            .unwrap();
+
+            Self {
+                storage,
+                block_alloc,
+                superblock,
+                id,
+            }
+        }
+
+        fn set(&mut self, name: &str, value: &str, mode: SetMode) -> Result<()> {
+            Xattr::set(
+                &mut self.storage,
+                &mut self.block_alloc,
+                &mut self.superblock,
+                self.id,
+                name.as_bytes(),
+                value.as_bytes(),
+                mode,
+            )
+        }
+
+        /// Stores an attribute at slot `slot` of `like`'s bucket, as if their names had the same
+        /// hash.
+        fn set_colliding(&mut self, name: &str, value: &str, like: &str, slot: u64) {
+            let xattr = Xattr {
+                name: name.as_bytes().into(),
+                value: value.as_bytes().into(),
+            };
+            let key = Key::xattr(self.id, Xattr::hash(like.as_bytes()) + slot);
+            Tree::try_insert(
+                &mut self.storage,
+                &mut self.block_alloc,
+                &mut self.superblock.root_addr,
+                key,
+                &xattr.as_bytes(),
+            )
+            .unwrap();
+        }
+
+        fn get(&self, name: &str) -> Result<String> {
+            let value = Xattr::get(&self.storage, &self.superblock, self.id, name.as_bytes())?;
+            Ok(String::from_utf8(value.into()).unwrap())
+        }
+
+        fn remove(&mut self, name: &str) -> Result<()> {
+            Xattr::remove(
+                &mut self.storage,
+                &mut self.block_alloc,
+                &mut self.superblock,
+                self.id,
+                name.as_bytes(),
+            )
+        }
+
+        fn list(&self) -> Vec<String> {
+            let mut names: Vec<_> = Xattr::list(&self.storage, &self.superblock, self.id)
+                .unwrap()
+                .into_iter()
+                .map(|name| String::from_utf8(name.into()).unwrap())
+                .collect();
+            names.sort();
+            names
+        }
+    }
+
+    #[test]
+    fn set_and_get() {
+        let mut ctx = Context::new();
+        ctx.set("user.a", "1", SetMode::Upsert).unwrap();
+        ctx.set("user.b", "2", SetMode::Upsert).unwrap();
+        assert_eq!(ctx.get("user.a").unwrap(), "1");
+        assert_eq!(ctx.get("user.b").unwrap(), "2");
+        assert!(matches!(ctx.get("user.c"), Err(Error::XattrNotFound)));
+
+        ctx.set("user.a", "3", SetMode::Upsert).unwrap();
+        assert_eq!(ctx.get("user.a").unwrap(), "3");
+        assert_eq!(ctx.list(), ["user.a", "user.b"]);
+    }
+
+    #[test]
+    fn set_modes() {
+        let mut ctx = Context::new();
+        let result = ctx.set("user.a", "1", SetMode::Replace);
+        assert!(matches!(result, Err(Error::XattrNotFound)));
+        ctx.set("user.a", "1", SetMode::Create).unwrap();
+
+        let result = ctx.set("user.a", "2", SetMode::Create);
+        assert!(matches!(result, Err(Error::XattrExists)));
+        ctx.set("user.a", "2", SetMode::Replace).unwrap();
+        assert_eq!(ctx.get("user.a").unwrap(), "2");
+    }
+
+    #[test]
+    fn invalid_names() {
+        let mut ctx = Context::new();
+        for name in ["", "user.\0", &"a".repeat(NAME_MAX_LEN + 1)] {
+            let result = ctx.set(name, "1", SetMode::Upsert);
+            assert!(matches!(result, Err(Error::InvalidXattrName)));
+        }
+
+        let value = "a".repeat(DATA_MAX_LEN);
+        let result = ctx.set("user.a", &value, SetMode::Upsert);
+        assert!(matches!(result, Err(Error::XattrTooLarge)));
+    }
+
+    #[test]
+    fn remove() {
+        let mut ctx = Context::new();
+        ctx.set("user.a", "1", SetMode::Upsert).unwrap();
+        ctx.set("user.b", "2", SetMode::Upsert).unwrap();
+        ctx.remove("user.a").unwrap();
+
+        assert!(matches!(ctx.get("user.a"), Err(Error::XattrNotFound)));
+        assert!(matches!(ctx.remove("user.a"), Err(Error::XattrNotFound)));
+        assert_eq!(ctx.list(), ["user.b"]);
+    }
+
+    #[test]
+    fn colliding_names() {
+        let mut ctx = Context::new();
+        // Only found through the bucket it was stored in, which isn't its name's
+        ctx.set_colliding("user.b", "2", "user.a", 0);
+
+        // The attribute takes the next slot of the bucket
+        ctx.set("user.a", "1", SetMode::Create).unwrap();
+        assert_eq!(ctx.get("user.a").unwrap(), "1");
+        assert_eq!(ctx.list(), ["user.a", "user.b"]);
+
+        ctx.set("user.a", "3", SetMode::Replace).unwrap();
+        assert_eq!(ctx.get("user.a").unwrap(), "3");
+        assert_eq!(ctx.list(), ["user.a", "user.b"]);
+
+        ctx.remove("user.a").unwrap();
+        assert!(matches!(ctx.get("user.a"), Err(Error::XattrNotFound)));
+        assert_eq!(ctx.list(), ["user.b"]);
+    }
+
+    #[test]
+    fn overflowing_bucket() {
+        let mut ctx = Context::new();
+        for slot in 0..COLLISION_MASK {
+            ctx.set_colliding(&format!("user.b{slot}"), "2", "user.a", slot);
+        }
+
+        // The full bucket overflows into the next one
+        ctx.set("user.a", "1", SetMode::Create).unwrap();
+        assert_eq!(ctx.get("user.a").unwrap(), "1");
+        assert_eq!(ctx.list().len(), COLLISION_MASK as usize + 1);
+
+        // Freeing a slot keeps the attributes past the bucket reachable
+        let key = Key::xattr(ctx.id, Xattr::hash(b"user.a"));
+        Tree::remove(
+            &mut ctx.storage,
+            &mut ctx.block_alloc,
+            &mut ctx.superblock.root_addr,
+            key,
+        )
+        .unwrap();
+        let result = ctx.set("user.a", "3", SetMode::Create);
+        assert!(matches!(result, Err(Error::XattrExists)));
+
+        ctx.remove("user.a").unwrap();
+        assert!(matches!(ctx.get("user.a"), Err(Error::XattrNotFound)));
+        assert_eq!(ctx.list().len(), COLLISION_MASK as usize - 1);
+    }
+}
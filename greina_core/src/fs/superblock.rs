@@ -1,26 +1,127 @@
+use std::fmt;
+
 use crate::{
     block::{BLOCK_SIZE, Block, BlockAddr},
-    fs::node::NodeId,
+    fs::{
+        error::{Error, Result},
+        journal::{self, Journal},
+        node::NodeId,
+    },
 };
 
-use zerocopy::{FromBytes, Immutable, IntoBytes};
+use zerocopy::{
+    FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned,
+    little_endian::{U32, U64},
+};
 
 /// Filesystem's signature.
 pub const SIGNATURE: &[u8; 8] = b"greinafs";
 
+/// Version of the on-disk format.
+/// Changes that can't be described by a feature flag bump it.
+pub const VERSION: u32 = 1;
+
 /// Superblock's address.
 pub const SUPER_ADDR: BlockAddr = 0;
 
+/// Maximum length of a filesystem's label, in bytes.
+pub const LABEL_MAX_LEN: usize = 64;
+
+/// Compatible features this implementation knows of.
+pub const COMPAT_SUPPORTED: u64 = 0;
+
+/// File data blocks are checksummed, see [crate::fs::node::checksum].
+/// Writing without updating the checksums would make them stale.
+pub const RO_COMPAT_DATA_CHECKSUMS: u64 = 1 << 0;
+/// Read-only compatible features this implementation knows of.
+pub const RO_COMPAT_SUPPORTED: u64 = RO_COMPAT_DATA_CHECKSUMS;
+
+/// Incompatible features this implementation knows of.
+pub const INCOMPAT_SUPPORTED: u64 = 0;
+
+/// Optional features a filesystem was formatted with, by what an implementation that
+/// doesn't know of them can safely do.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Features {
+    // Can be ignored
+    pub compat: u64,
+    // Can be ignored when only reading
+    pub ro_compat: u64,
+    // Can't be ignored
+    pub incompat: u64,
+}
+
+/// A filesystem's unique identifier.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Uuid(pub [u8; 16]);
+
+impl Uuid {
+    /// Generates a random (version 4) UUID.
+    pub fn random() -> Result<Self> {
+        let mut bytes = [0; 16];
+        getrandom::fill(&mut bytes)
+            .map_err(|err| Error::Storage(err.raw_os_error().unwrap_or(libc::EIO)))?;
+        bytes[6] = (bytes[6] & 0x0F) | 0x40;
+        bytes[8] = (bytes[8] & 0x3F) | 0x80;
+        Ok(Self(bytes))
+    }
+}
+
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, byte) in self.0.iter().enumerate() {
+            if matches!(idx, 4 | 6 | 8 | 10) {
+                write!(f, "-")?;
+            }
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
 /// Filesystem's metadata.
-#[repr(C)]
-#[derive(Clone)]
-#[derive(FromBytes, IntoBytes, Immutable)]
+#[derive(Debug, Clone)]
 pub struct Superblock {
-    pub signature: [u8; 8],
+    pub version: u32,
+    pub features: Features,
+    pub uuid: Uuid,
+    // At most 'LABEL_MAX_LEN' bytes, see [Self::set_label]
+    label: String,
     pub block_count: u64,
     pub next_node_id: u64,
     pub block_alloc_start: BlockAddr,
     pub root_addr: BlockAddr,
+    pub journal_start: BlockAddr,
+    pub journal_len: u64,
+}
+
+/// Superblock's on-disk layout.
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
+struct SuperblockRepr {
+    signature: [u8; 8],
+    version: U32,
+    compat: U64,
+    ro_compat: U64,
+    incompat: U64,
+    uuid: [u8; 16],
+    // NUL-padded UTF-8
+    label: [u8; LABEL_MAX_LEN],
+    block_count: U64,
+    next_node_id: U64,
+    block_alloc_start: U64,
+    root_addr: U64,
+    journal_start: U64,
+    journal_len: U64,
+    // crc32c of the preceding fields
+    checksum: U32,
+}
+
+impl SuperblockRepr {
+    fn checksum(&self) -> u32 {
+        let bytes = self.as_bytes();
+        crc32c::crc32c(&bytes[..bytes.len() - size_of::<U32>()])
+    }
 }
 
 impl Superblock {
@@ -31,17 +132,152 @@ impl Superblock {
 
         // Superblock lives at address 0
         let block_alloc_start = 1;
-        let root_addr = block_alloc_start + block_alloc_blocks;
+        let journal_start = block_alloc_start + block_alloc_blocks;
+        let journal_len = Journal::len_for(block_count);
+        let root_addr = journal_start + journal_len;
 
         Self {
-            signature: *SIGNATURE,
+            version: VERSION,
+            features: Features::default(),
+            uuid: Uuid::default(),
+            label: String::new(),
             block_count,
             next_node_id: 1,
             block_alloc_start,
             root_addr,
+            journal_start,
+            journal_len,
         }
     }
 
+    /// Interprets a block as a superblock, checking its signature, version and checksum.
+    pub fn decode(block: &Block) -> Result<Self> {
+        let (repr, _) =
+            SuperblockRepr::ref_from_prefix(&block[..]).expect("'Block' must fit a 'Superblock'");
+        if repr.signature != *SIGNATURE
+            || repr.version.get() != VERSION
+            || repr.checksum.get() != repr.checksum()
+        {
+            return Err(Error::InvalidSuperblock);
+        }
+
+        let label_len = repr
+            .label
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(LABEL_MAX_LEN);
+        let label =
+            str::from_utf8(&repr.label[..label_len]).map_err(|_| Error::InvalidSuperblock)?;
+
+        Ok(Self {
+            version: repr.version.get(),
+            features: Features {
+                compat: repr.compat.get(),
+                ro_compat: repr.ro_compat.get(),
+                incompat: repr.incompat.get(),
+            },
+            uuid: Uuid(repr.uuid),
+            label: label.to_owned(),
+            block_count: repr.block_count.get(),
+            next_node_id: repr.next_node_id.get(),
+            block_alloc_start: repr.block_alloc_start.get(),
+            root_addr: repr.root_addr.get(),
+            journal_start: repr.journal_start.get(),
+            journal_len: repr.journal_len.get(),
+        })
+    }
+
+    /// Encodes the superblock into a block, computing its checksum.
+    pub fn encode(&self) -> Block {
+        let mut label = [0; LABEL_MAX_LEN];
+        label[..self.label.len()].copy_from_slice(self.label.as_bytes());
+
+        let mut repr = SuperblockRepr {
+            signature: *SIGNATURE,
+            version: self.version.into(),
+            compat: self.features.compat.into(),
+            ro_compat: self.features.ro_compat.into(),
+            incompat: self.features.incompat.into(),
+            uuid: self.uuid.0,
+            label,
+            block_count: self.block_count.into(),
+            next_node_id: self.next_node_id.into(),
+            block_alloc_start: self.block_alloc_start.into(),
+            root_addr: self.root_addr.into(),
+            journal_start: self.journal_start.into(),
+            journal_len: self.journal_len.into(),
+            checksum: U32::ZERO,
+        };
+        repr.checksum = repr.checksum().into();
+        Block::new(repr.as_bytes())
+    }
+
+    /// Checks that the filesystem's layout is consistent and fits in `capacity` blocks.
+    pub fn validate(&self, capacity: u64) -> Result<()> {
+        let block_alloc_len = self.block_count.div_ceil(8).div_ceil(BLOCK_SIZE);
+        let journal_end = self.journal_start.checked_add(self.journal_len);
+        let is_valid = self.block_count <= capacity
+            && self.block_alloc_start == 1
+            && self.journal_start == self.block_alloc_start + block_alloc_len
+            && self.journal_len >= journal::MIN_LEN
+            && journal_end.is_some_and(|end| end <= self.root_addr)
+            && self.root_addr < self.block_count;
+        if !is_valid {
+            return Err(Error::InvalidSuperblock);
+        }
+        Ok(())
+    }
+
+    /// Returns the filesystem's label.
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Sets the filesystem's label, which must fit in [LABEL_MAX_LEN] bytes and not contain NUL.
+    pub fn set_label(&mut self, label: &str) -> Result<()> {
+        if label.len() > LABEL_MAX_LEN || label.contains('\0') {
+            return Err(Error::InvalidLabel);
+        }
+        self.label = label.to_owned();
+        Ok(())
+    }
+
+    /// Checks that every feature the filesystem was formatted with is known,
+    /// so it can be read and written.
+    pub fn check_features(&self) -> Result<()> {
+        if self.features.incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(Error::UnsupportedFeatures);
+        }
+        // NOTE: There's no read-only mount to fall back to.
+        if self.features.ro_compat & !RO_COMPAT_SUPPORTED != 0 {
+            return Err(Error::ReadOnlyFeatures);
+        }
+        Ok(())
+    }
+
+    /// Checks whether file data blocks are checksummed.
+    pub fn has_data_checksums(&self) -> bool {
+        self.features.ro_compat & RO_COMPAT_DATA_CHECKSUMS != 0
+    }
+
+    /// Returns the addresses of the superblock's backups, halfway through and at the end of
+    /// the filesystem, so they can be found again from its size alone.
+    /// Filesystems too small to hold them after the journal have none.
+    pub fn backup_addrs(&self) -> Vec<BlockAddr> {
+        let data_start = self.journal_start + self.journal_len;
+        let mut addrs: Vec<BlockAddr> = [self.block_count / 2, self.block_count.saturating_sub(1)]
+            .into_iter()
+            .filter(|&addr| addr >= data_start)
+            .collect();
+        addrs.dedup();
+        addrs
+    }
+
+    /// Returns the filesystem's journal.
+    pub fn journal(&self) -> Journal {
+        Journal::new(self.journal_start, self.journal_len)
+    }
+
     pub fn allocate_node(&mut self) -> NodeId {
         // NOTE: This wraps around after u64::MAX, possibly allocating used node ids.
         let id = self.next_node_id;
@@ -52,7 +288,119 @@ impl Superblock {
 
 impl From<&Superblock> for Block {
     fn from(value: &Superblock) -> Self {
-        let bytes = value.as_bytes();
-        Block::new(bytes)
+        value.encode()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn superblock() -> Superblock {
+        let mut superblock = Superblock::new(1024);
+        superblock.features.ro_compat = RO_COMPAT_DATA_CHECKSUMS;
+        superblock.uuid = Uuid::random().unwrap();
+        superblock.set_label("scratch").unwrap();
+        superblock
+    }
+
+    #[test]
+    fn roundtrip() {
+        let superblock = superblock();
+        let decoded = Superblock::decode(&superblock.encode()).unwrap();
+        assert_eq!(decoded.features, superblock.features);
+        assert_eq!(decoded.uuid, superblock.uuid);
+        assert_eq!(decoded.label(), "scratch");
+        assert_eq!(decoded.block_count, superblock.block_count);
+        assert_eq!(decoded.root_addr, superblock.root_addr);
+        assert_eq!(decoded.journal_len, superblock.journal_len);
+    }
+
+    #[test]
+    fn little_endian() {
+        let block = Superblock::new(0x0102).encode();
+        let offset = std::mem::offset_of!(SuperblockRepr, block_count);
+        assert_eq!(block[offset..offset + 8], [0x02, 0x01, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let mut block = superblock().encode();
+        let offset = std::mem::offset_of!(SuperblockRepr, root_addr);
+        block[offset] ^= 1;
+        assert!(matches!(
+            Superblock::decode(&block),
+            Err(Error::InvalidSuperblock)
+        ));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut superblock = superblock();
+        superblock.version = VERSION + 1;
+        assert!(matches!(
+            Superblock::decode(&superblock.encode()),
+            Err(Error::InvalidSuperblock)
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_layouts() {
+        let superblock = superblock();
+        assert!(superblock.validate(1024).is_ok());
+        assert!(superblock.validate(1023).is_err());
+
+        let mut bad_root = superblock.clone();
+        bad_root.root_addr = 1024;
+        assert!(bad_root.validate(1024).is_err());
+        bad_root.root_addr = bad_root.journal_start;
+        assert!(bad_root.validate(1024).is_err());
+
+        let mut bad_journal = superblock.clone();
+        bad_journal.journal_len = u64::MAX;
+        assert!(bad_journal.validate(1024).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_features() {
+        let mut superblock = superblock();
+        superblock.features.compat |= 1 << 63;
+        assert!(superblock.check_features().is_ok());
+
+        superblock.features.ro_compat |= 1 << 63;
+        assert!(matches!(
+            superblock.check_features(),
+            Err(Error::ReadOnlyFeatures)
+        ));
+
+        superblock.features.incompat |= 1 << 63;
+        assert!(matches!(
+            superblock.check_features(),
+            Err(Error::UnsupportedFeatures)
+        ));
+    }
+
+    #[test]
+    fn backup_addrs() {
+        let superblock = Superblock::new(1024);
+        assert_eq!(superblock.backup_addrs(), [512, 1023]);
+
+        // The journal takes up the first half
+        let superblock = Superblock::new(32);
+        assert_eq!(superblock.backup_addrs(), [31]);
+    }
+
+    #[test]
+    fn rejects_invalid_labels() {
+        let mut superblock = superblock();
+        assert!(superblock.set_label(&"a".repeat(LABEL_MAX_LEN)).is_ok());
+        assert!(matches!(
+            superblock.set_label(&"a".repeat(LABEL_MAX_LEN + 1)),
+            Err(Error::InvalidLabel)
+        ));
+        assert!(matches!(
+            superblock.set_label("a\0b"),
+            Err(Error::InvalidLabel)
+        ));
     }
 }
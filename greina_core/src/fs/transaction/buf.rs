@@ -1,67 +1,170 @@
 pub use allocator::BufAllocator;
 
 pub mod allocator {
+    use std::sync::Mutex;
+
     use crate::{
         block::{
-            BlockAddr,
+            Block, BlockAddr,
             allocator::{Allocator, Result, bitmap::BitmapAllocator},
             storage::Storage,
         },
-        fs::{self, Filesystem},
+        fs,
     };
 
     pub struct BufAllocator<'a> {
-        inner: &'a mut BitmapAllocator,
-        allocs: Vec<(BlockAddr, u64)>,
-        deallocs: Vec<(BlockAddr, u64)>,
+        inner: &'a BitmapAllocator,
+        allocs: Mutex<Vec<(BlockAddr, u64)>>,
+        deallocs: Mutex<Vec<(BlockAddr, u64)>>,
+        // Whether `deallocs` were applied to `inner`, which has to be undone if the transaction
+        // doesn't commit after all
+        synced: bool,
     }
 
     impl<'a> BufAllocator<'a> {
-        pub fn new(inner: &'a mut BitmapAllocator) -> Self {
+        pub fn new(inner: &'a BitmapAllocator) -> Self {
             Self {
                 inner,
-                allocs: Vec::new(),
-                deallocs: Vec::new(),
+                allocs: Default::default(),
+                deallocs: Default::default(),
+                synced: false,
             }
         }
 
+        /// Applies the deallocations to the inner allocator and writes its modified chunks.
+        /// The changes are kept track of until [Self::finish], so they're undone if the
+        /// transaction fails to commit.
         pub fn sync(
             &mut self,
             storage: &mut impl Storage,
             start: BlockAddr,
         ) -> fs::error::Result<()> {
-            for &(start, count) in &self.deallocs {
+            self.synced = true;
+            for &(start, count) in self.deallocs.get_mut().unwrap().iter() {
                 self.inner.deallocate(start, count)?;
             }
-            self.allocs.clear();
-            Filesystem::write_block_alloc(storage, self.inner, start)?;
+            self.inner.try_for_each_dirty(|idx, chunk| {
+                storage.write_at(&Block::new(chunk), start + idx)
+            })?;
             Ok(())
         }
+
+        /// Checks whether the block at `addr` is free, counting the deallocations only once
+        /// [Self::sync] applied them.
+        pub fn is_free(&self, addr: BlockAddr) -> bool {
+            !self.inner.is_allocated(addr)
+        }
+
+        /// Returns the blocks the transaction freed which were in use before it.
+        pub fn deallocated(&mut self) -> &[(BlockAddr, u64)] {
+            self.deallocs.get_mut().unwrap()
+        }
+
+        /// Keeps the changes and marks the written bitmap chunks as clean, once the transaction
+        /// is durable.
+        pub fn finish(&mut self) {
+            self.allocs.get_mut().unwrap().clear();
+            self.deallocs.get_mut().unwrap().clear();
+            self.inner.clear_dirty();
+        }
     }
 
     impl<'a> Allocator for BufAllocator<'a> {
-        fn allocate(&mut self, count: u64) -> Result<BlockAddr> {
+        fn allocate(&self, count: u64) -> Result<BlockAddr> {
             let start = self.inner.allocate(count)?;
-            self.allocs.push((start, count));
+            self.allocs.lock().unwrap().push((start, count));
             Ok(start)
         }
 
-        fn deallocate(&mut self, start: BlockAddr, count: u64) -> Result<()> {
-            self.deallocs.push((start, count));
+        fn deallocate(&self, start: BlockAddr, count: u64) -> Result<()> {
+            // Blocks allocated by this transaction aren't reachable from the committed state,
+            // so they can be reused right away
+            let mut allocs = self.allocs.lock().unwrap();
+            if let Some(idx) = allocs.iter().position(|&alloc| alloc == (start, count)) {
+                allocs.swap_remove(idx);
+                return self.inner.deallocate(start, count);
+            }
+            drop(allocs);
+
+            self.deallocs.lock().unwrap().push((start, count));
             Ok(())
         }
 
         fn available(&self) -> u64 {
-            let to_dealloc: u64 = self.deallocs.iter().map(|(_, count)| count).sum();
+            let deallocs = self.deallocs.lock().unwrap();
+            let to_dealloc: u64 = deallocs.iter().map(|(_, count)| count).sum();
             self.inner.available() + to_dealloc
         }
+
+        fn is_fresh(&self, addr: BlockAddr) -> bool {
+            let allocs = self.allocs.lock().unwrap();
+            allocs
+                .iter()
+                .any(|&(start, count)| (start..start + count).contains(&addr))
+        }
     }
 
     impl<'a> Drop for BufAllocator<'a> {
         fn drop(&mut self) {
-            for &(start, count) in &self.allocs {
+            for &(start, count) in self.allocs.get_mut().unwrap().iter() {
                 let _ = self.inner.deallocate(start, count);
             }
+            if self.synced {
+                for &(start, count) in self.deallocs.get_mut().unwrap().iter() {
+                    for addr in start..start + count {
+                        self.inner.set_allocated(addr, true);
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        use crate::block::storage::fake::FakeStorage;
+
+        #[test]
+        fn defers_deallocations() {
+            let inner = BitmapAllocator::new(64);
+            let start = inner.allocate(4).unwrap();
+            let available = inner.available();
+
+            let buf = BufAllocator::new(&inner);
+            buf.deallocate(start, 4).unwrap();
+
+            assert_eq!(inner.available(), available);
+            assert_eq!(buf.available(), available + 4);
+        }
+
+        #[test]
+        fn undoes_unfinished_sync() {
+            let inner = BitmapAllocator::new(64);
+            let start = inner.allocate(4).unwrap();
+            let available = inner.available();
+
+            let mut buf = BufAllocator::new(&inner);
+            buf.deallocate(start, 4).unwrap();
+            buf.allocate(8).unwrap();
+            buf.sync(&mut FakeStorage::default(), 0).unwrap();
+            drop(buf);
+
+            assert!((start..start + 4).all(|addr| inner.is_allocated(addr)));
+            assert_eq!(inner.available(), available);
+        }
+
+        #[test]
+        fn reuses_own_allocations() {
+            let inner = BitmapAllocator::new(64);
+            let available = inner.available();
+
+            let buf = BufAllocator::new(&inner);
+            let start = buf.allocate(4).unwrap();
+            buf.deallocate(start, 4).unwrap();
+
+            assert_eq!(inner.available(), available);
+            assert_eq!(buf.available(), available);
         }
     }
 }
@@ -69,40 +172,63 @@ pub mod allocator {
 pub use storage::BufStorage;
 
 pub mod storage {
-    use std::collections::BTreeMap;
+    use std::{collections::BTreeMap, sync::RwLock};
 
+    use super::BufAllocator;
     use crate::{
         block::{
-            Block, BlockAddr,
+            Allocator, Block, BlockAddr,
             storage::{Result, Storage},
         },
-        fs,
+        fs::{self, journal::Journal},
     };
 
     pub struct BufStorage<'a, S> {
-        inner: &'a mut S,
-        cache: BTreeMap<BlockAddr, Block>,
+        inner: &'a S,
+        cache: RwLock<BTreeMap<BlockAddr, Block>>,
     }
 
     impl<'a, S: Storage> BufStorage<'a, S> {
-        pub fn new(inner: &'a mut S) -> Self {
+        pub fn new(inner: &'a S) -> Self {
             Self {
                 inner,
                 cache: Default::default(),
             }
         }
 
-        pub fn sync(&mut self) -> fs::error::Result<()> {
-            for (addr, block) in &self.cache {
-                self.inner.write_at(block, *addr)?;
-            }
-            Ok(())
+        /// Returns the storage the writes are buffered for.
+        pub fn inner(&self) -> &'a S {
+            self.inner
+        }
+
+        /// Logs the buffered writes in `journal`, making them durable, and returns the blocks
+        /// left to checkpoint.
+        /// Blocks [fresh](Allocator::is_fresh) in `block_alloc` aren't referred to by the
+        /// committed state, so they're written in place rather than logged, while those it
+        /// freed are dropped.
+        pub fn log(
+            &mut self,
+            journal: &Journal,
+            block_alloc: &BufAllocator,
+        ) -> fs::error::Result<BTreeMap<BlockAddr, Block>> {
+            let cache = self.cache.get_mut().unwrap();
+            let (in_place, logged): (Vec<_>, Vec<_>) = cache
+                .iter()
+                .filter(|&(&addr, _)| !block_alloc.is_free(addr))
+                .map(|(&addr, block)| (addr, block))
+                .partition(|&(addr, _)| block_alloc.is_fresh(addr));
+            let logged = logged
+                .into_iter()
+                .map(|(addr, &block)| (addr, block))
+                .collect();
+            journal.log(self.inner, &in_place, &logged)?;
+            Ok(logged)
         }
     }
 
     impl<S: Storage> Storage for BufStorage<'_, S> {
         fn read_at(&self, block: &mut Block, addr: BlockAddr) -> Result<()> {
-            if let Some(cached) = self.cache.get(&addr) {
+            if let Some(cached) = self.cache.read().unwrap().get(&addr) {
                 *block = *cached;
                 Ok(())
             } else {
@@ -110,30 +236,56 @@ pub mod storage {
             }
         }
 
-        fn write_at(&mut self, block: &Block, addr: BlockAddr) -> Result<()> {
-            self.cache.insert(addr, *block);
+        fn write_at(&self, block: &Block, addr: BlockAddr) -> Result<()> {
+            self.cache.write().unwrap().insert(addr, *block);
             Ok(())
         }
 
         fn capacity(&self) -> Result<u64> {
             self.inner.capacity()
         }
+
+        /// Flushes the inner storage, which the buffered writes only reach on [Self::sync].
+        fn flush(&self) -> Result<()> {
+            self.inner.flush()
+        }
+
+        fn read_blocks(&self, blocks: &mut [(BlockAddr, &mut Block)]) -> Result<()> {
+            let cache = self.cache.read().unwrap();
+            let mut unbuffered = Vec::new();
+            for (addr, block) in blocks {
+                match cache.get(addr) {
+                    Some(cached) => **block = *cached,
+                    None => unbuffered.push((*addr, &mut **block)),
+                }
+            }
+            self.inner.read_blocks(&mut unbuffered)
+        }
+
+        fn write_blocks(&self, blocks: &[(BlockAddr, &Block)]) -> Result<()> {
+            let mut cache = self.cache.write().unwrap();
+            cache.extend(blocks.iter().map(|&(addr, block)| (addr, *block)));
+            Ok(())
+        }
     }
 
     #[cfg(test)]
     mod tests {
         use super::*;
 
-        use crate::block::storage::fake::FakeStorage;
+        use crate::{
+            block::{BitmapAllocator, storage::fake::FakeStorage},
+            fs::journal,
+        };
 
         #[test]
         fn reads_from_inner() {
-            let mut inner = FakeStorage::default();
+            let inner = FakeStorage::default();
             let mut write_block = Block::default();
             write_block.fill(0xAB);
             inner.write_at(&write_block, 0).unwrap();
 
-            let cached = BufStorage::new(&mut inner);
+            let cached = BufStorage::new(&inner);
 
             let mut read_block = Block::default();
             cached.read_at(&mut read_block, 0).unwrap();
@@ -142,8 +294,8 @@ pub mod storage {
 
         #[test]
         fn buffers_writes() {
-            let mut inner = FakeStorage::default();
-            let mut cached = BufStorage::new(&mut inner);
+            let inner = FakeStorage::default();
+            let cached = BufStorage::new(&inner);
 
             let mut write_block = Block::default();
             write_block.fill(0xAB);
@@ -158,9 +310,27 @@ pub mod storage {
         }
 
         #[test]
-        fn syncs_writes_to_inner() {
-            let mut inner = FakeStorage::default();
-            let mut cached = BufStorage::new(&mut inner);
+        fn reads_blocks_from_buffer_and_inner() {
+            let inner = FakeStorage::default();
+            let mut inner_block = Block::default();
+            inner_block.fill(0xAB);
+            inner.write_at(&inner_block, 0).unwrap();
+
+            let cached = BufStorage::new(&inner);
+            let mut buffered_block = Block::default();
+            buffered_block.fill(0xCD);
+            cached.write_at(&buffered_block, 1).unwrap();
+
+            let mut blocks = [Block::default(); 2];
+            let mut pairs: Vec<_> = (0..).zip(&mut blocks).collect();
+            cached.read_blocks(&mut pairs).unwrap();
+            assert_eq!(blocks, [inner_block, buffered_block]);
+        }
+
+        #[test]
+        fn logs_writes_to_inner() {
+            let inner = FakeStorage::default();
+            let mut cached = BufStorage::new(&inner);
 
             let mut write_block_1 = Block::default();
             let mut write_block_2 = Block::default();
@@ -171,7 +341,13 @@ pub mod storage {
             cached.write_at(&write_block_1, 0).unwrap();
             cached.write_at(&write_block_2, 1).unwrap();
 
-            cached.sync().unwrap();
+            let block_alloc = BitmapAllocator::new(2 + journal::MIN_LEN);
+            block_alloc.allocate(2).unwrap();
+            let journal = Journal::new(2, journal::MIN_LEN);
+            let logged = cached
+                .log(&journal, &BufAllocator::new(&block_alloc))
+                .unwrap();
+            Journal::checkpoint(cached.inner, &logged).unwrap();
 
             let mut inner_read_block_1 = Block::default();
             let mut inner_read_block_2 = Block::default();
@@ -182,5 +358,28 @@ pub mod storage {
             assert_eq!(inner_read_block_1, write_block_1);
             assert_eq!(inner_read_block_2, write_block_2);
         }
+
+        #[test]
+        fn drops_freed_blocks() {
+            let inner = FakeStorage::default();
+            let mut cached = BufStorage::new(&inner);
+            let block_alloc = BitmapAllocator::new(64);
+            let journal = Journal::new(
+                block_alloc.allocate(journal::MIN_LEN).unwrap(),
+                journal::MIN_LEN,
+            );
+            let buf_alloc = BufAllocator::new(&block_alloc);
+
+            let kept = buf_alloc.allocate(1).unwrap();
+            let freed = buf_alloc.allocate(1).unwrap();
+            cached.write_at(&Block::default(), kept).unwrap();
+            cached.write_at(&Block::default(), freed).unwrap();
+            buf_alloc.deallocate(freed, 1).unwrap();
+            cached.log(&journal, &buf_alloc).unwrap();
+
+            let mut block = Block::default();
+            inner.read_at(&mut block, kept).unwrap();
+            assert!(inner.read_at(&mut block, freed).is_err());
+        }
     }
 }
@@ -1,19 +1,32 @@
+#[cfg(test)]
+mod tests;
+
 mod buf;
 use buf::*;
 
+mod read;
+pub use read::ReadTransaction;
+
+use std::collections::BTreeMap;
+
 use crate::{
-    block::storage::Storage,
+    block::{Block, BlockAddr, storage::Storage},
     fs::{
         Filesystem,
         error::Result,
+        journal::Journal,
         node::{
-            FileType, Node, NodeId,
-            dir::{Dir, DirEntry, DirEntryName},
+            FileType, Node, NodeId, Perms,
+            dir::{Dir, DirEntry, DirEntryName, RenameMode},
             file::File,
+            orphan::Orphan,
             symlink::Symlink,
+            time::Timestamp,
+            xattr::{SetMode, Xattr},
         },
         superblock::Superblock,
     },
+    tree::{Key, Tree},
 };
 
 /// Filesystem operation that buffers changes in memory before commiting them to persistent storage.
@@ -22,6 +35,9 @@ pub struct Transaction<'a, S: Storage> {
     fs_superblock: &'a mut Superblock,
     superblock: Superblock,
     block_alloc: BufAllocator<'a>,
+    journal: Journal,
+    discard: bool,
+    pending: &'a mut Option<BTreeMap<BlockAddr, Block>>,
 }
 
 impl<'a, S: Storage> Transaction<'a, S> {
@@ -29,41 +45,76 @@ impl<'a, S: Storage> Transaction<'a, S> {
     pub(super) fn new(fs: &'a mut Filesystem<S>) -> Self {
         let superblock = fs.superblock.clone();
         Self {
-            storage: BufStorage::new(&mut fs.storage),
+            storage: BufStorage::new(&fs.storage),
             fs_superblock: &mut fs.superblock,
             superblock,
-            block_alloc: BufAllocator::new(&mut fs.block_alloc),
+            block_alloc: BufAllocator::new(&fs.block_alloc),
+            journal: fs.journal,
+            discard: fs.discard,
+            pending: fs.pending.get_mut().unwrap(),
         }
     }
 
     /// Commits the transaction to storage, consuming itself.
+    /// All changes to committed blocks are written through the journal, so either all or none
+    /// of them persist. Freshly allocated blocks are written in place, as nothing refers to
+    /// them before the commit.
     pub(super) fn commit(mut self) -> Result<()> {
-        self.sync_superblock()?;
+        Filesystem::write_superblock(&mut self.storage, &self.superblock)?;
         self.block_alloc
             .sync(&mut self.storage, self.superblock.block_alloc_start)?;
-        self.storage.sync()?;
-        Ok(())
-    }
+        let logged = self.storage.log(&self.journal, &self.block_alloc)?;
 
-    /// Queues a synchronization of allocation maps.
-    fn sync_superblock(&mut self) -> Result<()> {
-        Filesystem::write_superblock(&mut self.storage, &self.superblock)?;
+        // The transaction is durable, so it's kept in memory whatever happens next
+        let freed = if self.discard {
+            self.block_alloc.deallocated().to_vec()
+        } else {
+            Vec::new()
+        };
+        self.block_alloc.finish();
         *self.fs_superblock = self.superblock.clone();
+
+        // The journal is left to replay until checkpointing is retried, see [Filesystem::tx]
+        let storage = self.storage.inner();
+        if let Err(err) = Filesystem::checkpoint(storage, &self.journal, &self.superblock, &logged)
+        {
+            log::warn!("journal is left to replay: {err:?}");
+            *self.pending = Some(logged);
+            return Ok(());
+        }
+        self.discard_freed(&freed);
         Ok(())
     }
 
-    pub fn create_node(&mut self, filetype: FileType, links: u32) -> Result<NodeId> {
+    /// Discards the blocks freed by the committed transaction, which nothing refers to anymore.
+    /// Failures are only logged, as the transaction is committed already.
+    fn discard_freed(&self, freed: &[(BlockAddr, u64)]) {
+        let storage = self.storage.inner();
+        for &(start, count) in freed {
+            if let Err(err) = storage.discard(start, count) {
+                log::warn!("failed to discard {count} blocks at {start}: errno {err}");
+            }
+        }
+    }
+
+    pub fn create_node(&mut self, filetype: FileType, links: u32, perms: Perms) -> Result<NodeId> {
         Node::create(
             &mut self.storage,
             &mut self.block_alloc,
             &mut self.superblock,
             filetype,
             links,
+            perms,
         )
     }
 
+    /// Returns a read-only view of the transaction's current state.
+    pub fn reader(&self) -> ReadTransaction<'_, BufStorage<'a, S>> {
+        ReadTransaction::new(&self.storage, &self.superblock)
+    }
+
     pub fn read_node(&self, id: NodeId) -> Result<Node> {
-        Node::read(&self.storage, &self.superblock, id)
+        self.reader().read_node(id)
     }
 
     pub fn write_node(&mut self, node: &Node, id: NodeId) -> Result<()> {
@@ -75,6 +126,40 @@ impl<'a, S: Storage> Transaction<'a, S> {
         )
     }
 
+    pub fn set_node_times(
+        &mut self,
+        id: NodeId,
+        atime: Option<Timestamp>,
+        mtime: Option<Timestamp>,
+    ) -> Result<()> {
+        Node::set_times(
+            &mut self.storage,
+            &mut self.block_alloc,
+            &mut self.superblock,
+            id,
+            atime,
+            mtime,
+        )
+    }
+
+    pub fn set_node_perms(
+        &mut self,
+        id: NodeId,
+        mode: Option<u16>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> Result<()> {
+        Node::set_perms(
+            &mut self.storage,
+            &mut self.block_alloc,
+            &mut self.superblock,
+            id,
+            mode,
+            uid,
+            gid,
+        )
+    }
+
     pub fn remove_node(&mut self, id: NodeId) -> Result<()> {
         Node::remove(
             &mut self.storage,
@@ -85,11 +170,10 @@ impl<'a, S: Storage> Transaction<'a, S> {
     }
 
     pub fn find_entry(&self, parent: NodeId, name: &str) -> Result<DirEntry> {
-        let name = DirEntryName::try_from(name)?;
-        DirEntry::read(&self.storage, &self.superblock, parent, name.hash())
+        self.reader().find_entry(parent, name)
     }
 
-    pub fn create_dir(&mut self, parent: NodeId, name: &str) -> Result<NodeId> {
+    pub fn create_dir(&mut self, parent: NodeId, name: &str, perms: Perms) -> Result<NodeId> {
         let name = DirEntryName::try_from(name)?;
         Dir::create(
             &mut self.storage,
@@ -97,6 +181,7 @@ impl<'a, S: Storage> Transaction<'a, S> {
             &mut self.superblock,
             parent,
             name,
+            perms,
         )
     }
 
@@ -111,16 +196,17 @@ impl<'a, S: Storage> Transaction<'a, S> {
     }
 
     pub fn read_dir(&self, id: NodeId) -> Result<Vec<DirEntry>> {
-        Dir::list(&self.storage, &self.superblock, id)
+        self.reader().read_dir(id)
     }
 
-    pub fn create_root_dir(&mut self) -> Result<NodeId> {
+    pub fn create_root_dir(&mut self, perms: Perms) -> Result<NodeId> {
         let id = Node::create(
             &mut self.storage,
             &mut self.block_alloc,
             &mut self.superblock,
             FileType::Dir,
             1,
+            perms,
         )?;
 
         assert_eq!(id, NodeId::ROOT, "root must have id 1, got {:?}", id);
@@ -153,6 +239,7 @@ impl<'a, S: Storage> Transaction<'a, S> {
         parent: NodeId,
         name: &str,
         filetype: FileType,
+        perms: Perms,
     ) -> Result<NodeId> {
         File::create(
             &mut self.storage,
@@ -161,11 +248,12 @@ impl<'a, S: Storage> Transaction<'a, S> {
             parent,
             filetype,
             name,
+            perms,
         )
     }
 
     pub fn read_file_at(&self, id: NodeId, offset: u64, buf: &mut [u8]) -> Result<u64> {
-        File::read_at(&self.storage, &self.superblock, id, offset, buf)
+        self.reader().read_file_at(id, offset, buf)
     }
 
     pub fn write_file_at(&mut self, id: NodeId, offset: u64, buf: &[u8]) -> Result<u64> {
@@ -189,7 +277,13 @@ impl<'a, S: Storage> Transaction<'a, S> {
         )
     }
 
-    pub fn create_symlink(&mut self, parent: NodeId, name: &str, target: &str) -> Result<NodeId> {
+    pub fn create_symlink(
+        &mut self,
+        parent: NodeId,
+        name: &str,
+        target: &str,
+        perms: Perms,
+    ) -> Result<NodeId> {
         Symlink::create(
             &mut self.storage,
             &mut self.block_alloc,
@@ -197,11 +291,12 @@ impl<'a, S: Storage> Transaction<'a, S> {
             parent,
             name,
             target,
+            perms,
         )
     }
 
     pub fn read_symlink(&self, id: NodeId) -> Result<Box<[u8]>> {
-        Symlink::read(&self.storage, &self.superblock, id)
+        self.reader().read_symlink(id)
     }
 
     pub fn link_file(&mut self, parent: NodeId, id: NodeId, name: &str) -> Result<()> {
@@ -216,7 +311,8 @@ impl<'a, S: Storage> Transaction<'a, S> {
         )
     }
 
-    pub fn unlink_file(&mut self, parent: NodeId, name: &str) -> Result<()> {
+    /// Returns the node if it was left without links, see [Orphan].
+    pub fn unlink_file(&mut self, parent: NodeId, name: &str) -> Result<Option<NodeId>> {
         let name = DirEntryName::try_from(name)?;
         DirEntry::unlink(
             &mut self.storage,
@@ -227,13 +323,15 @@ impl<'a, S: Storage> Transaction<'a, S> {
         )
     }
 
+    /// Returns the replaced node if it was left without links, see [Orphan].
     pub fn rename_entry(
         &mut self,
         old_parent: NodeId,
         old_name: &str,
         new_parent: NodeId,
         new_name: &str,
-    ) -> Result<()> {
+        mode: RenameMode,
+    ) -> Result<Option<NodeId>> {
         let old_name = DirEntryName::try_from(old_name)?;
         let new_name = DirEntryName::try_from(new_name)?;
         DirEntry::rename(
@@ -244,6 +342,115 @@ impl<'a, S: Storage> Transaction<'a, S> {
             &old_name,
             new_parent,
             &new_name,
+            mode,
+        )
+    }
+
+    /// Removes an orphan along with its node, once nothing references it.
+    pub fn remove_orphan(&mut self, id: NodeId) -> Result<()> {
+        Orphan::remove(
+            &mut self.storage,
+            &mut self.block_alloc,
+            &mut self.superblock,
+            id,
+        )
+    }
+
+    pub fn is_orphan(&self, id: NodeId) -> Result<bool> {
+        self.reader().is_orphan(id)
+    }
+
+    pub fn list_orphans(&self) -> Result<Vec<NodeId>> {
+        self.reader().list_orphans()
+    }
+
+    /// Creates an entry linking to an existing node, without changing the node's links.
+    pub(in crate::fs) fn create_entry(
+        &mut self,
+        parent: NodeId,
+        filetype: FileType,
+        id: NodeId,
+        name: DirEntryName,
+    ) -> Result<()> {
+        DirEntry::create(
+            &mut self.storage,
+            &mut self.block_alloc,
+            &mut self.superblock,
+            parent,
+            filetype,
+            id,
+            name,
+        )
+    }
+
+    /// Overwrites the entry with the same name, or creates it if there's none.
+    pub(in crate::fs) fn upsert_entry(&mut self, parent: NodeId, entry: &DirEntry) -> Result<()> {
+        entry.upsert(
+            &mut self.storage,
+            &mut self.block_alloc,
+            &mut self.superblock,
+            parent,
+        )
+    }
+
+    /// Inserts a single item into the tree, regardless of what it describes.
+    #[cfg(test)]
+    pub(in crate::fs) fn insert_item(&mut self, key: Key, data: &[u8]) -> Result<()> {
+        Tree::try_insert(
+            &mut self.storage,
+            &mut self.block_alloc,
+            &mut self.superblock.root_addr,
+            key,
+            data,
+        )?;
+        Ok(())
+    }
+
+    /// Removes a single item from the tree, regardless of what it describes.
+    /// Nothing the item refers to is deallocated.
+    pub(in crate::fs) fn remove_item(&mut self, key: Key) -> Result<()> {
+        Tree::remove(
+            &mut self.storage,
+            &mut self.block_alloc,
+            &mut self.superblock.root_addr,
+            key,
+        )?;
+        Ok(())
+    }
+
+    pub fn get_xattr(&self, id: NodeId, name: &[u8]) -> Result<Box<[u8]>> {
+        self.reader().get_xattr(id, name)
+    }
+
+    pub fn list_xattrs(&self, id: NodeId) -> Result<Vec<Box<[u8]>>> {
+        self.reader().list_xattrs(id)
+    }
+
+    pub fn set_xattr(
+        &mut self,
+        id: NodeId,
+        name: &[u8],
+        value: &[u8],
+        mode: SetMode,
+    ) -> Result<()> {
+        Xattr::set(
+            &mut self.storage,
+            &mut self.block_alloc,
+            &mut self.superblock,
+            id,
+            name,
+            value,
+            mode,
+        )
+    }
+
+    pub fn remove_xattr(&mut self, id: NodeId, name: &[u8]) -> Result<()> {
+        Xattr::remove(
+            &mut self.storage,
+            &mut self.block_alloc,
+            &mut self.superblock,
+            id,
+            name,
         )
     }
 }
@@ -0,0 +1,68 @@
+use crate::{
+    block::storage::Storage,
+    fs::{
+        error::Result,
+        node::{
+            Node, NodeId,
+            dir::{Dir, DirEntry, DirEntryName},
+            file::File,
+            orphan::Orphan,
+            symlink::Symlink,
+            xattr::Xattr,
+        },
+        superblock::Superblock,
+    },
+};
+
+/// Filesystem operation that only reads from storage and is never committed.
+pub struct ReadTransaction<'a, S: Storage> {
+    storage: &'a S,
+    superblock: &'a Superblock,
+}
+
+impl<'a, S: Storage> ReadTransaction<'a, S> {
+    /// Constructs a `ReadTransaction` over a storage device and its superblock.
+    pub(crate) fn new(storage: &'a S, superblock: &'a Superblock) -> Self {
+        Self {
+            storage,
+            superblock,
+        }
+    }
+
+    pub fn read_node(&self, id: NodeId) -> Result<Node> {
+        Node::read(self.storage, self.superblock, id)
+    }
+
+    pub fn find_entry(&self, parent: NodeId, name: &str) -> Result<DirEntry> {
+        let name = DirEntryName::try_from(name)?;
+        DirEntry::read(self.storage, self.superblock, parent, &name)
+    }
+
+    pub fn read_dir(&self, id: NodeId) -> Result<Vec<DirEntry>> {
+        Dir::list(self.storage, self.superblock, id)
+    }
+
+    pub fn read_file_at(&self, id: NodeId, offset: u64, buf: &mut [u8]) -> Result<u64> {
+        File::read_at(self.storage, self.superblock, id, offset, buf)
+    }
+
+    pub fn read_symlink(&self, id: NodeId) -> Result<Box<[u8]>> {
+        Symlink::read(self.storage, self.superblock, id)
+    }
+
+    pub fn get_xattr(&self, id: NodeId, name: &[u8]) -> Result<Box<[u8]>> {
+        Xattr::get(self.storage, self.superblock, id, name)
+    }
+
+    pub fn list_xattrs(&self, id: NodeId) -> Result<Vec<Box<[u8]>>> {
+        Xattr::list(self.storage, self.superblock, id)
+    }
+
+    pub fn is_orphan(&self, id: NodeId) -> Result<bool> {
+        Orphan::contains(self.storage, self.superblock, id)
+    }
+
+    pub fn list_orphans(&self) -> Result<Vec<NodeId>> {
+        Orphan::list(self.storage, self.superblock)
+    }
+}
@@ -0,0 +1,633 @@
+use std::{collections::BTreeMap, fmt::Debug};
+
+use proptest::prelude::*;
+use proptest_state_machine::{ReferenceStateMachine, StateMachineTest, prop_state_machine};
+
+use crate::{
+    block::{Allocator, BLOCK_SIZE, storage::mem::MemStorage},
+    fs::{check, node::dir::DirEntryName},
+};
+
+use super::*;
+
+/// Few names, so that operations often hit existing entries.
+const NAMES: [&str; 6] = ["a", "b", "c", "foo", "bar", "baz"];
+
+/// The model's root directory.
+const ROOT: u64 = 0;
+
+fn arb_name() -> impl Strategy<Value = String> {
+    proptest::sample::select(&NAMES[..]).prop_map(String::from)
+}
+
+fn arb_mode() -> impl Strategy<Value = RenameMode> {
+    prop_oneof![
+        Just(RenameMode::Replace),
+        Just(RenameMode::NoReplace),
+        Just(RenameMode::Exchange),
+    ]
+}
+
+/// File contents, only showing their length when debugging.
+#[derive(Clone)]
+struct Data(Vec<u8>);
+
+impl Debug for Data {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[u8; {}]", self.0.len())
+    }
+}
+
+fn arb_data() -> impl Strategy<Value = Data> {
+    prop::collection::vec(any::<u8>(), 0..2 * BLOCK_SIZE as usize).prop_map(Data)
+}
+
+#[derive(Clone, Debug)]
+enum ModelNode {
+    File(Vec<u8>),
+    // Maps names to node ids, without `.` and `..`
+    Dir(BTreeMap<String, u64>),
+    Symlink(String),
+}
+
+impl ModelNode {
+    fn filetype(&self) -> FileType {
+        match self {
+            Self::File(_) => FileType::File,
+            Self::Dir(_) => FileType::Dir,
+            Self::Symlink(_) => FileType::Symlink,
+        }
+    }
+}
+
+/// What the filesystem should look like, with nodes identified by the order they're created in.
+#[derive(Clone, Debug)]
+struct Model {
+    nodes: BTreeMap<u64, ModelNode>,
+    next_id: u64,
+}
+
+impl Default for Model {
+    fn default() -> Self {
+        Self {
+            nodes: BTreeMap::from([(ROOT, ModelNode::Dir(BTreeMap::new()))]),
+            next_id: ROOT + 1,
+        }
+    }
+}
+
+impl Model {
+    fn ids(&self, f: impl Fn(&ModelNode) -> bool) -> Vec<u64> {
+        self.nodes
+            .iter()
+            .filter(|(_, node)| f(node))
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    fn dirs(&self) -> Vec<u64> {
+        self.ids(|node| matches!(node, ModelNode::Dir(_)))
+    }
+
+    fn files(&self) -> Vec<u64> {
+        self.ids(|node| matches!(node, ModelNode::File(_)))
+    }
+
+    fn non_dirs(&self) -> Vec<u64> {
+        self.ids(|node| !matches!(node, ModelNode::Dir(_)))
+    }
+
+    /// Returns the entries of every directory, as their parent and name.
+    fn entries(&self) -> Vec<(u64, String)> {
+        let mut entries = Vec::new();
+        for (&parent, node) in &self.nodes {
+            if let ModelNode::Dir(children) = node {
+                entries.extend(children.keys().map(|name| (parent, name.clone())));
+            }
+        }
+        entries
+    }
+
+    fn children(&self, dir: u64) -> Option<&BTreeMap<String, u64>> {
+        match self.nodes.get(&dir) {
+            Some(ModelNode::Dir(children)) => Some(children),
+            _ => None,
+        }
+    }
+
+    fn children_mut(&mut self, dir: u64) -> &mut BTreeMap<String, u64> {
+        match self.nodes.get_mut(&dir) {
+            Some(ModelNode::Dir(children)) => children,
+            _ => panic!("'dir' must be a directory"),
+        }
+    }
+
+    fn entry(&self, parent: u64, name: &str) -> Option<u64> {
+        self.children(parent)?.get(name).copied()
+    }
+
+    fn is_dir(&self, id: u64) -> bool {
+        self.children(id).is_some()
+    }
+
+    /// Returns how many entries link to the node.
+    fn links(&self, id: u64) -> usize {
+        self.nodes
+            .values()
+            .filter_map(|node| match node {
+                ModelNode::Dir(children) => Some(children.values().filter(|&&c| c == id).count()),
+                _ => None,
+            })
+            .sum()
+    }
+
+    /// Checks whether `dir` is `id` or one of its ancestors.
+    fn is_ancestor(&self, dir: u64, mut id: u64) -> bool {
+        loop {
+            if id == dir {
+                return true;
+            }
+            let parent = self.nodes.iter().find_map(|(&parent, node)| match node {
+                ModelNode::Dir(children) if children.values().any(|&c| c == id) => Some(parent),
+                _ => None,
+            });
+            match parent {
+                Some(parent) => id = parent,
+                None => return false,
+            }
+        }
+    }
+
+    /// Checks whether the entry can be moved into `new_parent`.
+    fn can_move(&self, id: u64, new_parent: u64) -> bool {
+        !self.is_dir(id) || !self.is_ancestor(id, new_parent)
+    }
+
+    fn create(&mut self, parent: u64, name: &str, node: ModelNode) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.nodes.insert(id, node);
+        self.children_mut(parent).insert(name.to_owned(), id);
+    }
+
+    /// Removes an entry, along with its node once nothing links to it.
+    fn remove_entry(&mut self, parent: u64, name: &str) {
+        let id = self.children_mut(parent).remove(name).unwrap();
+        if self.links(id) == 0 {
+            self.nodes.remove(&id);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Transition {
+    CreateFile {
+        parent: u64,
+        name: String,
+    },
+    CreateDir {
+        parent: u64,
+        name: String,
+    },
+    CreateSymlink {
+        parent: u64,
+        name: String,
+        target: String,
+    },
+    Write {
+        id: u64,
+        offset: u64,
+        data: Data,
+    },
+    Truncate {
+        id: u64,
+        size: u64,
+    },
+    RemoveDir {
+        parent: u64,
+        name: String,
+    },
+    Link {
+        parent: u64,
+        id: u64,
+        name: String,
+    },
+    Unlink {
+        parent: u64,
+        name: String,
+    },
+    Rename {
+        parent: u64,
+        name: String,
+        new_parent: u64,
+        new_name: String,
+        mode: RenameMode,
+    },
+}
+
+struct FsStateReference;
+
+impl ReferenceStateMachine for FsStateReference {
+    type State = Model;
+
+    type Transition = Transition;
+
+    fn init_state() -> BoxedStrategy<Self::State> {
+        Just(Self::State::default()).boxed()
+    }
+
+    fn transitions(state: &Self::State) -> BoxedStrategy<Self::Transition> {
+        let dirs = proptest::sample::select(state.dirs());
+        let mut strats = vec![
+            (dirs.clone(), arb_name())
+                .prop_map(|(parent, name)| Transition::CreateFile { parent, name })
+                .boxed(),
+            (dirs.clone(), arb_name())
+                .prop_map(|(parent, name)| Transition::CreateDir { parent, name })
+                .boxed(),
+            (dirs.clone(), arb_name(), "[a-z/]{1,32}")
+                .prop_map(|(parent, name, target)| Transition::CreateSymlink {
+                    parent,
+                    name,
+                    target,
+                })
+                .boxed(),
+        ];
+
+        let files = state.files();
+        if !files.is_empty() {
+            let files = proptest::sample::select(files);
+            strats.push(
+                (files.clone(), 0..3 * BLOCK_SIZE, arb_data())
+                    .prop_map(|(id, offset, data)| Transition::Write { id, offset, data })
+                    .boxed(),
+            );
+            strats.push(
+                (files, 0..4 * BLOCK_SIZE)
+                    .prop_map(|(id, size)| Transition::Truncate { id, size })
+                    .boxed(),
+            );
+        }
+
+        let non_dirs = state.non_dirs();
+        if !non_dirs.is_empty() {
+            strats.push(
+                (dirs.clone(), proptest::sample::select(non_dirs), arb_name())
+                    .prop_map(|(parent, id, name)| Transition::Link { parent, id, name })
+                    .boxed(),
+            );
+        }
+
+        let entries = state.entries();
+        if !entries.is_empty() {
+            let entries = proptest::sample::select(entries);
+            strats.push(
+                entries
+                    .clone()
+                    .prop_map(|(parent, name)| Transition::RemoveDir { parent, name })
+                    .boxed(),
+            );
+            strats.push(
+                entries
+                    .clone()
+                    .prop_map(|(parent, name)| Transition::Unlink { parent, name })
+                    .boxed(),
+            );
+            strats.push(
+                (entries, dirs, arb_name(), arb_mode())
+                    .prop_map(
+                        |((parent, name), new_parent, new_name, mode)| Transition::Rename {
+                            parent,
+                            name,
+                            new_parent,
+                            new_name,
+                            mode,
+                        },
+                    )
+                    .boxed(),
+            );
+        }
+
+        proptest::strategy::Union::new(strats).boxed()
+    }
+
+    fn apply(mut state: Self::State, transition: &Self::Transition) -> Self::State {
+        match transition {
+            Transition::CreateFile { parent, name } => {
+                state.create(*parent, name, ModelNode::File(Vec::new()));
+            }
+            Transition::CreateDir { parent, name } => {
+                state.create(*parent, name, ModelNode::Dir(BTreeMap::new()));
+            }
+            Transition::CreateSymlink {
+                parent,
+                name,
+                target,
+            } => {
+                state.create(*parent, name, ModelNode::Symlink(target.clone()));
+            }
+            Transition::Write { id, offset, data } => {
+                let Some(ModelNode::File(contents)) = state.nodes.get_mut(id) else {
+                    unreachable!();
+                };
+                if !data.0.is_empty() {
+                    let start = *offset as usize;
+                    let end = start + data.0.len();
+                    if contents.len() < end {
+                        contents.resize(end, 0);
+                    }
+                    contents[start..end].copy_from_slice(&data.0);
+                }
+            }
+            Transition::Truncate { id, size } => {
+                let Some(ModelNode::File(contents)) = state.nodes.get_mut(id) else {
+                    unreachable!();
+                };
+                contents.resize(*size as usize, 0);
+            }
+            Transition::RemoveDir { parent, name } | Transition::Unlink { parent, name } => {
+                state.remove_entry(*parent, name);
+            }
+            Transition::Link { parent, id, name } => {
+                state.children_mut(*parent).insert(name.clone(), *id);
+            }
+            Transition::Rename {
+                parent,
+                name,
+                new_parent,
+                new_name,
+                mode,
+            } => {
+                let id = state.entry(*parent, name).unwrap();
+                let target = state.entry(*new_parent, new_name);
+                if (parent, name) == (new_parent, new_name) || target == Some(id) {
+                    return state;
+                }
+
+                match mode {
+                    RenameMode::Exchange => {
+                        let target = target.unwrap();
+                        state.children_mut(*parent).insert(name.clone(), target);
+                        state.children_mut(*new_parent).insert(new_name.clone(), id);
+                    }
+                    RenameMode::Replace | RenameMode::NoReplace => {
+                        state.children_mut(*parent).remove(name);
+                        if target.is_some() {
+                            state.remove_entry(*new_parent, new_name);
+                        }
+                        state.children_mut(*new_parent).insert(new_name.clone(), id);
+                    }
+                }
+            }
+        }
+        state
+    }
+
+    fn preconditions(state: &Self::State, transition: &Self::Transition) -> bool {
+        match transition {
+            Transition::CreateFile { parent, name }
+            | Transition::CreateDir { parent, name }
+            | Transition::CreateSymlink { parent, name, .. } => {
+                state.is_dir(*parent) && state.entry(*parent, name).is_none()
+            }
+            Transition::Write { id, .. } | Transition::Truncate { id, .. } => {
+                matches!(state.nodes.get(id), Some(ModelNode::File(_)))
+            }
+            Transition::RemoveDir { parent, name } => state
+                .entry(*parent, name)
+                .and_then(|id| state.children(id))
+                .is_some_and(|children| children.is_empty()),
+            Transition::Link { parent, id, name } => {
+                state.is_dir(*parent)
+                    && state.nodes.contains_key(id)
+                    && !state.is_dir(*id)
+                    && state.entry(*parent, name).is_none()
+            }
+            Transition::Unlink { parent, name } => state
+                .entry(*parent, name)
+                .is_some_and(|id| !state.is_dir(id)),
+            Transition::Rename {
+                parent,
+                name,
+                new_parent,
+                new_name,
+                mode,
+            } => {
+                let (Some(id), true) = (state.entry(*parent, name), state.is_dir(*new_parent))
+                else {
+                    return false;
+                };
+                let target = state.entry(*new_parent, new_name);
+                if (parent, name) == (new_parent, new_name) {
+                    return true;
+                }
+
+                match (mode, target) {
+                    (RenameMode::NoReplace, Some(_)) | (RenameMode::Exchange, None) => false,
+                    (RenameMode::Exchange, Some(target)) => {
+                        parent == new_parent
+                            || (state.can_move(id, *new_parent) && state.can_move(target, *parent))
+                    }
+                    (RenameMode::Replace, Some(target)) if target == id => true,
+                    (RenameMode::Replace, Some(target)) => {
+                        let compatible = match (state.children(id), state.children(target)) {
+                            (Some(_), Some(children)) => children.is_empty(),
+                            (None, None) => true,
+                            _ => false,
+                        };
+                        compatible && state.can_move(id, *new_parent)
+                    }
+                    (_, None) => state.can_move(id, *new_parent),
+                }
+            }
+        }
+    }
+}
+
+struct FsState {
+    fs: Filesystem<MemStorage>,
+    // The filesystem's ids of the model's nodes
+    ids: BTreeMap<u64, NodeId>,
+    // The model before the last transition, to tell what a transition should return
+    model: Model,
+    // The blocks available on the empty filesystem
+    available: u64,
+}
+
+impl FsState {
+    fn tx<T>(&mut self, f: impl FnOnce(&mut Transaction<MemStorage>) -> Result<T>) -> T {
+        self.fs.tx(f).expect("transaction failed")
+    }
+
+    /// Removes the node the last link to which was just removed, as closing it would.
+    fn remove_orphan(&mut self, orphan: Option<NodeId>, expected: Option<u64>) {
+        assert_eq!(orphan, expected.map(|id| self.ids[&id]));
+        if let Some(orphan) = orphan {
+            self.tx(|tx| tx.remove_orphan(orphan));
+        }
+    }
+}
+
+impl Default for FsState {
+    fn default() -> Self {
+        let fs = Filesystem::format(MemStorage::new(1024)).unwrap();
+        let available = fs.block_alloc().available();
+        Self {
+            fs,
+            ids: BTreeMap::from([(ROOT, NodeId::ROOT)]),
+            model: Model::default(),
+            available,
+        }
+    }
+}
+
+impl StateMachineTest for FsState {
+    type SystemUnderTest = FsState;
+
+    type Reference = FsStateReference;
+
+    fn init_test(
+        _ref_state: &<Self::Reference as ReferenceStateMachine>::State,
+    ) -> Self::SystemUnderTest {
+        Self::SystemUnderTest::default()
+    }
+
+    fn apply(
+        mut state: Self::SystemUnderTest,
+        ref_state: &<Self::Reference as ReferenceStateMachine>::State,
+        transition: <Self::Reference as ReferenceStateMachine>::Transition,
+    ) -> Self::SystemUnderTest {
+        let model = std::mem::replace(&mut state.model, ref_state.clone());
+        // Nodes removed by the transition
+        let removed = |id: Option<u64>| id.filter(|id| !ref_state.nodes.contains_key(id));
+        let perms = Perms::new(0o644, 0, 0);
+
+        match transition {
+            Transition::CreateFile { parent, name } => {
+                let parent = state.ids[&parent];
+                let id = state.tx(|tx| tx.create_file(parent, &name, FileType::File, perms));
+                state.ids.insert(model.next_id, id);
+            }
+            Transition::CreateDir { parent, name } => {
+                let parent = state.ids[&parent];
+                let id = state.tx(|tx| tx.create_dir(parent, &name, perms));
+                state.ids.insert(model.next_id, id);
+            }
+            Transition::CreateSymlink {
+                parent,
+                name,
+                target,
+            } => {
+                let parent = state.ids[&parent];
+                let id = state.tx(|tx| tx.create_symlink(parent, &name, &target, perms));
+                state.ids.insert(model.next_id, id);
+            }
+            Transition::Write { id, offset, data } => {
+                let id = state.ids[&id];
+                let written = state.tx(|tx| tx.write_file_at(id, offset, &data.0));
+                assert_eq!(written, data.0.len() as u64);
+            }
+            Transition::Truncate { id, size } => {
+                let id = state.ids[&id];
+                state.tx(|tx| tx.truncate_file(id, size));
+            }
+            Transition::RemoveDir { parent, name } => {
+                let expected = state.ids[&model.entry(parent, &name).unwrap()];
+                let parent = state.ids[&parent];
+                let id = state.tx(|tx| tx.remove_dir(parent, &name));
+                assert_eq!(id, expected);
+            }
+            Transition::Link { parent, id, name } => {
+                let (parent, id) = (state.ids[&parent], state.ids[&id]);
+                state.tx(|tx| tx.link_file(parent, id, &name));
+            }
+            Transition::Unlink { parent, name } => {
+                let expected = removed(model.entry(parent, &name));
+                let parent = state.ids[&parent];
+                let orphan = state.tx(|tx| tx.unlink_file(parent, &name));
+                state.remove_orphan(orphan, expected);
+            }
+            Transition::Rename {
+                parent,
+                name,
+                new_parent,
+                new_name,
+                mode,
+            } => {
+                // Replaced directories are removed right away rather than orphaned
+                let expected = removed(model.entry(new_parent, &new_name))
+                    .filter(|&id| !model.is_dir(id) && mode == RenameMode::Replace);
+                let (parent, new_parent) = (state.ids[&parent], state.ids[&new_parent]);
+                let orphan =
+                    state.tx(|tx| tx.rename_entry(parent, &name, new_parent, &new_name, mode));
+                state.remove_orphan(orphan, expected);
+            }
+        }
+        state
+    }
+
+    fn check_invariants(
+        state: &Self::SystemUnderTest,
+        ref_state: &<Self::Reference as ReferenceStateMachine>::State,
+    ) {
+        let itself = DirEntryName::itself();
+        let parent = DirEntryName::parent();
+        state
+            .fs
+            .read_tx(|tx| {
+                for (&id, node) in &ref_state.nodes {
+                    let fs_id = state.ids[&id];
+                    if !matches!(node, ModelNode::Dir(_)) {
+                        let links = tx.read_node(fs_id)?.links.get();
+                        assert_eq!(links as usize, ref_state.links(id));
+                    }
+
+                    match node {
+                        ModelNode::File(contents) => {
+                            assert_eq!(tx.read_node(fs_id)?.size.get(), contents.len() as u64);
+                            let mut buf = vec![0; contents.len()];
+                            tx.read_file_at(fs_id, 0, &mut buf)?;
+                            assert!(buf == *contents, "contents of {id} differ");
+                        }
+                        ModelNode::Dir(children) => {
+                            let mut entries: Vec<_> = tx
+                                .read_dir(fs_id)?
+                                .into_iter()
+                                .filter(|entry| entry.name != itself && entry.name != parent)
+                                .map(|entry| {
+                                    (entry.name.as_str().to_owned(), entry.filetype, entry.id)
+                                })
+                                .collect();
+                            entries.sort_by(|a, b| a.0.cmp(&b.0));
+                            let expected: Vec<_> = children
+                                .iter()
+                                .map(|(name, child)| {
+                                    let filetype = ref_state.nodes[child].filetype();
+                                    (name.clone(), filetype, state.ids[child])
+                                })
+                                .collect();
+                            assert_eq!(entries, expected);
+                        }
+                        ModelNode::Symlink(target) => {
+                            assert_eq!(&*tx.read_symlink(fs_id)?, target.as_bytes());
+                        }
+                    }
+                }
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(check::check(&state.fs.storage).unwrap(), []);
+        // Everything but the root was removed, so all space is freed again
+        if ref_state.nodes.len() == 1 {
+            assert_eq!(state.fs.block_alloc().available(), state.available);
+        }
+    }
+}
+
+prop_state_machine! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn prop_state_machine(sequential 1..50 => FsState);
+}
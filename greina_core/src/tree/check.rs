@@ -0,0 +1,222 @@
+use std::{collections::BTreeSet, fmt};
+
+use crate::{
+    block::{Block, BlockAddr, storage::Storage},
+    tree::{DATA_MAX_LEN, Error, Key, Tree, node::NodeVariant},
+};
+
+/// An inconsistency in the structure of a tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    // The node can't be read or interpreted
+    Unreadable(BlockAddr),
+    // The node doesn't match its checksum
+    BadChecksum(BlockAddr),
+    // The node is the child of more than one item
+    SharedNode(BlockAddr),
+    // The node's height doesn't match its depth in the tree
+    BadHeight {
+        addr: BlockAddr,
+        expected: u16,
+        found: u16,
+    },
+    // The node's items or data don't fit in its block
+    Malformed(BlockAddr),
+    // The node's keys aren't in ascending order
+    Unsorted(BlockAddr),
+    // A key lies outside of the range the parent routes to the node
+    OutOfRange {
+        addr: BlockAddr,
+        key: Key,
+    },
+    // A non-root node is empty, or a non-root branch is deficient
+    Underfull(BlockAddr),
+    // An item's data is longer than 'DATA_MAX_LEN'
+    DataTooLong {
+        addr: BlockAddr,
+        key: Key,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unreadable(addr) => write!(f, "tree node {addr} is unreadable"),
+            Self::BadChecksum(addr) => write!(f, "tree node {addr} has a bad checksum"),
+            Self::SharedNode(addr) => write!(f, "tree node {addr} has more than one parent"),
+            Self::BadHeight {
+                addr,
+                expected,
+                found,
+            } => write!(
+                f,
+                "tree node {addr} has height {found}, expected {expected}"
+            ),
+            Self::Malformed(addr) => write!(f, "tree node {addr} is malformed"),
+            Self::Unsorted(addr) => write!(f, "tree node {addr} has unsorted keys"),
+            Self::OutOfRange { addr, key } => {
+                write!(f, "tree node {addr} has out of range key {key:?}")
+            }
+            Self::Underfull(addr) => write!(f, "tree node {addr} is underfull"),
+            Self::DataTooLong { addr, key } => {
+                write!(f, "tree node {addr} has too long data for key {key:?}")
+            }
+        }
+    }
+}
+
+/// The outcome of walking a tree with [Tree::check].
+#[derive(Debug, Default)]
+pub struct Check {
+    // The addresses of the nodes
+    pub blocks: Vec<BlockAddr>,
+    // The items stored in the leaves, in order
+    pub items: Vec<(Key, Box<[u8]>)>,
+    pub problems: Vec<Problem>,
+}
+
+impl<S> Tree<S>
+where
+    S: Storage,
+{
+    /// Walks the whole tree, collecting its nodes and items and checking that every node is
+    /// well-formed, sorted, of the right height and occupancy, and within its parent's bounds.
+    /// Subtrees under a node that can't be interpreted are skipped.
+    pub fn check(storage: &S, root_addr: BlockAddr) -> Check {
+        let mut check = Check::default();
+        let mut visited = BTreeSet::new();
+        Self::check_recursive(
+            storage,
+            root_addr,
+            None,
+            (None, None),
+            &mut visited,
+            &mut check,
+        );
+        check
+    }
+
+    /// Checks the subtree at `addr`, whose keys must lie within `bounds`.
+    /// `height` is `None` for the root, whose height isn't constrained.
+    fn check_recursive(
+        storage: &S,
+        addr: BlockAddr,
+        height: Option<u16>,
+        bounds: (Option<Key>, Option<Key>),
+        visited: &mut BTreeSet<BlockAddr>,
+        check: &mut Check,
+    ) {
+        if !visited.insert(addr) {
+            check.problems.push(Problem::SharedNode(addr));
+            return;
+        }
+
+        let mut block = Block::default();
+        match Self::read_node(storage, &mut block, addr) {
+            Ok(()) => (),
+            Err(Error::Checksum) => {
+                check.problems.push(Problem::BadChecksum(addr));
+                return;
+            }
+            Err(_) => {
+                check.problems.push(Problem::Unreadable(addr));
+                return;
+            }
+        }
+        let Ok(node) = NodeVariant::try_new(&block) else {
+            check.problems.push(Problem::Unreadable(addr));
+            return;
+        };
+        check.blocks.push(addr);
+
+        let is_root = height.is_none();
+        let found = match &node {
+            NodeVariant::Branch(branch) => branch.height(),
+            NodeVariant::Leaf(leaf) => leaf.height(),
+        };
+        if let Some(expected) = height
+            && expected != found
+        {
+            check.problems.push(Problem::BadHeight {
+                addr,
+                expected,
+                found,
+            });
+            return;
+        }
+
+        match node {
+            NodeVariant::Branch(branch) => {
+                if !branch.is_well_formed() {
+                    check.problems.push(Problem::Malformed(addr));
+                    return;
+                }
+
+                let keys: Vec<Key> = branch.keys().collect();
+                Self::check_keys(addr, &keys, bounds, check);
+
+                let underfull = if is_root {
+                    keys.len() < 2
+                } else {
+                    branch.is_deficient()
+                };
+                if underfull {
+                    check.problems.push(Problem::Underfull(addr));
+                }
+
+                for (idx, &key) in keys.iter().enumerate() {
+                    let child = branch.child_at(idx).expect("must have a child");
+                    let upper = keys.get(idx + 1).copied().or(bounds.1);
+                    Self::check_recursive(
+                        storage,
+                        child,
+                        Some(found - 1),
+                        (Some(key), upper),
+                        visited,
+                        check,
+                    );
+                }
+            }
+
+            NodeVariant::Leaf(leaf) => {
+                if !leaf.is_well_formed() {
+                    check.problems.push(Problem::Malformed(addr));
+                    return;
+                }
+
+                let keys: Vec<Key> = leaf.keys().collect();
+                Self::check_keys(addr, &keys, bounds, check);
+
+                if !is_root && keys.is_empty() {
+                    check.problems.push(Problem::Underfull(addr));
+                }
+
+                for idx in 0..keys.len() {
+                    let (key, data) = leaf.get_at(idx).expect("must have an item");
+                    if data.len() > DATA_MAX_LEN {
+                        check.problems.push(Problem::DataTooLong { addr, key });
+                    }
+                    check.items.push((key, data.into()));
+                }
+            }
+        }
+    }
+
+    fn check_keys(
+        addr: BlockAddr,
+        keys: &[Key],
+        (lower, upper): (Option<Key>, Option<Key>),
+        check: &mut Check,
+    ) {
+        if !keys.is_sorted_by(|a, b| a < b) {
+            check.problems.push(Problem::Unsorted(addr));
+        }
+
+        let out_of_range = keys.iter().find(|&&key| {
+            lower.is_some_and(|lower| key < lower) || upper.is_some_and(|upper| key >= upper)
+        });
+        if let Some(&key) = out_of_range {
+            check.problems.push(Problem::OutOfRange { addr, key });
+        }
+    }
+}
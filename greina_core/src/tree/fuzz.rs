@@ -0,0 +1,55 @@
+//! Entry points for fuzzing the tree's nodes, which aren't public otherwise.
+
+use crate::block::Block;
+
+use super::{Key, is_valid_node, node::*};
+
+/// Interprets `data` as a node's block, the way it's read once its checksum matches.
+/// A node that passes validation must be safe to read, modify and split.
+pub fn node(data: &[u8]) {
+    let mut block = Block::default();
+    let len = data.len().min(block.len());
+    block[..len].copy_from_slice(&data[..len]);
+    if !is_valid_node(&block, None) {
+        return;
+    }
+
+    let mut right = Block::default();
+    match NodeVariant::try_new(&mut block).expect("valid node must be interpretable") {
+        NodeVariant::Branch(mut branch) => {
+            let keys: Vec<Key> = branch.keys().collect();
+            for &key in &keys {
+                branch.child_for(key);
+            }
+
+            if let Some(&key) = keys.first() {
+                let child = branch.remove_at(0);
+                branch
+                    .insert(key, child)
+                    .expect("removed item must fit back");
+            }
+
+            if branch.item_count() > 1 {
+                let mut right = Branch::format(&mut right, branch.height());
+                branch.split(&mut right);
+            }
+        }
+        NodeVariant::Leaf(mut leaf) => {
+            let keys: Vec<Key> = leaf.keys().collect();
+            for &key in &keys {
+                leaf.get(key).expect("item must exist");
+                leaf.get_le(key).expect("item must exist");
+            }
+
+            if let Some(&key) = keys.first() {
+                let data = leaf.remove(key).expect("item must exist");
+                leaf.insert(key, &data).expect("removed item must fit back");
+            }
+
+            if leaf.item_count() > 1 {
+                let mut right = Leaf::format(&mut right, 0);
+                leaf.split(&mut right);
+            }
+        }
+    }
+}
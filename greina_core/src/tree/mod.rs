@@ -1,6 +1,9 @@
 #[cfg(test)]
 mod tests;
 
+pub mod check;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
 mod node;
 pub use node::DataType;
 pub use node::Key;
@@ -12,6 +15,33 @@ use crate::block::{self, Block, BlockAddr, allocator, storage::Storage};
 
 pub const DATA_MAX_LEN: usize = 512;
 
+/// Checks whether a node is well-formed, sorted and non-empty, unless it's an empty root.
+/// `height` is the height the node's parent expects, or `None` for the root.
+fn is_valid_node(block: &Block, height: Option<u16>) -> bool {
+    let Ok(node) = NodeVariant::try_new(block) else {
+        return false;
+    };
+    let (found, item_count, is_well_formed, is_sorted) = match &node {
+        NodeVariant::Branch(branch) => (
+            branch.height(),
+            branch.item_count(),
+            branch.is_well_formed(),
+            branch.keys().is_sorted_by(|a, b| a < b),
+        ),
+        NodeVariant::Leaf(leaf) => (
+            leaf.height(),
+            leaf.item_count(),
+            leaf.is_well_formed(),
+            leaf.keys().is_sorted_by(|a, b| a < b),
+        ),
+    };
+    let is_empty_root = height.is_none() && found == 0;
+    is_well_formed
+        && is_sorted
+        && (item_count > 0 || is_empty_root)
+        && height.is_none_or(|height| height == found)
+}
+
 pub struct Tree<S> {
     _storage: PhantomData<S>,
 }
@@ -20,22 +50,60 @@ impl<S> Tree<S>
 where
     S: Storage,
 {
+    /// Reads the node at `addr` into `block`, verifying its checksum.
+    fn read_node(storage: &S, block: &mut Block, addr: BlockAddr) -> Result<()> {
+        storage.read_at(block, addr)?;
+        if !node::verify(block, addr) {
+            log::error!("tree node {addr} has a bad checksum");
+            return Err(Error::Checksum);
+        }
+        Ok(())
+    }
+
+    /// Reads the node at `addr` into `block`, verifying its checksum and that it's well-formed,
+    /// sorted and non-empty, unless it's an empty root.
+    /// `height` is the height the node's parent expects, or `None` for the root. Requiring every
+    /// child to be one level below its parent also rules out cycles.
+    fn read_valid_node(
+        storage: &S,
+        block: &mut Block,
+        addr: BlockAddr,
+        height: Option<u16>,
+    ) -> Result<()> {
+        Self::read_node(storage, block, addr)?;
+        if !is_valid_node(block, height) {
+            log::error!("tree node {addr} is malformed");
+            return Err(Error::Uninterpretable);
+        }
+        Ok(())
+    }
+
+    /// Writes the node in `block` to `addr`, along with its checksum.
+    fn write_node(storage: &S, block: &Block, addr: BlockAddr) -> Result<()> {
+        let mut block = *block;
+        node::seal(&mut block, addr);
+        storage.write_at(&block, addr)?;
+        Ok(())
+    }
+
     pub fn format(storage: &mut S, root_addr: BlockAddr) -> Result<()> {
         let mut block = Block::default();
         let _ = Leaf::format(&mut block, 0);
-        storage.write_at(&block, root_addr)?;
+        Self::write_node(storage, &block, root_addr)?;
         Ok(())
     }
 
     pub fn get(storage: &S, mut root_addr: BlockAddr, key: Key) -> Result<Option<Box<[u8]>>> {
         let mut block = Block::default();
+        let mut height = None;
 
         let leaf = loop {
-            storage.read_at(&mut block, root_addr)?;
+            Self::read_valid_node(storage, &mut block, root_addr, height)?;
 
             match NodeVariant::try_new(&block)? {
                 NodeVariant::Branch(branch) => {
                     root_addr = branch.child_for(key);
+                    height = Some(branch.height() - 1);
                 }
 
                 NodeVariant::Leaf(leaf) => break leaf,
@@ -51,13 +119,15 @@ where
         key: Key,
     ) -> Result<Option<(Key, Box<[u8]>)>> {
         let mut block = Block::default();
+        let mut height = None;
 
         let leaf = loop {
-            storage.read_at(&mut block, root_addr)?;
+            Self::read_valid_node(storage, &mut block, root_addr, height)?;
 
             match NodeVariant::try_new(&block)? {
                 NodeVariant::Branch(branch) => {
                     root_addr = branch.child_for(key);
+                    height = Some(branch.height() - 1);
                 }
 
                 NodeVariant::Leaf(leaf) => break leaf,
@@ -94,7 +164,7 @@ where
             return Err(Error::DataTooLong);
         }
 
-        match Self::insert_recursive(storage, block_alloc, *root_addr, key, data)? {
+        match Self::insert_recursive(storage, block_alloc, root_addr, None, key, data)? {
             InsertOutcome::Done => Ok(()),
             InsertOutcome::LowerBoundChanged(_) => Ok(()),
             InsertOutcome::Split(result) => {
@@ -107,6 +177,27 @@ where
         }
     }
 
+    /// Writes a modified node to a freshly allocated address and frees its old address,
+    /// so that a block reachable from the last committed root is never overwritten.
+    /// A node allocated since the last commit is rewritten in place, as nothing committed
+    /// refers to it.
+    /// Returns the node's new address.
+    fn relocate(
+        storage: &mut S,
+        block_alloc: &mut impl block::Allocator,
+        block: &Block,
+        addr: BlockAddr,
+    ) -> Result<BlockAddr> {
+        if block_alloc.is_fresh(addr) {
+            Self::write_node(storage, block, addr)?;
+            return Ok(addr);
+        }
+        let new_addr = block_alloc.allocate(1)?;
+        Self::write_node(storage, block, new_addr)?;
+        block_alloc.deallocate(addr, 1)?;
+        Ok(new_addr)
+    }
+
     fn handle_split_root(
         storage: &mut S,
         block_alloc: &mut impl block::Allocator,
@@ -114,7 +205,7 @@ where
         result: SplitOutcome,
     ) -> Result<()> {
         let mut old_root_block = Block::default();
-        storage.read_at(&mut old_root_block, *root_addr)?;
+        Self::read_valid_node(storage, &mut old_root_block, *root_addr, None)?;
         let (old_root_lower_bound, old_root_height) =
             match NodeVariant::try_new(&mut old_root_block)? {
                 NodeVariant::Branch(old_root) => (old_root.lower_bound(), old_root.height()),
@@ -132,42 +223,50 @@ where
             .insert(result.right_lower_bound, result.right_addr)
             .expect("must have one item");
 
-        storage.write_at(&new_root_block, new_root_addr)?;
+        Self::write_node(storage, &new_root_block, new_root_addr)?;
         *root_addr = new_root_addr;
 
         Ok(())
     }
 
+    /// Inserts an item into the subtree at `addr`, whose root has the given `height`.
+    /// The modified nodes are relocated, so `addr` is updated to the subtree's new address.
     fn insert_recursive(
         storage: &mut S,
         block_alloc: &mut impl block::Allocator,
-        addr: BlockAddr,
+        addr: &mut BlockAddr,
+        height: Option<u16>,
         key: Key,
         data: &[u8],
     ) -> Result<InsertOutcome> {
         let mut block = Block::default();
-        storage.read_at(&mut block, addr)?;
+        Self::read_valid_node(storage, &mut block, *addr, height)?;
 
-        match NodeVariant::try_new(&mut block)? {
+        let outcome = match NodeVariant::try_new(&mut block)? {
             NodeVariant::Branch(mut branch) => {
                 let child_idx = branch.child_idx_for(key);
-                let child_addr = branch.child_at(child_idx).expect("child must exist");
-
-                match Self::insert_recursive(storage, block_alloc, child_addr, key, data)? {
-                    InsertOutcome::Done => Ok(InsertOutcome::Done),
+                let mut child_addr = branch.child_at(child_idx).expect("child must exist");
+                let child_height = Some(branch.height() - 1);
+
+                let child_outcome = Self::insert_recursive(
+                    storage,
+                    block_alloc,
+                    &mut child_addr,
+                    child_height,
+                    key,
+                    data,
+                )?;
+                branch.set_child_at(child_idx, child_addr);
+
+                let outcome = match child_outcome {
+                    InsertOutcome::Done => InsertOutcome::Done,
 
                     InsertOutcome::Split(result) => {
-                        Self::handle_split_child(storage, block_alloc, &mut branch, addr, result)
+                        Self::handle_split_child(storage, block_alloc, &mut branch, result)?
                     }
 
                     InsertOutcome::LowerBoundChanged(child_lower_bound) => {
-                        Self::handle_lower_bound_changed(
-                            storage,
-                            &mut branch,
-                            addr,
-                            child_idx,
-                            child_lower_bound,
-                        )
+                        Self::handle_lower_bound_changed(&mut branch, child_idx, child_lower_bound)
                     }
 
                     InsertOutcome::SplitAndLowerBoundChanged {
@@ -175,21 +274,18 @@ where
                         lower_bound: child_lower_bound,
                     } => {
                         let lower_bound_result = Self::handle_lower_bound_changed(
-                            storage,
                             &mut branch,
-                            addr,
                             child_idx,
                             child_lower_bound,
-                        )?;
+                        );
                         let split_result = Self::handle_split_child(
                             storage,
                             block_alloc,
                             &mut branch,
-                            addr,
                             child_result,
                         )?;
                         use InsertOutcome::*;
-                        let result = match (lower_bound_result, split_result) {
+                        match (lower_bound_result, split_result) {
                             (Done, Done) => Done,
                             (Done, Split(result)) => Split(result),
                             (LowerBoundChanged(key), Done) => LowerBoundChanged(key),
@@ -198,54 +294,60 @@ where
                                 lower_bound: key,
                             },
                             _ => unreachable!(),
-                        };
-                        Ok(result)
+                        }
                     }
-                }
+                };
+
+                *addr = Self::relocate(storage, block_alloc, branch.block(), *addr)?;
+                outcome
             }
 
-            NodeVariant::Leaf(mut leaf) => match leaf.insert(key, data) {
-                Ok(()) => {
-                    storage.write_at(leaf.block(), addr)?;
-                    if key == leaf.lower_bound() {
-                        Ok(InsertOutcome::LowerBoundChanged(key))
-                    } else {
-                        Ok(InsertOutcome::Done)
+            NodeVariant::Leaf(mut leaf) => {
+                let outcome = match leaf.insert(key, data) {
+                    Ok(()) => {
+                        if key == leaf.lower_bound() {
+                            InsertOutcome::LowerBoundChanged(key)
+                        } else {
+                            InsertOutcome::Done
+                        }
                     }
-                }
 
-                Err(InsertError::Overflow) => {
-                    let result = Self::handle_overflow(storage, block_alloc, &mut leaf, addr)?;
-                    Self::handle_split_leaf(storage, &mut leaf, addr, key, data, result)
-                }
+                    Err(InsertError::Overflow) => {
+                        let result = Self::handle_overflow(storage, block_alloc, &mut leaf)?;
+                        Self::handle_split_leaf(storage, &mut leaf, key, data, result)?
+                    }
 
-                Err(InsertError::Occupied) => Err(Error::Occupied),
-            },
-        }
+                    Err(InsertError::Occupied) => return Err(Error::Occupied),
+                };
+
+                *addr = Self::relocate(storage, block_alloc, leaf.block(), *addr)?;
+                outcome
+            }
+        };
+
+        Ok(outcome)
     }
 
     fn handle_lower_bound_changed(
-        storage: &mut S,
         branch: &mut Branch<&mut Block>,
-        branch_addr: BlockAddr,
         child_idx: usize,
         child_lower_bound: Key,
-    ) -> Result<InsertOutcome> {
+    ) -> InsertOutcome {
         branch.set_key_at(child_idx, child_lower_bound);
-        storage.write_at(branch.block(), branch_addr)?;
         let lower_bound = branch.lower_bound();
         if lower_bound == child_lower_bound {
-            Ok(InsertOutcome::LowerBoundChanged(lower_bound))
+            InsertOutcome::LowerBoundChanged(lower_bound)
         } else {
-            Ok(InsertOutcome::Done)
+            InsertOutcome::Done
         }
     }
 
+    /// Moves the upper half of `node` into a new right sibling, which is written to storage.
+    /// `node` itself is left to be written by the caller.
     fn handle_overflow<I: Item>(
         storage: &mut S,
         block_alloc: &mut impl block::Allocator,
         node: &mut Node<&mut Block, I>,
-        node_addr: BlockAddr,
     ) -> Result<SplitOutcome>
     where
         for<'a> Node<&'a mut Block, I>: Split<Item = I>,
@@ -257,8 +359,7 @@ where
         node.split(&mut right);
         let right_lower_bound = right.lower_bound();
 
-        storage.write_at(node.block(), node_addr)?;
-        storage.write_at(&right_block, right_addr)?;
+        Self::write_node(storage, &right_block, right_addr)?;
 
         Ok(SplitOutcome {
             right_lower_bound,
@@ -270,36 +371,37 @@ where
         storage: &mut S,
         block_alloc: &mut impl block::Allocator,
         branch: &mut Branch<&mut Block>,
-        branch_addr: BlockAddr,
         child_result: SplitOutcome,
     ) -> Result<InsertOutcome> {
         match branch.insert(child_result.right_lower_bound, child_result.right_addr) {
-            Ok(()) => {
-                storage.write_at(branch.block(), branch_addr)?;
-                // 'child_result.right_lower_bound' can't become a lower bound,
-                // because it's the right sibling of some node
-                Ok(InsertOutcome::Done)
-            }
+            // 'child_result.right_lower_bound' can't become a lower bound,
+            // because it's the right sibling of some node
+            Ok(()) => Ok(InsertOutcome::Done),
 
             Err(InsertError::Overflow) => {
-                let mut result = Self::handle_overflow(storage, block_alloc, branch, branch_addr)?;
+                let mut result = Self::handle_overflow(storage, block_alloc, branch)?;
                 if child_result.right_lower_bound < result.right_lower_bound {
                     branch
                         .insert(child_result.right_lower_bound, child_result.right_addr)
                         .expect("must be able to insert after split");
-                    storage.write_at(branch.block(), branch_addr)?;
                     // 'child_result.right_lower_bound' can't become a lower bound,
                     // because it's the right sibling of some node
                     Ok(InsertOutcome::Split(result))
                 } else {
+                    // The right sibling is new, so it can be written in place
                     let mut block = Block::default();
-                    storage.read_at(&mut block, result.right_addr)?;
+                    Self::read_valid_node(
+                        storage,
+                        &mut block,
+                        result.right_addr,
+                        Some(branch.height()),
+                    )?;
                     let mut right = Branch::try_new(&mut block)?;
                     right
                         .insert(child_result.right_lower_bound, child_result.right_addr)
                         .expect("must be able to insert after split");
                     result.right_lower_bound = right.lower_bound();
-                    storage.write_at(right.block(), result.right_addr)?;
+                    Self::write_node(storage, right.block(), result.right_addr)?;
                     Ok(InsertOutcome::Split(result))
                 }
             }
@@ -311,7 +413,6 @@ where
     fn handle_split_leaf(
         storage: &mut S,
         leaf: &mut Leaf<&mut Block>,
-        leaf_addr: BlockAddr,
         key: Key,
         data: &[u8],
         mut result: SplitOutcome,
@@ -322,7 +423,6 @@ where
                 Err(InsertError::Occupied) => return Err(Error::Occupied),
                 Err(InsertError::Overflow) => unreachable!(),
             }
-            storage.write_at(leaf.block(), leaf_addr)?;
             let lower_bound = leaf.lower_bound();
             if lower_bound == key {
                 Ok(InsertOutcome::SplitAndLowerBoundChanged {
@@ -333,15 +433,16 @@ where
                 Ok(InsertOutcome::Split(result))
             }
         } else {
+            // The right sibling is new, so it can be written in place
             let mut block = Block::default();
-            storage.read_at(&mut block, result.right_addr)?;
+            Self::read_valid_node(storage, &mut block, result.right_addr, Some(0))?;
             let mut right = Leaf::try_new(&mut block)?;
             match right.insert(key, data) {
                 Ok(()) => (),
                 Err(InsertError::Occupied) => return Err(Error::Occupied),
                 Err(InsertError::Overflow) => unreachable!(),
             }
-            storage.write_at(right.block(), result.right_addr)?;
+            Self::write_node(storage, right.block(), result.right_addr)?;
             result.right_lower_bound = right.lower_bound();
             Ok(InsertOutcome::Split(result))
         }
@@ -353,7 +454,7 @@ where
         root_addr: &mut BlockAddr,
         key: Key,
     ) -> Result<Option<Box<[u8]>>> {
-        match Self::remove_recursive(storage, block_alloc, *root_addr, key)? {
+        match Self::remove_recursive(storage, block_alloc, root_addr, None, key)? {
             RemoveOutcome::BecameDeficient(data) => {
                 Self::handle_deficient_root(storage, block_alloc, root_addr)?;
                 Ok(data)
@@ -369,7 +470,7 @@ where
         root_addr: &mut BlockAddr,
     ) -> Result<()> {
         let mut block = Block::default();
-        storage.read_at(&mut block, *root_addr)?;
+        Self::read_valid_node(storage, &mut block, *root_addr, None)?;
         match NodeVariant::try_new(&block)? {
             NodeVariant::Branch(root) => {
                 if root.item_count() == 1 {
@@ -384,31 +485,46 @@ where
         }
     }
 
+    /// Removes an item from the subtree at `addr`, whose root has the given `height`.
+    /// The modified nodes are relocated, so `addr` is updated to the subtree's new address.
     fn remove_recursive(
         storage: &mut S,
         block_alloc: &mut impl block::Allocator,
-        root_addr: BlockAddr,
+        addr: &mut BlockAddr,
+        height: Option<u16>,
         key: Key,
     ) -> Result<RemoveOutcome> {
         let mut block = Block::default();
-        storage.read_at(&mut block, root_addr)?;
+        Self::read_valid_node(storage, &mut block, *addr, height)?;
 
         match NodeVariant::try_new(&mut block)? {
             NodeVariant::Branch(mut branch) => {
                 let child_idx = branch.child_idx_for(key);
-                let child_addr = branch.child_at(child_idx).expect("must have a child");
+                let mut child_addr = branch.child_at(child_idx).expect("must have a child");
+                let child_height = Some(branch.height() - 1);
+
+                let data = match Self::remove_recursive(
+                    storage,
+                    block_alloc,
+                    &mut child_addr,
+                    child_height,
+                    key,
+                )? {
+                    // Nothing was removed, so the subtree is unchanged
+                    RemoveOutcome::Done(None) => return Ok(RemoveOutcome::Done(None)),
 
-                let data = match Self::remove_recursive(storage, block_alloc, child_addr, key)? {
                     RemoveOutcome::BecameDeficient(data) => {
+                        branch.set_child_at(child_idx, child_addr);
+
                         let mut child_block = Block::default();
-                        storage.read_at(&mut child_block, child_addr)?;
+                        // The child was just relocated, and may have become empty
+                        Self::read_node(storage, &mut child_block, child_addr)?;
 
                         match NodeVariant::try_new(&mut child_block)? {
                             NodeVariant::Branch(mut child) => Self::handle_deficient(
                                 storage,
                                 block_alloc,
                                 &mut branch,
-                                root_addr,
                                 &mut child,
                                 child_addr,
                                 child_idx,
@@ -418,7 +534,6 @@ where
                                 storage,
                                 block_alloc,
                                 &mut branch,
-                                root_addr,
                                 &mut child,
                                 child_addr,
                                 child_idx,
@@ -428,9 +543,14 @@ where
                         data
                     }
 
-                    RemoveOutcome::Done(data) => data,
+                    RemoveOutcome::Done(data) => {
+                        branch.set_child_at(child_idx, child_addr);
+                        data
+                    }
                 };
 
+                *addr = Self::relocate(storage, block_alloc, branch.block(), *addr)?;
+
                 if branch.is_deficient() {
                     Ok(RemoveOutcome::BecameDeficient(data))
                 } else {
@@ -439,23 +559,26 @@ where
             }
 
             NodeVariant::Leaf(mut leaf) => {
-                let data = leaf.remove(key);
-                storage.write_at(leaf.block(), root_addr)?;
+                let Some(data) = leaf.remove(key) else {
+                    return Ok(RemoveOutcome::Done(None));
+                };
+                *addr = Self::relocate(storage, block_alloc, leaf.block(), *addr)?;
 
                 if leaf.is_deficient() {
-                    Ok(RemoveOutcome::BecameDeficient(data))
+                    Ok(RemoveOutcome::BecameDeficient(Some(data)))
                 } else {
-                    Ok(RemoveOutcome::Done(data))
+                    Ok(RemoveOutcome::Done(Some(data)))
                 }
             }
         }
     }
 
+    /// Replenishes a deficient child by rotating or merging with a sibling.
+    /// `parent` is updated to point to the relocated nodes, but is left to be written by the caller.
     fn handle_deficient<I: Item>(
         storage: &mut S,
         block_alloc: &mut impl block::Allocator,
         parent: &mut Branch<&mut Block>,
-        parent_addr: BlockAddr,
         child: &mut Node<&mut Block, I>,
         child_addr: BlockAddr,
         child_idx: usize,
@@ -468,14 +591,18 @@ where
         let right_idx = child_idx + 1;
         let right_addr = parent.child_at(right_idx);
         if let Some(right_addr) = right_addr {
-            storage.read_at(&mut sibling_block, right_addr)?;
+            Self::read_valid_node(
+                storage,
+                &mut sibling_block,
+                right_addr,
+                Some(child.height()),
+            )?;
             let mut right = Node::<&mut Block, I>::try_new(&mut sibling_block)?;
 
             if Self::rotate(
                 storage,
                 block_alloc,
                 parent,
-                parent_addr,
                 child,
                 child_addr,
                 &mut right,
@@ -490,14 +617,13 @@ where
         let left_idx = child_idx.checked_sub(1);
         let left_addr = left_idx.and_then(|idx| parent.child_at(idx));
         if let Some(left_addr) = left_addr {
-            storage.read_at(&mut sibling_block, left_addr)?;
+            Self::read_valid_node(storage, &mut sibling_block, left_addr, Some(child.height()))?;
             let mut left = Node::<&mut Block, I>::try_new(&mut sibling_block)?;
 
             Self::rotate(
                 storage,
                 block_alloc,
                 parent,
-                parent_addr,
                 &mut left,
                 left_addr,
                 child,
@@ -510,11 +636,11 @@ where
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn rotate<I: Item>(
         storage: &mut S,
         block_alloc: &mut impl block::Allocator,
         parent: &mut Branch<&mut Block>,
-        parent_addr: BlockAddr,
         left: &mut Node<&mut Block, I>,
         left_addr: BlockAddr,
         right: &mut Node<&mut Block, I>,
@@ -535,7 +661,6 @@ where
                 storage,
                 block_alloc,
                 parent,
-                parent_addr,
                 left,
                 left_addr,
                 right,
@@ -544,20 +669,21 @@ where
             );
         }
 
-        parent.set_key_at(right_idx, right.lower_bound());
+        let left_addr = Self::relocate(storage, block_alloc, left.block(), left_addr)?;
+        let right_addr = Self::relocate(storage, block_alloc, right.block(), right_addr)?;
 
-        storage.write_at(left.block(), left_addr)?;
-        storage.write_at(right.block(), right_addr)?;
-        storage.write_at(parent.block(), parent_addr)?;
+        parent.set_child_at(right_idx - 1, left_addr);
+        parent.set_child_at(right_idx, right_addr);
+        parent.set_key_at(right_idx, right.lower_bound());
 
         Ok(true)
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn merge<I: Item>(
         storage: &mut S,
         block_alloc: &mut impl block::Allocator,
         parent: &mut Branch<&mut Block>,
-        parent_addr: BlockAddr,
         left: &mut Node<&mut Block, I>,
         left_addr: BlockAddr,
         right: &mut Node<&mut Block, I>,
@@ -571,10 +697,10 @@ where
             return Ok(false);
         }
 
-        parent.remove_at(right_idx);
+        let left_addr = Self::relocate(storage, block_alloc, left.block(), left_addr)?;
 
-        storage.write_at(left.block(), left_addr)?;
-        storage.write_at(parent.block(), parent_addr)?;
+        parent.remove_at(right_idx);
+        parent.set_child_at(right_idx - 1, left_addr);
 
         block_alloc.deallocate(right_addr, 1)?;
 
@@ -630,6 +756,8 @@ pub type Result<T> = core::result::Result<T, Error>;
 #[derive(Debug)]
 pub enum Error {
     Uninterpretable,
+    // A node doesn't match its checksum
+    Checksum,
     Occupied,
     DataTooLong,
 
@@ -8,8 +8,8 @@ use std::{
 };
 
 use zerocopy::{
-    Immutable, IntoBytes, KnownLayout, TryFromBytes, Unaligned,
-    little_endian::{U16, U64},
+    FromBytes, Immutable, IntoBytes, KnownLayout, TryFromBytes, Unaligned,
+    little_endian::{U16, U32, U64},
 };
 
 use crate::{
@@ -43,6 +43,26 @@ where
     }
 }
 
+/// Stores the checksum of a node's block in its header, before it's written at `addr`.
+pub(super) fn seal(block: &mut Block, addr: BlockAddr) {
+    let checksum = checksum(block, addr);
+    block[..CHECKSUM_SIZE].copy_from_slice(checksum.as_bytes());
+}
+
+/// Checks whether a node's block read from `addr` matches the checksum in its header.
+/// The address is part of the checksum, so a block written to the wrong address fails too.
+pub(super) fn verify(block: &Block, addr: BlockAddr) -> bool {
+    let (stored, _) = U32::read_from_prefix(&block[..]).expect("'block' must fit a checksum");
+    stored.get() == checksum(block, addr)
+}
+
+fn checksum(block: &Block, addr: BlockAddr) -> u32 {
+    let seed = crc32c::crc32c(&addr.to_le_bytes());
+    crc32c::crc32c_append(seed, &block[CHECKSUM_SIZE..])
+}
+
+const CHECKSUM_SIZE: usize = size_of::<U32>();
+
 pub(super) const NODE_CAPACITY: usize = BLOCK_SIZE as usize - HEADER_SIZE;
 
 const OCCUPANCY_THRESH: usize = NODE_CAPACITY / 2;
@@ -155,6 +175,18 @@ where
         NODE_CAPACITY - self.used_space()
     }
 
+    /// Returns the keys of the items in order.
+    pub(super) fn keys(&self) -> impl Iterator<Item = Key> + '_ {
+        self.items().iter().map(|item| item.key())
+    }
+
+    /// Checks whether the data area lies between the item list and the end of the block.
+    fn has_valid_data_offset(&self) -> bool {
+        let items_end = HEADER_SIZE + usize::from(self.item_count()) * Self::ITEM_SIZE;
+        let data_offset = usize::from(self.data_offset());
+        (items_end..=BLOCK_SIZE as usize).contains(&data_offset)
+    }
+
     fn can_insert(&self, item_count: usize, data_size: usize) -> bool {
         let required = item_count * Self::ITEM_SIZE + data_size;
         self.free_space() >= required
@@ -318,6 +350,11 @@ where
     pub(super) fn child_for(&self, key: Key) -> BlockAddr {
         self.items()[self.child_idx_for(key)].child.into()
     }
+
+    /// Checks whether the header is consistent with the block.
+    pub(super) fn is_well_formed(&self) -> bool {
+        self.has_valid_data_offset()
+    }
 }
 
 impl<B> Branch<B>
@@ -351,6 +388,14 @@ where
     pub(super) fn set_key_at(&mut self, idx: usize, key: Key) {
         self.items_mut()[idx].key = key
     }
+
+    /// Sets the child of the item at index.
+    ///
+    /// # Panics
+    /// Panics if the index is out of bounds.
+    pub(super) fn set_child_at(&mut self, idx: usize, child: BlockAddr) {
+        self.items_mut()[idx].child = child.into()
+    }
 }
 
 pub(super) type Leaf<B> = Node<B, LeafItem>;
@@ -375,6 +420,45 @@ where
         self.get_item_le(key)
             .map(|item| (item.key, self.get_for_item(item)))
     }
+
+    /// Returns the key and data of the item at index.
+    pub(super) fn get_at(&self, idx: usize) -> Option<(Key, &[u8])> {
+        self.items()
+            .get(idx)
+            .map(|item| (item.key, self.get_for_item(item)))
+    }
+
+    /// Checks whether the header is consistent with the block and the items' data exactly fills
+    /// the data area, the way inserting and removing items packs it.
+    pub(super) fn is_well_formed(&self) -> bool {
+        if !self.has_valid_data_offset() {
+            return false;
+        }
+        let data_area = usize::from(self.data_offset())..=BLOCK_SIZE as usize;
+        let mut spans = Vec::with_capacity(self.items().len());
+        for item in self.items() {
+            let start = usize::from(item.offset);
+            let end = start + usize::from(item.size);
+            if !data_area.contains(&start) || !data_area.contains(&end) {
+                return false;
+            }
+            // Empty data takes no space, so it can't overlap
+            if start != end {
+                spans.push(start..end);
+            }
+        }
+
+        // Overlapping data would be moved over other items' data when compacting
+        spans.sort_unstable_by_key(|span| span.start);
+        let mut offset = *data_area.start();
+        for span in spans {
+            if span.start != offset {
+                return false;
+            }
+            offset = span.end;
+        }
+        offset == BLOCK_SIZE as usize
+    }
 }
 
 impl<B> Leaf<B>
@@ -577,6 +661,8 @@ const HEADER_SIZE: usize = size_of::<Header>();
 #[derive(Debug, Clone, Copy)]
 #[derive(TryFromBytes, IntoBytes, Immutable, KnownLayout, Unaligned)]
 struct Header {
+    // The crc32c of the rest of the block, seeded with the node's address
+    checksum: U32,
     // The distance from this node to a leaf node
     height: U16,
     item_count: U16,
@@ -587,6 +673,7 @@ struct Header {
 impl Default for Header {
     fn default() -> Self {
         Self {
+            checksum: Default::default(),
             height: Default::default(),
             item_count: Default::default(),
             data_offset: U16::new(BLOCK_SIZE as u16),
@@ -639,6 +726,31 @@ impl Key {
         }
     }
 
+    pub fn xattr(id: NodeId, hash: u64) -> Self {
+        Self {
+            id,
+            datatype: DataType::Xattr,
+            offset: hash.into(),
+        }
+    }
+
+    /// Orphans are all stored under the null node, so they can be listed together.
+    pub fn orphan(id: NodeId) -> Self {
+        Self {
+            id: NodeId::NULL,
+            datatype: DataType::Orphan,
+            offset: id.get().into(),
+        }
+    }
+
+    pub fn checksum(id: NodeId, offset: u64) -> Self {
+        Self {
+            id,
+            datatype: DataType::Checksum,
+            offset: offset.into(),
+        }
+    }
+
     pub fn offset(&self) -> u64 {
         self.offset.get()
     }
@@ -678,6 +790,12 @@ pub enum DataType {
     Extent,
     // A mapping of a name to a node
     DirEntry,
+    // A named extended attribute of a node
+    Xattr,
+    // A node without links that is still referenced
+    Orphan,
+    // Checksums of a range of a file's data blocks
+    Checksum,
 }
 
 pub(super) trait Item:
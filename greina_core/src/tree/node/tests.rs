@@ -3,10 +3,10 @@ use super::*;
 #[macro_export]
 macro_rules! key {
     () => {
-        Key::new(crate::fs::node::NodeId::NULL, DataType::Node, 0)
+        Key::new($crate::fs::node::NodeId::NULL, DataType::Node, 0)
     };
     ($obj_id:expr) => {
-        Key::new(crate::fs::node::NodeId::new($obj_id), DataType::Node, 0)
+        Key::new($crate::fs::node::NodeId::new($obj_id), DataType::Node, 0)
     };
 }
 
@@ -120,6 +120,22 @@ mod branch {
         );
     }
 
+    #[test]
+    fn set_child() {
+        let mut branch = branch!(
+            10 => 10,
+            20 => 20,
+        );
+
+        branch.set_child_at(1, 40);
+
+        assert_routes!(
+            branch,
+            10 => 10,
+            20 => 40,
+        );
+    }
+
     #[test]
     fn rotate_left() {
         let mut right = branch!();
@@ -241,7 +257,7 @@ mod leaf {
                     let key = $key;
                     let got = $leaf.get(key);
                     assert!(
-                        matches!(got, None),
+                        got.is_none(),
                         "mismatch when getting {:?}: expected None, got {:?}",
                         key,
                         got
@@ -253,7 +269,7 @@ mod leaf {
     #[test]
     fn get_nonexistent() {
         let leaf = leaf!();
-        assert!(matches!(leaf.get(key!(0)), None));
+        assert!(leaf.get(key!(0)).is_none());
     }
 
     #[test]
@@ -308,6 +324,16 @@ mod leaf {
         assert_eq!(leaf.free_space(), expect_free);
     }
 
+    #[test]
+    fn overlapping_data_is_malformed() {
+        let mut leaf = leaf!(0 => b"foo", 1 => b"bar", 2 => b"");
+        assert!(leaf.is_well_formed());
+
+        let offset = leaf.items()[0].offset;
+        leaf.items_mut()[1].offset = offset;
+        assert!(!leaf.is_well_formed());
+    }
+
     #[test]
     fn trigger_overflow() {
         const COUNT: usize = NODE_CAPACITY / (DATA_MAX_LEN + size_of::<LeafItem>());
@@ -368,7 +394,7 @@ mod leaf {
     #[test]
     fn remove_nonexistent() {
         let mut leaf = leaf!();
-        assert!(matches!(leaf.remove(key!(0)), None))
+        assert!(leaf.remove(key!(0)).is_none())
     }
 
     #[test]
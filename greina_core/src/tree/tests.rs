@@ -20,6 +20,9 @@ fn arb_data_type() -> impl Strategy<Value = DataType> {
         Just(DataType::Node),
         Just(DataType::Extent),
         Just(DataType::DirEntry),
+        Just(DataType::Xattr),
+        Just(DataType::Orphan),
+        Just(DataType::Checksum),
     ]
 }
 
@@ -43,6 +46,7 @@ struct TreeStateReference;
 enum Transition {
     Insert(Key, Box<[u8]>),
     Remove(Key),
+    Commit,
 }
 
 impl Debug for Transition {
@@ -54,6 +58,7 @@ impl Debug for Transition {
                 .field(&format!("[u8; {}]", data.len()))
                 .finish(),
             Self::Remove(key) => f.debug_tuple("Remove").field(key).finish(),
+            Self::Commit => f.write_str("Commit"),
         }
     }
 }
@@ -74,6 +79,7 @@ impl ReferenceStateMachine for TreeStateReference {
             .prop_map(|(key, data)| Transition::Insert(key, data))
             .boxed();
         strats.push(insert_strat);
+        strats.push(Just(Transition::Commit).boxed());
 
         if !state.is_empty() {
             let keys: Vec<_> = state.keys().copied().collect();
@@ -94,6 +100,7 @@ impl ReferenceStateMachine for TreeStateReference {
             Transition::Remove(key) => {
                 state.remove(key);
             }
+            Transition::Commit => {}
         }
         state
     }
@@ -102,6 +109,7 @@ impl ReferenceStateMachine for TreeStateReference {
         match transition {
             Transition::Insert(key, _) => !state.contains_key(key),
             Transition::Remove(key) => state.contains_key(key),
+            Transition::Commit => true,
         }
     }
 }
@@ -123,7 +131,7 @@ impl TreeState {
             &mut self.block_alloc,
             &mut self.root_addr,
             key,
-            &data,
+            data,
         )
     }
 
@@ -135,20 +143,33 @@ impl TreeState {
             key,
         )
     }
+
+    fn reachable_blocks(&self) -> Vec<(BlockAddr, Block)> {
+        fn collect(tree: &TreeState, addr: BlockAddr, blocks: &mut Vec<(BlockAddr, Block)>) {
+            let mut block = Block::default();
+            tree.storage.read_at(&mut block, addr).unwrap();
+            if let NodeVariant::Branch(node) = NodeVariant::try_new(&block).unwrap() {
+                for child_idx in 0..node.item_count() {
+                    collect(tree, node.child_at(child_idx.into()).unwrap(), blocks);
+                }
+            }
+            blocks.push((addr, block));
+        }
+
+        let mut blocks = Vec::new();
+        collect(self, self.root_addr, &mut blocks);
+        blocks
+    }
 }
 
 impl Default for TreeState {
     fn default() -> Self {
         let mut storage = FakeStorage::default();
-        let mut block_alloc = FakeAllocator::default();
+        let block_alloc = FakeAllocator::default();
         let root_addr = block_alloc
             .allocate(1)
             .expect("must be able to allocate root");
-        let mut block = Block::default();
-        Leaf::format(&mut block, 0);
-        storage
-            .write_at(&block, root_addr)
-            .expect("must be able to write root");
+        Tree::format(&mut storage, root_addr).expect("must be able to write root");
         Self {
             storage,
             block_alloc,
@@ -215,6 +236,7 @@ impl StateMachineTest for TreeState {
                 let data = state.get(key).expect("retrieval failed");
                 assert_eq!(data, ref_data);
             }
+            Transition::Commit => state.block_alloc.commit(),
         }
         state
     }
@@ -227,6 +249,10 @@ impl StateMachineTest for TreeState {
             let data = state.get(*key).unwrap();
             assert_eq!(data.as_ref(), Some(ref_data));
         }
+
+        let check = Tree::check(&state.storage, state.root_addr);
+        assert_eq!(check.problems, []);
+        assert_eq!(check.items.len(), ref_state.len());
     }
 }
 
@@ -299,7 +325,7 @@ fn insert_many_sorted() {
 
     for &key in &keys {
         let got_data = state.get(key).unwrap();
-        assert_eq!(got_data.as_deref(), Some(data.as_ref()), "{:?}", &state);
+        assert_eq!(got_data.as_deref(), Some(data.as_ref()), "{:?}", state);
     }
 }
 
@@ -316,7 +342,7 @@ fn insert_many_reverse() {
 
     for &key in &keys {
         let got_data = state.get(key).unwrap();
-        assert_eq!(got_data.as_deref(), Some(data.as_ref()), "{:?}", &state);
+        assert_eq!(got_data.as_deref(), Some(data.as_ref()), "{:?}", state);
     }
 }
 
@@ -360,3 +386,139 @@ fn remove_many_reverse() {
         assert_eq!(got_data, None);
     }
 }
+
+#[test]
+fn never_overwrites_reachable_blocks() {
+    let mut state = TreeState::default();
+    let keys = keys![0..MANY_COUNT as u64];
+    let data = [0xAB; DATA_MAX_LEN];
+
+    for &key in &keys {
+        state.insert(key, &data).unwrap();
+    }
+    state.block_alloc.commit();
+
+    let old_root_addr = state.root_addr;
+    let old_blocks = state.reachable_blocks();
+
+    state.insert(key!(MANY_COUNT as u64), &data).unwrap();
+    state.remove(keys[0]).unwrap();
+    assert_ne!(state.root_addr, old_root_addr);
+
+    for (addr, old_block) in old_blocks {
+        let mut block = Block::default();
+        state.storage.read_at(&mut block, addr).unwrap();
+        assert_eq!(block, old_block);
+    }
+
+    // The old root still describes the tree as it was
+    let got_data = Tree::get(&state.storage, old_root_addr, keys[0]).unwrap();
+    assert_eq!(got_data.as_deref(), Some(data.as_ref()));
+}
+
+#[test]
+fn rewrites_fresh_nodes_in_place() {
+    let mut state = TreeState::default();
+    let keys = keys![0..MANY_COUNT as u64];
+    let data = [0xAB; DATA_MAX_LEN];
+
+    for &key in &keys {
+        state.insert(key, &data).unwrap();
+    }
+    state.block_alloc.commit();
+
+    // The first removal relocates the path to the leaf, which the second one rewrites in place
+    state.remove(keys[0]).unwrap();
+    let root_addr = state.root_addr;
+    state.remove(keys[1]).unwrap();
+    assert_eq!(state.root_addr, root_addr);
+    assert_eq!(state.get(keys[1]).unwrap(), None);
+}
+
+#[test]
+fn detects_corruption() {
+    let mut state = TreeState::default();
+    let keys = keys![0..MANY_COUNT as u64];
+    let data = [0xAB; DATA_MAX_LEN];
+
+    for &key in &keys {
+        state.insert(key, &data).unwrap();
+    }
+
+    let (addr, mut block) = state.reachable_blocks()[0];
+    block[BLOCK_SIZE as usize - 1] ^= 1;
+    state.storage.write_at(&block, addr).unwrap();
+
+    let corrupted = keys
+        .iter()
+        .any(|&key| matches!(state.get(key), Err(Error::Checksum)));
+    assert!(corrupted);
+
+    let check = Tree::check(&state.storage, state.root_addr);
+    assert_eq!(check.problems, [check::Problem::BadChecksum(addr)]);
+}
+
+#[test]
+fn detects_misdirected_write() {
+    let mut state = TreeState::default();
+    state.insert(key!(), b"foo").unwrap();
+
+    let mut block = Block::default();
+    state.storage.read_at(&mut block, state.root_addr).unwrap();
+    let addr = state.block_alloc.allocate(1).unwrap();
+    state.storage.write_at(&block, addr).unwrap();
+
+    assert!(matches!(
+        Tree::get(&state.storage, addr, key!()),
+        Err(Error::Checksum)
+    ));
+}
+
+/// Rewrites the root node with `f` applied, keeping its checksum valid.
+fn tamper_root(state: &TreeState, f: impl FnOnce(&mut Block)) {
+    let mut block = Block::default();
+    state.storage.read_at(&mut block, state.root_addr).unwrap();
+    f(&mut block);
+    node::seal(&mut block, state.root_addr);
+    state.storage.write_at(&block, state.root_addr).unwrap();
+}
+
+#[test]
+fn rejects_empty_branch() {
+    let mut state = TreeState::default();
+    let keys = keys![0..MANY_COUNT as u64];
+    for &key in &keys {
+        state.insert(key, &[0xAB; DATA_MAX_LEN]).unwrap();
+    }
+
+    tamper_root(&state, |block| {
+        let mut root = Branch::try_new(block).unwrap();
+        while root.item_count() > 0 {
+            root.remove_at(0);
+        }
+    });
+
+    assert!(matches!(state.get(keys[0]), Err(Error::Uninterpretable)));
+    assert!(matches!(state.remove(keys[0]), Err(Error::Uninterpretable)));
+}
+
+#[test]
+fn rejects_cycles() {
+    let mut state = TreeState::default();
+    let keys = keys![0..MANY_COUNT as u64];
+    for &key in &keys {
+        state.insert(key, &[0xAB; DATA_MAX_LEN]).unwrap();
+    }
+
+    let root_addr = state.root_addr;
+    tamper_root(&state, |block| {
+        let mut root = Branch::try_new(block).unwrap();
+        root.set_child_at(0, root_addr);
+    });
+
+    assert!(matches!(state.get(keys[0]), Err(Error::Uninterpretable)));
+    assert!(matches!(
+        state.insert(keys[0], b"foo"),
+        Err(Error::Uninterpretable)
+    ));
+}
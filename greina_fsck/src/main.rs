@@ -0,0 +1,195 @@
+use greina_core::{
+    block::storage::file::FileStorage,
+    fs::{
+        Filesystem, MountOptions,
+        check::{self, Problem},
+    },
+};
+
+/// The filesystem is consistent.
+const EXIT_CLEAN: i32 = 0;
+/// Problems were found and all of them were repaired.
+const EXIT_REPAIRED: i32 = 1;
+/// Problems were found and some of them remain.
+const EXIT_UNREPAIRED: i32 = 4;
+/// The check couldn't be completed.
+const EXIT_ERROR: i32 = 8;
+
+fn usage() -> ! {
+    eprintln!("fsck.greina [--repair] [--trim] device");
+    std::process::exit(EXIT_ERROR);
+}
+
+fn fail(msg: &str, errno: i32) -> ! {
+    eprintln!(
+        "fsck.greina: {}: {}",
+        msg,
+        std::io::Error::from_raw_os_error(errno)
+    );
+    std::process::exit(EXIT_ERROR);
+}
+
+fn main() {
+    let mut repair = false;
+    let mut trim = false;
+    let mut storage_path = None;
+    let args = std::env::args().skip(1);
+    for arg in args {
+        if arg == "--repair" {
+            repair = true;
+        } else if arg == "--trim" {
+            trim = true;
+        } else if storage_path.is_none() {
+            storage_path = Some(arg);
+        } else {
+            eprintln!("fsck.greina: too many arguments");
+            usage();
+        }
+    }
+
+    let storage_path = if let Some(path) = storage_path {
+        path
+    } else {
+        eprintln!("fsck.greina: no device specified");
+        usage();
+    };
+
+    let mut code = if repair {
+        repair_fs(&storage_path)
+    } else {
+        check_fs(open_storage(&storage_path, trim), &storage_path)
+    };
+    // Free blocks are only known for sure on a consistent filesystem
+    if trim && (code == EXIT_CLEAN || code == EXIT_REPAIRED) && !trim_fs(&storage_path) {
+        code = EXIT_ERROR;
+    }
+    std::process::exit(code);
+}
+
+fn check_fs(storage: FileStorage, storage_path: &str) -> i32 {
+    let problems = match check::check(&storage) {
+        Ok(problems) => problems,
+        Err(e) => fail(
+            &format!("failed to check filesystem on {}", storage_path),
+            e.into(),
+        ),
+    };
+
+    for problem in &problems {
+        println!("{}", problem);
+    }
+
+    if problems.is_empty() {
+        eprintln!("fsck.greina: filesystem on {} is clean", storage_path);
+        EXIT_CLEAN
+    } else {
+        eprintln!(
+            "fsck.greina: found {} problems on {}",
+            problems.len(),
+            storage_path
+        );
+        EXIT_UNREPAIRED
+    }
+}
+
+/// Opens the device, exclusively when it will be written to by a repair or trim, so that a mounted
+/// one is left alone.
+fn open_storage(storage_path: &str, exclusive: bool) -> FileStorage {
+    let storage = if exclusive {
+        FileStorage::open_exclusive(storage_path)
+    } else {
+        FileStorage::open(storage_path)
+    };
+    match storage {
+        Ok(storage) => storage,
+        Err(e) => fail(&format!("failed to open device {}", storage_path), e),
+    }
+}
+
+/// Discards the free blocks, returning whether it succeeded.
+fn trim_fs(storage_path: &str) -> bool {
+    let fs = match Filesystem::open(open_storage(storage_path, true)) {
+        Ok(fs) => fs,
+        Err(e) => fail(&format!("failed to open filesystem on {}", storage_path), e),
+    };
+    match fs.trim() {
+        Ok(count) => {
+            eprintln!(
+                "fsck.greina: discarded {} free blocks on {}",
+                count, storage_path
+            );
+            true
+        }
+        Err(e) => {
+            eprintln!(
+                "fsck.greina: failed to discard free blocks on {}: {}",
+                storage_path,
+                std::io::Error::from_raw_os_error(e)
+            );
+            false
+        }
+    }
+}
+
+fn repair_fs(storage_path: &str) -> i32 {
+    // Only fall back to a backup superblock if the primary one is invalid, to report it
+    let (mut fs, recovered) = match Filesystem::open(open_storage(storage_path, true)) {
+        Ok(fs) => (fs, false),
+        Err(libc::EINVAL) => {
+            let options = MountOptions {
+                backup_superblock: true,
+                ..Default::default()
+            };
+            match Filesystem::open_with(open_storage(storage_path, true), &options) {
+                Ok(fs) => (fs, true),
+                Err(e) => fail(&format!("failed to open filesystem on {}", storage_path), e),
+            }
+        }
+        Err(e) => fail(&format!("failed to open filesystem on {}", storage_path), e),
+    };
+
+    let mut repair = match check::repair(&mut fs) {
+        Ok(repair) => repair,
+        Err(e) => fail(
+            &format!("failed to repair filesystem on {}", storage_path),
+            e.into(),
+        ),
+    };
+    if recovered {
+        repair.found.insert(0, Problem::BadSuperblock);
+    }
+
+    for problem in &repair.found {
+        if repair.remaining.contains(problem) {
+            println!("{}", problem);
+        } else {
+            println!("{} (repaired)", problem);
+        }
+    }
+
+    if repair.found.is_empty() {
+        eprintln!("fsck.greina: filesystem on {} is clean", storage_path);
+        EXIT_CLEAN
+    } else if repair.remaining.is_empty() {
+        eprintln!(
+            "fsck.greina: repaired {} problems on {}",
+            repair.found.len(),
+            storage_path
+        );
+        EXIT_REPAIRED
+    } else {
+        for problem in repair
+            .remaining
+            .iter()
+            .filter(|problem| !repair.found.contains(problem))
+        {
+            println!("{}", problem);
+        }
+        eprintln!(
+            "fsck.greina: {} problems remain on {}",
+            repair.remaining.len(),
+            storage_path
+        );
+        EXIT_UNREPAIRED
+    }
+}
@@ -1,15 +1,47 @@
-use greina_core::{block::storage::file::FileStorage, fs::Filesystem};
+use greina_core::{
+    block::storage::file::FileStorage,
+    fs::{Filesystem, FormatOptions},
+};
 
 fn usage() -> ! {
-    eprintln!("mkfs.greina device");
+    eprintln!("mkfs.greina [--data-checksums] [--label label] [--root-owner uid:gid] device");
     std::process::exit(1);
 }
 
+/// Parses a `uid:gid` pair.
+fn parse_owner(owner: &str) -> Option<(u32, u32)> {
+    let (uid, gid) = owner.split_once(':')?;
+    Some((uid.parse().ok()?, gid.parse().ok()?))
+}
+
 fn main() {
+    let mut options = FormatOptions::default();
+    // The root directory belongs to whoever formats the filesystem, unless told otherwise
+    unsafe {
+        options.root_uid = libc::getuid();
+        options.root_gid = libc::getgid();
+    }
     let mut storage_path = None;
-    let args = std::env::args().skip(1);
-    for arg in args {
-        if storage_path.is_none() {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--data-checksums" {
+            options.data_checksums = true;
+        } else if arg == "--label" {
+            if let Some(label) = args.next() {
+                options.label = label;
+            } else {
+                eprintln!("mkfs.greina: no label specified");
+                usage();
+            }
+        } else if arg == "--root-owner" {
+            if let Some((uid, gid)) = args.next().as_deref().and_then(parse_owner) {
+                options.root_uid = uid;
+                options.root_gid = gid;
+            } else {
+                eprintln!("mkfs.greina: root owner must be given as uid:gid");
+                usage();
+            }
+        } else if storage_path.is_none() {
             storage_path = Some(arg);
         } else {
             eprintln!("mkfs.greina: too many arguments");
@@ -24,7 +56,8 @@ fn main() {
         std::process::exit(1);
     };
 
-    let storage = match FileStorage::open(&storage_path) {
+    // Refuses devices in use, such as ones mounted already
+    let storage = match FileStorage::open_exclusive(&storage_path) {
         Ok(storage) => storage,
         Err(e) => {
             eprintln!(
@@ -36,10 +69,11 @@ fn main() {
         }
     };
 
-    match Filesystem::format(storage) {
+    match Filesystem::format_with(storage, &options) {
         Ok(fs) => {
             eprintln!(
-                "mkfs.greina: created filesystem on {} with {} blocks",
+                "mkfs.greina: created filesystem {} on {} with {} blocks",
+                fs.superblock().uuid,
                 storage_path,
                 fs.superblock().block_count
             );
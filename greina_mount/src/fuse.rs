@@ -1,380 +1,610 @@
 use std::{
+    collections::BTreeMap,
     ffi::OsStr,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    os::unix::ffi::OsStrExt,
+    sync::{Mutex, RwLock},
+    time::{Duration, SystemTime},
 };
 
-use fuser::{FileAttr, FileType, Filesystem};
+use fuser::{
+    Errno, FileAttr, FileHandle, FileType, Filesystem, FopenFlags, Generation, INodeNo, LockOwner,
+    OpenFlags, RenameFlags, WriteFlags,
+};
 
-use crate::{
+use greina_core::{
     block::{Allocator, BLOCK_SIZE, storage::Storage},
     fs::{
         self,
-        node::{self, Node, NodeId, dir::NAME_MAX_LEN},
+        node::{
+            self, Node, NodeId, Perms,
+            dir::{NAME_MAX_LEN, RenameMode},
+            time::Timestamp,
+            xattr::SetMode,
+        },
     },
 };
 
 /// How long the kernel should cache node attributes
 const TTL: Duration = Duration::from_secs(1);
 
+/// References the kernel holds to a node.
+#[derive(Default)]
+struct Refs {
+    // How many times the node was looked up and not yet forgotten
+    lookups: u64,
+    // How many open handles to the node there are
+    opens: u64,
+}
+
 pub struct Fuse<S: Storage> {
-    fs: fs::Filesystem<S>,
+    fs: RwLock<fs::Filesystem<S>>,
+    refs: Mutex<BTreeMap<NodeId, Refs>>,
 }
 
 impl<S: Storage> Fuse<S> {
     pub fn new(fs: fs::Filesystem<S>) -> Self {
-        Self { fs }
+        Self {
+            fs: RwLock::new(fs),
+            refs: Default::default(),
+        }
+    }
+
+    /// Records references the kernel acquired to a node.
+    fn acquire_refs(&self, id: NodeId, lookups: u64, opens: u64) {
+        let mut refs = self.refs.lock().unwrap();
+        let node_refs = refs.entry(id).or_default();
+        node_refs.lookups += lookups;
+        node_refs.opens += opens;
+    }
+
+    /// Forgets references recorded for a node the kernel never learned of, as the transaction
+    /// handing it out failed.
+    fn undo_refs(&self, id: NodeId, lookups: u64, opens: u64) {
+        let mut refs = self.refs.lock().unwrap();
+        if let Some(node_refs) = refs.get_mut(&id) {
+            node_refs.lookups = node_refs.lookups.saturating_sub(lookups);
+            node_refs.opens = node_refs.opens.saturating_sub(opens);
+            if node_refs.lookups == 0 && node_refs.opens == 0 {
+                refs.remove(&id);
+            }
+        }
+    }
+
+    /// Records references the kernel dropped to a node.
+    /// An orphan is removed once its last reference is dropped.
+    fn release_refs(&self, id: NodeId, lookups: u64, opens: u64) -> fs::error::Result<()> {
+        {
+            let mut refs = self.refs.lock().unwrap();
+            let Some(node_refs) = refs.get_mut(&id) else {
+                return Ok(());
+            };
+            node_refs.lookups = node_refs.lookups.saturating_sub(lookups);
+            node_refs.opens = node_refs.opens.saturating_sub(opens);
+            if node_refs.lookups > 0 || node_refs.opens > 0 {
+                return Ok(());
+            }
+            refs.remove(&id);
+        }
+
+        if self.read_tx(|tx| tx.is_orphan(id))? {
+            self.tx(|tx| tx.remove_orphan(id))?;
+        }
+        Ok(())
+    }
+
+    fn is_referenced(&self, id: NodeId) -> bool {
+        self.refs.lock().unwrap().contains_key(&id)
+    }
+
+    /// Removes a node left without links right away, unless the kernel still references it.
+    fn remove_unreferenced(
+        &self,
+        tx: &mut fs::transaction::Transaction<S>,
+        orphan: Option<NodeId>,
+    ) -> fs::error::Result<()> {
+        match orphan {
+            Some(id) if !self.is_referenced(id) => tx.remove_orphan(id),
+            _ => Ok(()),
+        }
+    }
+
+    /// Executes a transaction on the filesystem, see [`fs::Filesystem::tx`].
+    fn tx<F, T>(&self, f: F) -> fs::error::Result<T>
+    where
+        F: FnOnce(&mut fs::transaction::Transaction<S>) -> fs::error::Result<T>,
+    {
+        self.fs.write().unwrap().tx(f)
+    }
+
+    /// Executes a transaction handing a node out to the kernel, recording the references it
+    /// acquires while the filesystem is still locked, so that a concurrent unlink can't remove
+    /// the node in between. The references are undone if the transaction fails.
+    fn tx_acquiring<F>(&self, lookups: u64, opens: u64, f: F) -> fs::error::Result<(NodeId, Node)>
+    where
+        F: FnOnce(&mut fs::transaction::Transaction<S>) -> fs::error::Result<(NodeId, Node)>,
+    {
+        let mut acquired = None;
+        let res = self.tx(|tx| {
+            let (node_id, node) = f(tx)?;
+            self.acquire_refs(node_id, lookups, opens);
+            acquired = Some(node_id);
+            Ok((node_id, node))
+        });
+        if res.is_err()
+            && let Some(node_id) = acquired
+        {
+            self.undo_refs(node_id, lookups, opens);
+        }
+        res
+    }
+
+    /// Executes a read-only transaction on the filesystem, see [`fs::Filesystem::read_tx`].
+    fn read_tx<F, T>(&self, f: F) -> fs::error::Result<T>
+    where
+        F: FnOnce(&fs::transaction::ReadTransaction<S>) -> fs::error::Result<T>,
+    {
+        self.fs.read().unwrap().read_tx(f)
+    }
+
+    /// Waits for the filesystem's writes to reach the device, see [`fs::Filesystem::flush`].
+    fn sync(&self, reply: fuser::ReplyEmpty) {
+        match self.fs.read().unwrap().flush() {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(Errno::from_i32(e)),
+        }
     }
 }
 
-impl<S: Storage> Filesystem for Fuse<S> {
+impl<S: Storage + Send + Sync + 'static> Filesystem for Fuse<S> {
     fn init(
         &mut self,
-        _req: &fuser::Request<'_>,
+        _req: &fuser::Request,
         _config: &mut fuser::KernelConfig,
-    ) -> Result<(), libc::c_int> {
+    ) -> std::io::Result<()> {
         Ok(())
     }
 
-    fn destroy(&mut self) {}
+    fn destroy(&mut self) {
+        if let Err(e) = self.fs.get_mut().unwrap().flush() {
+            eprintln!(
+                "mount.greina: failed to flush the device: {}",
+                std::io::Error::from_raw_os_error(e)
+            );
+        }
+    }
 
     fn lookup(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        parent: u64,
+        &self,
+        _req: &fuser::Request,
+        parent: INodeNo,
         name: &OsStr,
         reply: fuser::ReplyEntry,
     ) {
-        let parent_id = NodeId::new(parent);
+        let parent_id = NodeId::new(parent.0);
         let name = match name.to_str() {
             Some(name) => name,
-            None => return reply.error(libc::EILSEQ),
+            None => return reply.error(Errno::EILSEQ),
         };
-        let res = self.fs.tx(|tx| {
+        let res = self.read_tx(|tx| {
             let entry = tx.find_entry(parent_id, name)?;
             let node_id = entry.id;
             let node = tx.read_node(node_id)?;
+            // Recorded under the lock, so that the node isn't removed before the reply
+            self.acquire_refs(node_id, 1, 0);
             Ok((node_id, node))
         });
         match res {
-            Ok((node_id, node)) => reply.entry(&TTL, &node_attr(node_id, &node), 0),
-            Err(e) => reply.error(e.into()),
+            Ok((node_id, node)) => reply.entry(&TTL, &node_attr(node_id, &node), Generation(0)),
+            Err(e) => reply.error(errno(e)),
         }
     }
 
+    fn forget(&self, _req: &fuser::Request, ino: INodeNo, nlookup: u64) {
+        // Nothing can be replied, the orphan is removed at the next mount on failure
+        let _ = self.release_refs(NodeId::new(ino.0), nlookup, 0);
+    }
+
     fn getattr(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        ino: u64,
-        _fh: Option<u64>,
+        &self,
+        _req: &fuser::Request,
+        ino: INodeNo,
+        _fh: Option<FileHandle>,
         reply: fuser::ReplyAttr,
     ) {
-        let node_id = NodeId::new(ino);
-        let res = self.fs.tx(|tx| tx.read_node(node_id));
+        let node_id = NodeId::new(ino.0);
+        let res = self.read_tx(|tx| tx.read_node(node_id));
         match res {
             Ok(node) => reply.attr(&TTL, &node_attr(node_id, &node)),
-            Err(e) => reply.error(e.into()),
+            Err(e) => reply.error(errno(e)),
         }
     }
 
     fn setattr(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        ino: u64,
-        _mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
+        &self,
+        _req: &fuser::Request,
+        ino: INodeNo,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
         size: Option<u64>,
-        _atime: Option<fuser::TimeOrNow>,
-        _mtime: Option<fuser::TimeOrNow>,
+        atime: Option<fuser::TimeOrNow>,
+        mtime: Option<fuser::TimeOrNow>,
         _ctime: Option<SystemTime>,
-        _fh: Option<u64>,
+        _fh: Option<FileHandle>,
         _crtime: Option<SystemTime>,
         _chgtime: Option<SystemTime>,
         _bkuptime: Option<SystemTime>,
-        _flags: Option<u32>,
+        _flags: Option<fuser::BsdFileFlags>,
         reply: fuser::ReplyAttr,
     ) {
-        let node_id = NodeId::new(ino);
-        let res = self.fs.tx(|tx| {
+        let node_id = NodeId::new(ino.0);
+        let res = self.tx(|tx| {
+            if mode.is_some() || uid.is_some() || gid.is_some() {
+                let mode = mode.map(|mode| mode as u16);
+                tx.set_node_perms(node_id, mode, uid, gid)?;
+            }
             if let Some(size) = size {
                 tx.truncate_file(node_id, size)?;
             }
+            if atime.is_some() || mtime.is_some() {
+                tx.set_node_times(node_id, atime.map(timestamp), mtime.map(timestamp))?;
+            }
             let node = tx.read_node(node_id)?;
             Ok(node)
         });
 
         match res {
             Ok(node) => reply.attr(&TTL, &node_attr(node_id, &node)),
-            Err(e) => reply.error(e.into()),
+            Err(e) => reply.error(errno(e)),
         }
     }
 
     fn mkdir(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        parent: u64,
+        &self,
+        req: &fuser::Request,
+        parent: INodeNo,
         name: &OsStr,
-        _mode: u32,
-        _umask: u32,
+        mode: u32,
+        umask: u32,
         reply: fuser::ReplyEntry,
     ) {
-        let parent_id = NodeId::new(parent);
+        let parent_id = NodeId::new(parent.0);
 
         let name = match name.to_str() {
             Some(name) => name,
-            None => return reply.error(libc::EILSEQ),
+            None => return reply.error(Errno::EILSEQ),
         };
 
-        let res = self.fs.tx(|tx| {
-            let node_id = tx.create_dir(parent_id, name)?;
+        let res = self.tx_acquiring(1, 0, |tx| {
+            let node_id = tx.create_dir(parent_id, name, perms(req, mode, umask))?;
             let node = tx.read_node(node_id)?;
             Ok((node_id, node))
         });
 
         match res {
-            Ok((node_id, node)) => reply.entry(&TTL, &node_attr(node_id, &node), 0),
-            Err(e) => reply.error(e.into()),
+            Ok((node_id, node)) => reply.entry(&TTL, &node_attr(node_id, &node), Generation(0)),
+            Err(e) => reply.error(errno(e)),
         }
     }
 
     fn rmdir(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        parent: u64,
+        &self,
+        _req: &fuser::Request,
+        parent: INodeNo,
         name: &OsStr,
         reply: fuser::ReplyEmpty,
     ) {
-        let parent_id = NodeId::new(parent);
+        let parent_id = NodeId::new(parent.0);
         let name = match name.to_str() {
             Some(name) => name,
-            None => return reply.error(libc::EILSEQ),
+            None => return reply.error(Errno::EILSEQ),
         };
-        let res = self.fs.tx(|tx| {
+        let res = self.tx(|tx| {
             tx.remove_dir(parent_id, name)?;
             Ok(())
         });
         match res {
             Ok(()) => reply.ok(),
-            Err(e) => reply.error(e.into()),
+            Err(e) => reply.error(errno(e)),
         }
     }
 
     fn symlink(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        parent: u64,
+        &self,
+        req: &fuser::Request,
+        parent: INodeNo,
         link_name: &OsStr,
         target: &std::path::Path,
         reply: fuser::ReplyEntry,
     ) {
-        let parent_id = NodeId::new(parent);
+        let parent_id = NodeId::new(parent.0);
         let name = match link_name.to_str() {
             Some(name) => name,
-            None => return reply.error(libc::EILSEQ),
+            None => return reply.error(Errno::EILSEQ),
         };
         let target = match target.to_str() {
             Some(target) => target,
-            None => return reply.error(libc::EILSEQ),
+            None => return reply.error(Errno::EILSEQ),
         };
 
-        let res = self.fs.tx(|tx| {
-            let node_id = tx.create_symlink(parent_id, name, target)?;
+        let res = self.tx_acquiring(1, 0, |tx| {
+            // Symlink permissions aren't used for access checks
+            let perms = perms(req, 0o777, 0);
+            let node_id = tx.create_symlink(parent_id, name, target, perms)?;
             let node = tx.read_node(node_id)?;
             Ok((node_id, node))
         });
 
         match res {
-            Ok((node_id, node)) => reply.entry(&TTL, &node_attr(node_id, &node), 0),
-            Err(e) => reply.error(e.into()),
+            Ok((node_id, node)) => reply.entry(&TTL, &node_attr(node_id, &node), Generation(0)),
+            Err(e) => reply.error(errno(e)),
         }
     }
 
-    fn readlink(&mut self, _req: &fuser::Request<'_>, ino: u64, reply: fuser::ReplyData) {
-        let symlink_id = NodeId::new(ino);
-        let res = self.fs.tx(|tx| tx.read_symlink(symlink_id));
+    fn readlink(&self, _req: &fuser::Request, ino: INodeNo, reply: fuser::ReplyData) {
+        let symlink_id = NodeId::new(ino.0);
+        let res = self.read_tx(|tx| tx.read_symlink(symlink_id));
         match res {
             Ok(path) => reply.data(&path),
-            Err(e) => reply.error(e.into()),
+            Err(e) => reply.error(errno(e)),
         }
     }
 
     fn link(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        ino: u64,
-        newparent: u64,
+        &self,
+        _req: &fuser::Request,
+        ino: INodeNo,
+        newparent: INodeNo,
         newname: &OsStr,
         reply: fuser::ReplyEntry,
     ) {
-        let node_id = NodeId::new(ino);
-        let parent_id = NodeId::new(newparent);
+        let node_id = NodeId::new(ino.0);
+        let parent_id = NodeId::new(newparent.0);
         let name = match newname.to_str() {
             Some(name) => name,
-            None => return reply.error(libc::EILSEQ),
+            None => return reply.error(Errno::EILSEQ),
         };
 
-        let res = self.fs.tx(|tx| {
+        let res = self.tx_acquiring(1, 0, |tx| {
             tx.link_file(parent_id, node_id, name)?;
             let node = tx.read_node(node_id)?;
             Ok((node_id, node))
         });
 
         match res {
-            Ok((node_id, node)) => reply.entry(&TTL, &node_attr(node_id, &node), 0),
-            Err(e) => reply.error(e.into()),
+            Ok((node_id, node)) => reply.entry(&TTL, &node_attr(node_id, &node), Generation(0)),
+            Err(e) => reply.error(errno(e)),
         }
     }
 
     fn unlink(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        parent: u64,
+        &self,
+        _req: &fuser::Request,
+        parent: INodeNo,
         name: &OsStr,
         reply: fuser::ReplyEmpty,
     ) {
-        let parent_id = NodeId::new(parent);
+        let parent_id = NodeId::new(parent.0);
         let name = match name.to_str() {
             Some(name) => name,
-            None => return reply.error(libc::EILSEQ),
+            None => return reply.error(Errno::EILSEQ),
         };
-        let res = self.fs.tx(|tx| tx.unlink_file(parent_id, name));
+        let res = self.tx(|tx| {
+            let orphan = tx.unlink_file(parent_id, name)?;
+            self.remove_unreferenced(tx, orphan)
+        });
         match res {
             Ok(()) => reply.ok(),
-            Err(e) => reply.error(e.into()),
+            Err(e) => reply.error(errno(e)),
         }
     }
 
     fn rename(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        parent: u64,
+        &self,
+        _req: &fuser::Request,
+        parent: INodeNo,
         name: &OsStr,
-        newparent: u64,
+        newparent: INodeNo,
         newname: &OsStr,
-        _flags: u32,
+        flags: RenameFlags,
         reply: fuser::ReplyEmpty,
     ) {
-        let old_parent_id = NodeId::new(parent);
+        let mode = if flags.is_empty() {
+            RenameMode::Replace
+        } else if flags == RenameFlags::RENAME_NOREPLACE {
+            RenameMode::NoReplace
+        } else if flags == RenameFlags::RENAME_EXCHANGE {
+            RenameMode::Exchange
+        } else {
+            return reply.error(Errno::EINVAL);
+        };
+
+        let old_parent_id = NodeId::new(parent.0);
         let old_name = match name.to_str() {
             Some(name) => name,
-            None => return reply.error(libc::EILSEQ),
+            None => return reply.error(Errno::EILSEQ),
         };
 
-        let new_parent_id = NodeId::new(newparent);
+        let new_parent_id = NodeId::new(newparent.0);
         let new_name = match newname.to_str() {
             Some(name) => name,
-            None => return reply.error(libc::EILSEQ),
+            None => return reply.error(Errno::EILSEQ),
         };
 
-        let res = self
-            .fs
-            .tx(|tx| tx.rename_entry(old_parent_id, old_name, new_parent_id, new_name));
+        let res = self.tx(|tx| {
+            let orphan = tx.rename_entry(old_parent_id, old_name, new_parent_id, new_name, mode)?;
+            self.remove_unreferenced(tx, orphan)
+        });
 
         match res {
             Ok(()) => reply.ok(),
-            Err(e) => reply.error(e.into()),
+            Err(e) => reply.error(errno(e)),
         }
     }
 
     fn create(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        parent: u64,
+        &self,
+        req: &fuser::Request,
+        parent: INodeNo,
         name: &OsStr,
         mode: u32,
-        _umask: u32,
+        umask: u32,
         _flags: i32,
         reply: fuser::ReplyCreate,
     ) {
-        let parent_id = NodeId::new(parent);
+        let parent_id = NodeId::new(parent.0);
 
         let name = match name.to_str() {
             Some(name) => name,
-            None => return reply.error(libc::EILSEQ),
+            None => return reply.error(Errno::EILSEQ),
         };
 
-        let file_type = match node::FileType::try_from(mode) {
+        let file_type = match mode_file_type(mode) {
             Ok(ft) => ft,
             Err(e) => return reply.error(e),
         };
         match file_type {
             node::FileType::File => (),
-            _ => return reply.error(libc::EINVAL),
+            _ => return reply.error(Errno::EINVAL),
         }
 
-        let res = self.fs.tx(|tx| {
-            let node_id = tx.create_file(parent_id, name, file_type)?;
+        let res = self.tx_acquiring(1, 1, |tx| {
+            let perms = perms(req, mode, umask);
+            let node_id = tx.create_file(parent_id, name, file_type, perms)?;
             let node = tx.read_node(node_id)?;
             Ok((node_id, node))
         });
 
         match res {
-            Ok((node_id, node)) => reply.created(&TTL, &node_attr(node_id, &node), 0, 0, 0),
-            Err(e) => reply.error(e.into()),
+            Ok((node_id, node)) => reply.created(
+                &TTL,
+                &node_attr(node_id, &node),
+                Generation(0),
+                FileHandle(0),
+                FopenFlags::empty(),
+            ),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn open(
+        &self,
+        _req: &fuser::Request,
+        ino: INodeNo,
+        _flags: OpenFlags,
+        reply: fuser::ReplyOpen,
+    ) {
+        self.acquire_refs(NodeId::new(ino.0), 0, 1);
+        reply.opened(FileHandle(0), FopenFlags::empty());
+    }
+
+    fn flush(
+        &self,
+        _req: &fuser::Request,
+        _ino: INodeNo,
+        _fh: FileHandle,
+        _lock_owner: LockOwner,
+        reply: fuser::ReplyEmpty,
+    ) {
+        // Writes are committed as they're made, so closing a file has nothing left to write
+        reply.ok();
+    }
+
+    fn fsync(
+        &self,
+        _req: &fuser::Request,
+        _ino: INodeNo,
+        _fh: FileHandle,
+        _datasync: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.sync(reply);
+    }
+
+    fn release(
+        &self,
+        _req: &fuser::Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        match self.release_refs(NodeId::new(ino.0), 0, 1) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(e)),
         }
     }
 
     fn read(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
+        &self,
+        _req: &fuser::Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
         size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
         reply: fuser::ReplyData,
     ) {
-        let node_id = NodeId::new(ino);
+        let node_id = NodeId::new(ino.0);
         let mut buf = vec![0u8; size as usize];
-        let res = self
-            .fs
-            .tx(|tx| tx.read_file_at(node_id, offset as u64, &mut buf));
+        let res = self.read_tx(|tx| tx.read_file_at(node_id, offset, &mut buf));
         match res {
             Ok(read) => reply.data(&buf[..read as usize]),
-            Err(e) => reply.error(e.into()),
+            Err(e) => reply.error(errno(e)),
         }
     }
 
     fn write(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
+        &self,
+        _req: &fuser::Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
         data: &[u8],
-        _write_flags: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
+        _write_flags: WriteFlags,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
         reply: fuser::ReplyWrite,
     ) {
-        let node_id = NodeId::new(ino);
-        let res = self
-            .fs
-            .tx(|tx| tx.write_file_at(node_id, offset as u64, data));
+        let node_id = NodeId::new(ino.0);
+        let res = self.tx(|tx| tx.write_file_at(node_id, offset, data));
         match res {
             Ok(written) => reply.written(written as u32),
-            Err(e) => reply.error(e.into()),
+            Err(e) => reply.error(errno(e)),
         }
     }
 
+    fn fsyncdir(
+        &self,
+        _req: &fuser::Request,
+        _ino: INodeNo,
+        _fh: FileHandle,
+        _datasync: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.sync(reply);
+    }
+
     fn readdir(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
+        &self,
+        _req: &fuser::Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
         mut reply: fuser::ReplyDirectory,
     ) {
-        let node_id = NodeId::new(ino);
-        let res = self.fs.tx(|tx| tx.read_dir(node_id));
+        let node_id = NodeId::new(ino.0);
+        let res = self.read_tx(|tx| tx.read_dir(node_id));
         match res {
             Ok(dir) => {
                 for (i, entry) in dir.iter().enumerate().skip(offset as usize) {
                     let is_full = reply.add(
-                        entry.id.get(),
-                        (i + 1) as i64,
-                        entry.filetype.into(),
+                        INodeNo(entry.id.get()),
+                        (i + 1) as u64,
+                        file_type(entry.filetype),
                         entry.name.as_str(),
                     );
                     if is_full {
@@ -383,13 +613,86 @@ impl<S: Storage> Filesystem for Fuse<S> {
                 }
                 reply.ok();
             }
-            Err(e) => reply.error(e.into()),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn setxattr(
+        &self,
+        _req: &fuser::Request,
+        ino: INodeNo,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let node_id = NodeId::new(ino.0);
+        let mode = match flags {
+            0 => SetMode::Upsert,
+            libc::XATTR_CREATE => SetMode::Create,
+            libc::XATTR_REPLACE => SetMode::Replace,
+            _ => return reply.error(Errno::EINVAL),
+        };
+        let res = self.tx(|tx| tx.set_xattr(node_id, name.as_bytes(), value, mode));
+        match res {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(e)),
         }
     }
 
-    fn statfs(&mut self, _req: &fuser::Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
-        let blocks = self.fs.superblock().block_count;
-        let blocks_free = self.fs.block_alloc().available();
+    fn getxattr(
+        &self,
+        _req: &fuser::Request,
+        ino: INodeNo,
+        name: &OsStr,
+        size: u32,
+        reply: fuser::ReplyXattr,
+    ) {
+        let node_id = NodeId::new(ino.0);
+        let res = self.read_tx(|tx| tx.get_xattr(node_id, name.as_bytes()));
+        match res {
+            Ok(value) => reply_xattr(reply, size, &value),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn listxattr(&self, _req: &fuser::Request, ino: INodeNo, size: u32, reply: fuser::ReplyXattr) {
+        let node_id = NodeId::new(ino.0);
+        let res = self.read_tx(|tx| tx.list_xattrs(node_id));
+        match res {
+            Ok(names) => {
+                // Names are sent null-terminated, back to back
+                let mut list = Vec::new();
+                for name in names {
+                    list.extend_from_slice(&name);
+                    list.push(0);
+                }
+                reply_xattr(reply, size, &list)
+            }
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn removexattr(
+        &self,
+        _req: &fuser::Request,
+        ino: INodeNo,
+        name: &OsStr,
+        reply: fuser::ReplyEmpty,
+    ) {
+        let node_id = NodeId::new(ino.0);
+        let res = self.tx(|tx| tx.remove_xattr(node_id, name.as_bytes()));
+        match res {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn statfs(&self, _req: &fuser::Request, _ino: INodeNo, reply: fuser::ReplyStatfs) {
+        let fs = self.fs.read().unwrap();
+        let blocks = fs.superblock().block_count;
+        let blocks_free = fs.block_alloc().available();
 
         reply.statfs(
             blocks,
@@ -404,70 +707,87 @@ impl<S: Storage> Filesystem for Fuse<S> {
     }
 }
 
-fn node_attr(node_id: NodeId, node: &Node) -> FileAttr {
-    let perm = match node.filetype {
-        node::FileType::Dir => 0o777,
-        _ => 0o666,
-    };
+fn errno(err: fs::error::Error) -> Errno {
+    Errno::from_i32(err.into())
+}
+
+/// Replies with the size of `data` if `size` is 0, or with `data` if it fits in `size` bytes.
+fn reply_xattr(reply: fuser::ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() <= size as usize {
+        reply.data(data);
+    } else {
+        reply.error(Errno::ERANGE);
+    }
+}
 
+fn node_attr(node_id: NodeId, node: &Node) -> FileAttr {
     FileAttr {
-        ino: node_id.get(),
+        ino: INodeNo(node_id.get()),
         size: node.size.get(),
         blocks: node.size.get().div_ceil(BLOCK_SIZE),
-        atime: UNIX_EPOCH,
-        mtime: UNIX_EPOCH,
-        ctime: UNIX_EPOCH,
-        crtime: UNIX_EPOCH,
-        kind: FileType::from(node.filetype),
-        perm,
+        atime: node.atime.into(),
+        mtime: node.mtime.into(),
+        ctime: node.ctime.into(),
+        crtime: node.crtime.into(),
+        kind: file_type(node.filetype),
+        perm: node.mode.get(),
         nlink: node.links.get(),
-        uid: 0,
-        gid: 0,
+        uid: node.uid.get(),
+        gid: node.gid.get(),
         rdev: 0,
         blksize: BLOCK_SIZE as u32,
         flags: 0,
     }
 }
 
-impl From<node::FileType> for FileType {
-    fn from(value: node::FileType) -> Self {
-        match value {
-            node::FileType::File => Self::RegularFile,
-            node::FileType::Dir => FileType::Directory,
-            node::FileType::Symlink => FileType::Symlink,
-        }
+/// Constructs the permissions of a node created by a request.
+fn perms(req: &fuser::Request, mode: u32, umask: u32) -> Perms {
+    let mode = (mode & !umask) as u16 & Perms::MODE_MASK;
+    Perms::new(mode, req.uid(), req.gid())
+}
+
+/// Converts a FUSE time into a timestamp.
+fn timestamp(time: fuser::TimeOrNow) -> Timestamp {
+    match time {
+        fuser::TimeOrNow::SpecificTime(time) => time.into(),
+        fuser::TimeOrNow::Now => Timestamp::now(),
     }
 }
 
-#[cfg(target_os = "linux")]
-impl TryFrom<u32> for node::FileType {
-    type Error = libc::c_int;
+/// Converts a node's filetype into a FUSE filetype.
+fn file_type(filetype: node::FileType) -> FileType {
+    match filetype {
+        node::FileType::File => FileType::RegularFile,
+        node::FileType::Dir => FileType::Directory,
+        node::FileType::Symlink => FileType::Symlink,
+    }
+}
 
-    fn try_from(mode: u32) -> Result<Self, Self::Error> {
-        let file_type = mode & libc::S_IFMT;
-        match file_type {
-            libc::S_IFREG => Ok(Self::File),
-            libc::S_IFDIR => Ok(Self::Dir),
-            libc::S_IFLNK => Ok(Self::Symlink),
-            _ => Err(libc::EINVAL),
-        }
+/// Extracts a node's filetype from a mode.
+#[cfg(target_os = "linux")]
+fn mode_file_type(mode: u32) -> Result<node::FileType, Errno> {
+    let file_type = mode & libc::S_IFMT;
+    match file_type {
+        libc::S_IFREG => Ok(node::FileType::File),
+        libc::S_IFDIR => Ok(node::FileType::Dir),
+        libc::S_IFLNK => Ok(node::FileType::Symlink),
+        _ => Err(Errno::EINVAL),
     }
 }
 
+/// Extracts a node's filetype from a mode.
 #[cfg(target_os = "macos")]
-impl TryFrom<u32> for node::FileType {
-    type Error = libc::c_int;
-
-    fn try_from(mode: u32) -> Result<Self, Self::Error> {
-        let file_type = mode & libc::S_IFMT as u32;
-        if file_type == (libc::S_IFREG as u32) {
-            Ok(Self::File)
-        } else if file_type == (libc::S_IFDIR as u32) {
-            Ok(Self::Dir)
-        } else if file_type == (libc::S_IFLNK as u32) {
-            Ok(Self::Symlink)
-        } else {
-            Err(libc::EINVAL)
-        }
+fn mode_file_type(mode: u32) -> Result<node::FileType, Errno> {
+    let file_type = mode & libc::S_IFMT as u32;
+    if file_type == (libc::S_IFREG as u32) {
+        Ok(node::FileType::File)
+    } else if file_type == (libc::S_IFDIR as u32) {
+        Ok(node::FileType::Dir)
+    } else if file_type == (libc::S_IFLNK as u32) {
+        Ok(node::FileType::Symlink)
+    } else {
+        Err(Errno::EINVAL)
     }
 }
@@ -1,19 +1,34 @@
-use fuser::{MountOption, spawn_mount2};
-use greina_core::{block::storage::file::FileStorage, fs::Filesystem, fuse::Fuse};
+mod fuse;
+
+use fuser::{Config, MountOption, spawn_mount2};
+use greina_core::{
+    block::storage::{cache::CachedStorage, file::FileStorage},
+    fs::{Filesystem, MountOptions},
+};
+
+use crate::fuse::Fuse;
+
+/// How many blocks are cached in memory, 16 MiB.
+const CACHE_BLOCKS: usize = 4096;
 
 fn usage() -> ! {
-    eprintln!("mount.greina device mountpoint");
+    eprintln!("mount.greina [--backup-superblock] [--discard] device mountpoint");
     std::process::exit(1);
 }
 
 fn main() {
     env_logger::init();
 
+    let mut options = MountOptions::default();
     let mut storage_path = None;
     let mut mount_point = None;
     let args = std::env::args().skip(1);
     for arg in args {
-        if storage_path.is_none() {
+        if arg == "--backup-superblock" {
+            options.backup_superblock = true;
+        } else if arg == "--discard" {
+            options.discard = true;
+        } else if storage_path.is_none() {
             storage_path = Some(arg);
         } else if mount_point.is_none() {
             mount_point = Some(arg);
@@ -37,24 +52,26 @@ fn main() {
         std::process::exit(1);
     };
 
-    let storage = match FileStorage::open(&storage_path) {
+    // Refuses devices in use, such as ones mounted already
+    let storage = match FileStorage::open_exclusive(&storage_path) {
         Ok(storage) => storage,
         Err(e) => {
             eprintln!(
                 "mount.greina: failed to open device {}: {}",
-                &storage_path,
+                storage_path,
                 std::io::Error::from_raw_os_error(e)
             );
             std::process::exit(1);
         }
     };
 
-    let fs = match Filesystem::mount(storage) {
+    let storage = CachedStorage::new(storage, CACHE_BLOCKS);
+    let fs = match Filesystem::mount_with(storage, &options) {
         Ok(fs) => fs,
         Err(e) => {
             eprintln!(
                 "mount.greina: failed to read filesystem from device {}: {}",
-                &storage_path,
+                storage_path,
                 std::io::Error::from_raw_os_error(e)
             );
             std::process::exit(1);
@@ -63,23 +80,24 @@ fn main() {
 
     let fuse = Fuse::new(fs);
 
-    let opts = vec![
+    let mut config = Config::default();
+    config.mount_options = vec![
         MountOption::DefaultPermissions,
         MountOption::FSName("greina".to_string()),
     ];
 
-    let session = match spawn_mount2(fuse, &mount_point, &opts) {
+    let session = match spawn_mount2(fuse, &mount_point, &config) {
         Ok(session) => {
             eprintln!(
                 "mount.greina: mounted filesystem from device {} on mountpoint {}",
-                &storage_path, &mount_point
+                storage_path, mount_point
             );
             session
         }
         Err(e) => {
             eprintln!(
                 "mount.greina: failed to mount filesystem from device {}: {}",
-                &storage_path, e
+                storage_path, e
             );
             std::process::exit(1);
         }
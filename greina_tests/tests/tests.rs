@@ -1,13 +1,16 @@
+use std::ffi::CString;
 use std::fs::{self, File};
-use std::io::Write;
-use std::os::unix::fs::{MetadataExt, symlink};
-use std::path::PathBuf;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt, symlink};
+use std::path::{Path, PathBuf};
 use std::process::{Child, Command};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use tempfile::TempDir;
 
+const FSCK_BIN: &str = env!("CARGO_BIN_FILE_GREINA_FSCK_greina_fsck");
 const MKFS_BIN: &str = env!("CARGO_BIN_FILE_GREINA_MKFS_greina_mkfs");
 const MOUNT_BIN: &str = env!("CARGO_BIN_FILE_GREINA_MOUNT_greina_mount");
 
@@ -15,11 +18,16 @@ struct MountedContext {
     mount_process: Child,
     _mount_dir: TempDir,
     _storage_dir: TempDir,
+    storage_path: PathBuf,
     mount_path: PathBuf,
 }
 
 impl MountedContext {
     fn new() -> Self {
+        Self::with_mkfs_args(&[])
+    }
+
+    fn with_mkfs_args(mkfs_args: &[&str]) -> Self {
         let storage_dir = tempfile::tempdir().expect("failed to create storage dir");
         let storage_path = storage_dir.path().join("disk.img");
 
@@ -29,6 +37,7 @@ impl MountedContext {
         drop(file);
 
         let status = Command::new(MKFS_BIN)
+            .args(mkfs_args)
             .arg(&storage_path)
             .status()
             .expect("failed to run mkfs");
@@ -37,50 +46,77 @@ impl MountedContext {
         let mount_dir = tempfile::tempdir().expect("failed to create mount dir");
         let mount_path = mount_dir.path().to_owned();
 
-        let mut child = Command::new(MOUNT_BIN)
-            .arg(&storage_path)
-            .arg(&mount_path)
-            .spawn()
-            .expect("failed to run mount");
-
-        let mut mounted = false;
-        for _ in 0..10 {
-            if let Ok(metadata) = fs::metadata(&mount_path) {
-                let parent = mount_path.parent().unwrap();
-                if let Ok(parent_metadata) = fs::metadata(parent) {
-                    if metadata.dev() != parent_metadata.dev() {
-                        mounted = true;
-                        break;
-                    }
-                }
-            }
+        let mount_process = spawn_mount(&storage_path, &mount_path, &[]);
 
-            if let Ok(Some(status)) = child.try_wait() {
-                panic!("mount process exited prematurely with {}", status);
-            }
+        Self {
+            mount_process,
+            _mount_dir: mount_dir,
+            _storage_dir: storage_dir,
+            storage_path,
+            mount_path,
+        }
+    }
+
+    /// Mounts the filesystem again, after it was unmounted.
+    fn remount(&mut self) {
+        self.remount_with_args(&[]);
+    }
+
+    /// Mounts the filesystem again with extra arguments, after it was unmounted.
+    fn remount_with_args(&mut self, mount_args: &[&str]) {
+        self.unmount();
+        self.mount_process = spawn_mount(&self.storage_path, &self.mount_path, mount_args);
+    }
 
-            thread::sleep(Duration::from_millis(500));
+    /// Unmounts the filesystem, keeping its storage around.
+    fn unmount(&mut self) {
+        if let Ok(None) = self.mount_process.try_wait() {
+            unsafe {
+                libc::kill(self.mount_process.id() as i32, libc::SIGINT);
+            }
+            let _ = self.mount_process.wait();
         }
+    }
+}
 
-        if !mounted {
-            panic!("timed out waiting for filesystem to mount");
+/// Mounts the filesystem on `storage_path` at `mount_path`, waiting until it's mounted.
+fn spawn_mount(storage_path: &Path, mount_path: &Path, mount_args: &[&str]) -> Child {
+    let mut child = Command::new(MOUNT_BIN)
+        .args(mount_args)
+        .arg(storage_path)
+        .arg(mount_path)
+        .spawn()
+        .expect("failed to run mount");
+
+    let mut mounted = false;
+    for _ in 0..10 {
+        if let Ok(metadata) = fs::metadata(mount_path) {
+            let parent = mount_path.parent().unwrap();
+            if let Ok(parent_metadata) = fs::metadata(parent)
+                && metadata.dev() != parent_metadata.dev()
+            {
+                mounted = true;
+                break;
+            }
         }
 
-        Self {
-            mount_process: child,
-            _mount_dir: mount_dir,
-            _storage_dir: storage_dir,
-            mount_path,
+        if let Ok(Some(status)) = child.try_wait() {
+            panic!("mount process exited prematurely with {}", status);
         }
+
+        thread::sleep(Duration::from_millis(500));
+    }
+
+    if !mounted {
+        panic!("timed out waiting for filesystem to mount");
     }
+
+    child
 }
 
 impl Drop for MountedContext {
     fn drop(&mut self) {
-        unsafe {
-            libc::kill(self.mount_process.id() as i32, libc::SIGINT);
-        }
-        let _ = self.mount_process.wait();
+        self.unmount();
     }
 }
 
@@ -89,6 +125,19 @@ fn test_mkfs_and_mount() {
     let _ctx = MountedContext::new();
 }
 
+#[test]
+fn test_root_owner() {
+    let ctx = MountedContext::new();
+    let metadata = fs::metadata(&ctx.mount_path).expect("failed to stat root");
+    let owner = unsafe { (libc::getuid(), libc::getgid()) };
+    assert_eq!((metadata.uid(), metadata.gid()), owner);
+    drop(ctx);
+
+    let ctx = MountedContext::with_mkfs_args(&["--root-owner", "1234:5678"]);
+    let metadata = fs::metadata(&ctx.mount_path).expect("failed to stat root");
+    assert_eq!((metadata.uid(), metadata.gid()), (1234, 5678));
+}
+
 #[test]
 fn test_file() {
     let ctx = MountedContext::new();
@@ -195,3 +244,391 @@ fn test_symlink() {
     fs::remove_file(&link_path).expect("failed to remove symlink");
     fs::remove_file(&target_path).expect("failed to remove target file");
 }
+
+#[test]
+fn test_times() {
+    let ctx = MountedContext::new();
+    let root = &ctx.mount_path;
+    let file_path = root.join("hello.txt");
+
+    let before = SystemTime::now();
+    {
+        let mut file = File::create(&file_path).expect("failed to create file");
+        file.write_all(b"Hello from Greina!")
+            .expect("failed to write to file");
+    }
+
+    let metadata = fs::metadata(&file_path).unwrap();
+    assert!(metadata.modified().unwrap() >= before);
+    assert!(fs::metadata(root).unwrap().modified().unwrap() >= before);
+
+    // Set explicitly
+    let mtime = UNIX_EPOCH + Duration::new(1_000_000_000, 123_456_789);
+    {
+        let file = File::options()
+            .write(true)
+            .open(&file_path)
+            .expect("failed to open file");
+        file.set_modified(mtime).expect("failed to set mtime");
+    }
+    assert_eq!(fs::metadata(&file_path).unwrap().modified().unwrap(), mtime);
+}
+
+#[test]
+fn test_perms() {
+    let ctx = MountedContext::new();
+    let root = &ctx.mount_path;
+    let file_path = root.join("hello.txt");
+    let dir_path = root.join("foo");
+
+    File::create(&file_path).expect("failed to create file");
+    fs::create_dir(&dir_path).expect("failed to create directory");
+
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+    let metadata = fs::metadata(&file_path).unwrap();
+    assert_eq!((metadata.uid(), metadata.gid()), (uid, gid));
+
+    fs::set_permissions(&file_path, fs::Permissions::from_mode(0o640))
+        .expect("failed to change mode");
+    let metadata = fs::metadata(&file_path).unwrap();
+    assert_eq!(metadata.mode() & 0o7777, 0o640);
+    assert!(metadata.is_file());
+
+    fs::set_permissions(&dir_path, fs::Permissions::from_mode(0o700))
+        .expect("failed to change mode");
+    let metadata = fs::metadata(&dir_path).unwrap();
+    assert_eq!(metadata.mode() & 0o7777, 0o700);
+    assert!(metadata.is_dir());
+
+    if uid == 0 {
+        std::os::unix::fs::chown(&file_path, Some(1000), Some(1000)).expect("failed to chown");
+        let metadata = fs::metadata(&file_path).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (1000, 1000));
+    }
+}
+
+#[test]
+fn test_xattr() {
+    let ctx = MountedContext::new();
+    let root = &ctx.mount_path;
+    let file_path = root.join("hello.txt");
+    File::create(&file_path).expect("failed to create file");
+
+    let path = CString::new(file_path.as_os_str().as_bytes()).unwrap();
+    let name = c"user.greina";
+    const VALUE: &[u8] = b"Hello from Greina!";
+
+    let res = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            VALUE.as_ptr().cast(),
+            VALUE.len(),
+            libc::XATTR_CREATE,
+        )
+    };
+    assert_eq!(res, 0, "failed to set xattr");
+
+    let mut value = [0u8; 64];
+    let len = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr().cast(),
+            value.len(),
+        )
+    };
+    assert_eq!(&value[..len as usize], VALUE);
+
+    let mut list = [0u8; 64];
+    let len = unsafe { libc::listxattr(path.as_ptr(), list.as_mut_ptr().cast(), list.len()) };
+    assert_eq!(&list[..len as usize], name.to_bytes_with_nul());
+
+    let res = unsafe { libc::removexattr(path.as_ptr(), name.as_ptr()) };
+    assert_eq!(res, 0, "failed to remove xattr");
+
+    let len = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr().cast(),
+            value.len(),
+        )
+    };
+    assert_eq!(len, -1);
+    assert_eq!(
+        std::io::Error::last_os_error().raw_os_error(),
+        Some(libc::ENODATA)
+    );
+}
+
+fn renameat2(from: &Path, to: &Path, flags: libc::c_uint) -> std::io::Result<()> {
+    let from = CString::new(from.as_os_str().as_bytes()).unwrap();
+    let to = CString::new(to.as_os_str().as_bytes()).unwrap();
+    let ret = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            from.as_ptr(),
+            libc::AT_FDCWD,
+            to.as_ptr(),
+            flags,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[test]
+fn test_rename() {
+    let ctx = MountedContext::new();
+    let root = &ctx.mount_path;
+    let a_path = root.join("a");
+    let b_path = root.join("b");
+    let c_path = root.join("c");
+
+    fs::write(&a_path, b"a").unwrap();
+    fs::write(&b_path, b"b").unwrap();
+
+    // Replace
+    fs::rename(&a_path, &b_path).expect("failed to replace file");
+    assert!(!a_path.exists());
+    assert_eq!(fs::read(&b_path).unwrap(), b"a");
+
+    // No replace
+    fs::write(&c_path, b"c").unwrap();
+    let err = renameat2(&b_path, &c_path, libc::RENAME_NOREPLACE).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+    renameat2(&b_path, &a_path, libc::RENAME_NOREPLACE).expect("failed to rename file");
+    assert_eq!(fs::read(&a_path).unwrap(), b"a");
+
+    // Exchange
+    renameat2(&a_path, &c_path, libc::RENAME_EXCHANGE).expect("failed to exchange files");
+    assert_eq!(fs::read(&a_path).unwrap(), b"c");
+    assert_eq!(fs::read(&c_path).unwrap(), b"a");
+
+    // Replace an empty directory, but not a non-empty one
+    let dir_path = root.join("dir");
+    let other_path = root.join("other");
+    fs::create_dir(&dir_path).unwrap();
+    fs::create_dir(&other_path).unwrap();
+    fs::rename(&a_path, dir_path.join("a")).unwrap();
+
+    let err = fs::rename(&other_path, &dir_path).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOTEMPTY));
+    fs::rename(&dir_path, &other_path).expect("failed to replace directory");
+    assert_eq!(fs::read(other_path.join("a")).unwrap(), b"c");
+    assert!(!dir_path.exists());
+}
+
+#[test]
+fn test_orphan() {
+    let ctx = MountedContext::new();
+    let root = &ctx.mount_path;
+    let file_path = root.join("temp");
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .truncate(true)
+        .open(&file_path)
+        .expect("failed to create file");
+    file.write_all(b"Hello").unwrap();
+
+    // The data outlives the last link while the file is open
+    fs::remove_file(&file_path).expect("failed to remove file");
+    assert!(!file_path.exists());
+    file.write_all(b" from Greina!").unwrap();
+
+    let metadata = file.metadata().unwrap();
+    assert_eq!(metadata.nlink(), 0);
+    assert_eq!(metadata.len(), 18);
+
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "Hello from Greina!");
+}
+
+#[test]
+fn test_fsync() {
+    let mut ctx = MountedContext::new();
+    let root = &ctx.mount_path;
+    let dir_path = root.join("dir");
+    let file_path = dir_path.join("file");
+
+    fs::create_dir(&dir_path).expect("failed to create dir");
+    let mut file = File::create(&file_path).expect("failed to create file");
+    file.write_all(b"Hello from Greina!").unwrap();
+    file.sync_all().expect("failed to sync file");
+    file.sync_data().expect("failed to sync file data");
+    File::open(&dir_path)
+        .and_then(|dir| dir.sync_all())
+        .expect("failed to sync dir");
+    drop(file);
+
+    ctx.remount();
+    let contents = fs::read(&file_path).expect("failed to read synced file");
+    assert_eq!(contents, b"Hello from Greina!");
+}
+
+/// Returns how many bytes of the storage file are allocated on the host.
+fn allocated_bytes(storage_path: &Path) -> u64 {
+    fs::metadata(storage_path).unwrap().blocks() * 512
+}
+
+#[test]
+fn test_discard() {
+    let mut ctx = MountedContext::new();
+    ctx.remount_with_args(&["--discard"]);
+    let root = &ctx.mount_path;
+    let file_path = root.join("file");
+
+    fs::write(&file_path, vec![0xAB; 64 * 1024]).expect("failed to write file");
+    let allocated = allocated_bytes(&ctx.storage_path);
+    File::options()
+        .write(true)
+        .open(&file_path)
+        .and_then(|file| file.set_len(0))
+        .expect("failed to truncate file");
+    ctx.unmount();
+
+    // The file's blocks were punched out of the image, while the truncation itself wrote a few
+    // new ones
+    assert!(allocated_bytes(&ctx.storage_path) <= allocated - 32 * 1024);
+    assert_eq!(fsck(&ctx.storage_path, false), Some(0));
+}
+
+#[test]
+fn test_trim() {
+    let mut ctx = MountedContext::new();
+    let root = &ctx.mount_path;
+    let file_path = root.join("file");
+
+    fs::write(&file_path, vec![0xAB; 64 * 1024]).expect("failed to write file");
+    File::options()
+        .write(true)
+        .open(&file_path)
+        .and_then(|file| file.set_len(0))
+        .expect("failed to truncate file");
+    fs::write(root.join("kept"), b"Hello from Greina!").expect("failed to write file");
+    ctx.unmount();
+    let allocated = allocated_bytes(&ctx.storage_path);
+
+    let status = Command::new(FSCK_BIN)
+        .arg("--trim")
+        .arg(&ctx.storage_path)
+        .status()
+        .expect("failed to run fsck");
+    assert_eq!(status.code(), Some(0));
+    assert!(allocated_bytes(&ctx.storage_path) <= allocated - 64 * 1024);
+
+    ctx.remount();
+    let contents = fs::read(ctx.mount_path.join("kept")).expect("failed to read file");
+    assert_eq!(contents, b"Hello from Greina!");
+}
+
+fn fsck(storage_path: &Path, repair: bool) -> Option<i32> {
+    let mut command = Command::new(FSCK_BIN);
+    if repair {
+        command.arg("--repair");
+    }
+    command
+        .arg(storage_path)
+        .status()
+        .expect("failed to run fsck")
+        .code()
+}
+
+#[test]
+fn test_fsck() {
+    let mut ctx = MountedContext::new();
+    let root = &ctx.mount_path;
+
+    fs::create_dir(root.join("dir")).expect("failed to create dir");
+    fs::write(root.join("dir/file"), vec![0xAB; 64 * 1024]).expect("failed to write file");
+    fs::hard_link(root.join("dir/file"), root.join("link")).expect("failed to link file");
+    symlink("dir/file", root.join("symlink")).expect("failed to create symlink");
+    fs::write(root.join("removed"), b"removed").expect("failed to write file");
+    fs::remove_file(root.join("removed")).expect("failed to remove file");
+    ctx.unmount();
+
+    assert_eq!(fsck(&ctx.storage_path, false), Some(0));
+
+    // Clear the allocator's bitmap, which starts right after the superblock
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .open(&ctx.storage_path)
+        .expect("failed to open storage");
+    file.seek(SeekFrom::Start(4096)).unwrap();
+    file.write_all(&[0; 4096]).expect("failed to clear bitmap");
+    drop(file);
+
+    assert_eq!(fsck(&ctx.storage_path, false), Some(4));
+    assert_eq!(fsck(&ctx.storage_path, true), Some(1));
+    assert_eq!(fsck(&ctx.storage_path, false), Some(0));
+}
+
+/// Overwrites the primary superblock, at the start of the storage, with zeroes.
+fn clear_superblock(storage_path: &Path) {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .open(storage_path)
+        .expect("failed to open storage");
+    file.write_all(&[0; 4096])
+        .expect("failed to clear superblock");
+}
+
+#[test]
+fn test_backup_superblock() {
+    let mut ctx = MountedContext::new();
+    let file_path = ctx.mount_path.join("file");
+    fs::write(&file_path, b"contents").expect("failed to write file");
+    ctx.unmount();
+
+    clear_superblock(&ctx.storage_path);
+    assert_eq!(fsck(&ctx.storage_path, false), Some(4));
+    assert_eq!(fsck(&ctx.storage_path, true), Some(1));
+    assert_eq!(fsck(&ctx.storage_path, false), Some(0));
+
+    clear_superblock(&ctx.storage_path);
+    ctx.remount_with_args(&["--backup-superblock"]);
+    assert_eq!(
+        fs::read(&file_path).expect("failed to read file"),
+        b"contents"
+    );
+    ctx.unmount();
+    assert_eq!(fsck(&ctx.storage_path, false), Some(0));
+}
+
+#[test]
+fn test_data_checksums() {
+    let mut ctx = MountedContext::with_mkfs_args(&["--data-checksums"]);
+    let file_path = ctx.mount_path.join("file");
+
+    const BLOCK: usize = 4096;
+    let contents: Vec<u8> = (0..4 * BLOCK).map(|i| i as u8).collect();
+    fs::write(&file_path, &contents).expect("failed to write file");
+    assert_eq!(fs::read(&file_path).expect("failed to read file"), contents);
+    ctx.unmount();
+
+    // Flip a bit in every block holding the file's contents, including stale journal copies
+    let mut image = fs::read(&ctx.storage_path).expect("failed to read storage");
+    let mut corrupted = 0;
+    for block in image.chunks_mut(BLOCK) {
+        if contents.chunks(BLOCK).any(|chunk| chunk == block) {
+            block[0] ^= 1;
+            corrupted += 1;
+        }
+    }
+    assert!(corrupted >= 4);
+    fs::write(&ctx.storage_path, &image).expect("failed to write storage");
+
+    ctx.remount();
+    let err = fs::read(ctx.mount_path.join("file")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+}